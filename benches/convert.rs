@@ -0,0 +1,110 @@
+//! 1080p frame conversion, the current path against what the uvc backend used
+//! to do (libuvc's `uvc_any2rgb`, then a copy into egui pixels).
+//! run with `cargo bench --bench convert`
+
+#[allow(dead_code)]
+#[path = "../src/convert.rs"]
+mod convert;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use convert::{Colorimetry, Frame, PixelFormat};
+use egui::Color32;
+
+const WIDTH: usize = 1920;
+const HEIGHT: usize = 1080;
+
+/// median and mean of `f`, after a few runs to warm the allocator up
+fn time(name: &str, mut f: impl FnMut()) {
+    for _ in 0..20 {
+        f();
+    }
+    let mut runs: Vec<Duration> = (0..200)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    runs.sort();
+    let mean = runs.iter().sum::<Duration>() / runs.len() as u32;
+    println!(
+        "{name:<24} median {:>6.2} ms   mean {:>6.2} ms",
+        runs[runs.len() / 2].as_secs_f64() * 1e3,
+        mean.as_secs_f64() * 1e3
+    );
+}
+
+/// libuvc's `uvc_yuyv2rgb`: fixed point, one pixel pair at a time, into a
+/// fresh rgb buffer
+fn libuvc_yuyv2rgb(src: &[u8]) -> Vec<u8> {
+    let sat = |x: i32| x.clamp(0, 255) as u8;
+    let mut rgb = vec![0; WIDTH * HEIGHT * 3];
+    for (yuyv, rgb) in src.chunks_exact(4).zip(rgb.chunks_exact_mut(6)) {
+        let (u, v) = (yuyv[1] as i32 - 128, yuyv[3] as i32 - 128);
+        let r = (22987 * v) >> 14;
+        let g = (-5636 * u - 11698 * v) >> 14;
+        let b = (29049 * u) >> 14;
+        for (y, px) in [yuyv[0], yuyv[2]].into_iter().zip(rgb.chunks_exact_mut(3)) {
+            let y = y as i32;
+            px.copy_from_slice(&[sat(y + r), sat(y + g), sat(y + b)]);
+        }
+    }
+    rgb
+}
+
+/// what `handle_frame` did with the rgb
+fn old_copy(rgb: &[u8]) -> egui::ColorImage {
+    let mut pixels = vec![Color32::TRANSPARENT; WIDTH * HEIGHT];
+    for (rgba, rgb) in pixels.iter_mut().zip(rgb.chunks_exact(3)) {
+        *rgba = Color32::from_rgb(rgb[0], rgb[1], rgb[2]);
+    }
+    egui::ColorImage {
+        size: [WIDTH, HEIGHT],
+        pixels,
+    }
+}
+
+fn new_path(format: PixelFormat, data: &[u8]) -> egui::ColorImage {
+    let frame = Frame {
+        width: WIDTH,
+        height: HEIGHT,
+        format,
+        data,
+        captured: Instant::now(),
+    };
+    convert::to_color_image(&frame, Colorimetry::default()).unwrap()
+}
+
+fn main() {
+    // not a real picture, but every byte different enough that nothing's constant
+    let noise = |len: usize| -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect()
+    };
+    let yuyv = noise(WIDTH * HEIGHT * 2);
+    let nv12 = noise(WIDTH * HEIGHT * 3 / 2);
+    let rgb = noise(WIDTH * HEIGHT * 3);
+
+    time("yuyv, old", || {
+        black_box(old_copy(&libuvc_yuyv2rgb(black_box(&yuyv))));
+    });
+    time("yuyv, new", || {
+        black_box(new_path(PixelFormat::Yuyv, black_box(&yuyv)));
+    });
+    time("nv12, new", || {
+        black_box(new_path(PixelFormat::Nv12, black_box(&nv12)));
+    });
+    time("rgb24, old", || {
+        black_box(old_copy(black_box(&rgb)));
+    });
+    time("rgb24, new", || {
+        black_box(new_path(PixelFormat::Rgb24, black_box(&rgb)));
+    });
+    // how much of the above is just getting a zeroed buffer to write into
+    time("zeroed 1080p buffer", || {
+        black_box(bytemuck::zeroed_vec::<Color32>(WIDTH * HEIGHT));
+    });
+}
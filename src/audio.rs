@@ -1,99 +1,549 @@
 use std::cell::{Cell, RefCell};
-use std::future::{self, Future};
-use std::io::{Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future;
 use std::rc::Rc;
-use std::sync::Arc;
-use std::task::{Context, Poll, Wake, Waker};
+use std::sync::Mutex;
+use std::task::Poll;
+use std::thread::ThreadId;
+use std::time::Duration;
 
-use futures_util::{stream::FusedStream, FutureExt, StreamExt};
+use futures_util::FutureExt;
 use pulse::context::{self, introspect};
-use pulse::error::PAErr;
-use pulse::mainloop::standard::Mainloop;
-use pulse::mainloop::{self, api::Mainloop as _};
-
-pub fn audio_loop(
-    done_ch: (flume::Sender<()>, flume::Receiver<()>),
-    mut audname: String,
-    audname_rx: flume::Receiver<String>,
-) {
+use pulse::def::BufferAttr;
+use pulse::proplist::Proplist;
+use pulse::stream::{PeekResult, SeekMode, Stream};
+use serde::{Deserialize, Serialize};
+
+use crate::meter::Meters;
+use crate::pa::{self, PaRuntime};
+use crate::record::{self, Recorder};
+use crate::stats::{AudioStatus, Stats};
+
+pub(crate) struct AudioParams {
+    /// we get a message on `.1` when it's time to quit, and send one on `.0`
+    /// once everything's cleaned up
+    pub done_ch: (flume::Sender<()>, flume::Receiver<()>),
+    pub config: AudioConfig,
+    pub config_rx: flume::Receiver<AudioConfig>,
+    pub recorder: Recorder,
+    pub rec_rx: flume::Receiver<bool>,
+    pub volume: Volume,
+    pub volume_rx: flume::Receiver<Volume>,
+    pub meters: Meters,
+    pub stats: Stats,
+}
+
+/// the ui's ends of an `AudioParams`, for tests
+#[cfg(all(test, feature = "pipewire"))]
+pub(crate) struct TestUi {
+    pub done_tx: flume::Sender<()>,
+    pub finished_rx: flume::Receiver<()>,
+    pub config_tx: flume::Sender<AudioConfig>,
+    pub volume_tx: flume::Sender<Volume>,
+    pub stats: Stats,
+}
+
+#[cfg(all(test, feature = "pipewire"))]
+impl AudioParams {
+    pub fn for_test(source: AudioSource) -> (Self, TestUi) {
+        let (done_tx, done_rx) = flume::bounded(1);
+        let (finished_tx, finished_rx) = flume::bounded(1);
+        let (config_tx, config_rx) = flume::unbounded();
+        let (volume_tx, volume_rx) = flume::unbounded();
+        let (recorder, rec_rx) = Recorder::new();
+        let stats = Stats::default();
+        let params = AudioParams {
+            done_ch: (finished_tx, done_rx),
+            config: AudioConfig::for_test(source),
+            config_rx,
+            recorder,
+            rec_rx,
+            volume: Volume::default(),
+            volume_rx,
+            meters: Default::default(),
+            stats: stats.clone(),
+        };
+        let ui = TestUi {
+            done_tx,
+            finished_rx,
+            config_tx,
+            volume_tx,
+            stats,
+        };
+        (params, ui)
+    }
+}
+
+/// what we set on our sink input, whichever way it got made
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Volume {
+    pub percent: u32,
+    pub muted: bool,
+}
+
+impl Volume {
+    pub const MAX_PERCENT: u32 = 150;
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            percent: 100,
+            muted: false,
+        }
+    }
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.muted {
+            f.write_str("muted")
+        } else {
+            write!(f, "{}%", self.percent)
+        }
+    }
+}
+
+/// what the audio thread should be playing, and how
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct AudioConfig {
+    pub source: AudioSource,
+    /// None for whatever the default sink is
+    pub sink: Option<String>,
+    pub mode: AudioMode,
+    /// roughly how far behind the source we play
+    pub latency_ms: u32,
+    /// extra delay on top of that, for lining up with the video
+    pub delay_ms: u32,
+    pub loopback: LoopbackOptions,
+}
+
+#[cfg(test)]
+impl AudioConfig {
+    pub fn for_test(source: AudioSource) -> Self {
+        AudioConfig {
+            source,
+            sink: None,
+            mode: AudioMode::Loopback,
+            latency_ms: 40,
+            delay_ms: 0,
+            loopback: LoopbackOptions {
+                adjust_time: 0,
+                channel_map: None,
+                rate: None,
+            },
+        }
+    }
+}
+
+/// which source to play
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AudioSource {
+    /// whichever source lives on the same usb device as the video
+    SameDevice(UsbMatch),
+    Named(String),
+}
+
+/// what we know about the video device's place on the bus
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct UsbMatch {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// the chain of ports it's plugged into, like `2` or `1.4`
+    pub port_path: Option<String>,
+}
+
+impl AudioSource {
+    fn matches(&self, info: &introspect::SourceInfo) -> bool {
+        match self {
+            AudioSource::Named(name) => info.name.as_deref() == Some(name),
+            // a headset on the same card would have a monitor source too
+            AudioSource::SameDevice(_) if info.monitor_of_sink.is_some() => false,
+            AudioSource::SameDevice(usb) => usb.matches(
+                |key| info.proplist.get_str(key),
+                "device.vendor.id",
+                "device.product.id",
+                "device.bus_path",
+            ),
+        }
+    }
+}
+
+impl UsbMatch {
+    /// check it against a device's properties. pulse and pipewire spell the
+    /// keys differently, and sometimes put 0x in front of the ids
+    pub fn matches(
+        &self,
+        prop: impl Fn(&str) -> Option<String>,
+        vendor_key: &str,
+        product_key: &str,
+        bus_path_key: &str,
+    ) -> bool {
+        let id = |key: &str| {
+            let s = prop(key)?;
+            u16::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+        };
+        if self.vendor_id.is_none() && self.port_path.is_none() {
+            // nothing to go on, e.g. the test pattern
+            return false;
+        }
+        let same_id =
+            |ours: Option<u16>, key: &str| ours.map_or(true, |ours| id(key) == Some(ours));
+        // udev's ID_PATH, which looks like pci-0000:00:14.0-usb-0:2:1.2
+        let same_port = || match (&self.port_path, prop(bus_path_key)) {
+            (Some(port), Some(path)) => path.contains(&format!("-usb-0:{port}:")),
+            _ => true,
+        };
+        same_id(self.vendor_id, vendor_key) && same_id(self.product_id, product_key) && same_port()
+    }
+}
+
+impl fmt::Display for AudioSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioSource::SameDevice(_) => f.write_str("the video device's audio"),
+            AudioSource::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+/// module-loopback knobs that passthrough doesn't need, since it always plays
+/// the same format the recorder wants
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LoopbackOptions {
+    /// seconds between resampler adjustments, 0 to never adjust
+    pub adjust_time: u32,
+    /// e.g. `front-left,front-right`, None to copy the source's
+    pub channel_map: Option<String>,
+    /// None to copy the source's
+    pub rate: Option<u32>,
+}
+
+/// how the pulse backend gets the source to the speakers
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AudioMode {
+    /// have the server load module-loopback for us
+    #[default]
+    Loopback,
+    /// record the source and play it back ourselves
+    Passthrough,
+}
+
+/// what plumbs the capture card's audio through to the speakers
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AudioBackend {
+    /// module-loopback through libpulse, works on pulseaudio and pipewire-pulse
+    #[default]
+    Pulse,
+    /// link the nodes together directly in the pipewire graph
+    PipeWire,
+}
+
+impl AudioBackend {
+    pub fn run(self, params: AudioParams) {
+        match self {
+            AudioBackend::Pulse => pulse_loop(params),
+            #[cfg(feature = "pipewire")]
+            AudioBackend::PipeWire => {
+                // audio_loop hands the params back if it couldn't even get going
+                if let Err((e, params)) = crate::pwaudio::audio_loop(params) {
+                    eprintln!("pipewire backend failed, falling back to pulse: {e:#}");
+                    pulse_loop(params)
+                }
+            }
+            #[cfg(not(feature = "pipewire"))]
+            AudioBackend::PipeWire => {
+                eprintln!("built without pipewire support, using pulse instead");
+                pulse_loop(params)
+            }
+        }
+    }
+}
+
+// the loopback modules we've got loaded right now, one per source being
+// played, and the audio thread that loaded each one, for the panic hook
+static LOADED_MODULES: Mutex<Vec<(ThreadId, u32)>> = Mutex::new(Vec::new());
+
+fn loaded_modules() -> std::sync::MutexGuard<'static, Vec<(ThreadId, u32)>> {
+    // a panic while holding it is exactly when we want it most
+    LOADED_MODULES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// the current thread has to clean this one up, or the panic hook does
+fn remember_module(mod_id: u32) {
+    loaded_modules().push((std::thread::current().id(), mod_id));
+}
+
+/// the route's module isn't ours to clean up anymore
+fn forget_module(route: &Option<Route>) {
+    if let Some(Route::Module(mod_id)) = route {
+        loaded_modules().retain(|(_, m)| m != mod_id);
+    }
+}
+
+/// make sure a panic doesn't leave our loopback modules playing forever. we
+/// can't get at the audio threads' mainloops from here, so this makes its own
+/// connection to unload them
+pub(crate) fn install_panic_hook() {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        prev(info);
+        let thread = std::thread::current();
+        // a panic anywhere else leaves the audio threads running and looking
+        // after their own modules, unless it's taking the whole process down
+        let everything = cfg!(panic = "abort") || thread.name() == Some("main");
+        let mod_ids = orphaned_by(&mut loaded_modules(), thread.id(), everything);
+        unload_modules(mod_ids);
+    }));
+}
+
+/// take out the modules nobody's going to unload once `thread` has panicked
+fn orphaned_by(loaded: &mut Vec<(ThreadId, u32)>, thread: ThreadId, everything: bool) -> Vec<u32> {
+    let (orphaned, kept) = loaded
+        .drain(..)
+        .partition::<Vec<_>, _>(|&(t, _)| everything || t == thread);
+    *loaded = kept;
+    orphaned.into_iter().map(|(_, m)| m).collect()
+}
+
+fn unload_modules(mod_ids: Vec<u32>) {
+    if mod_ids.is_empty() {
+        return;
+    }
     let rt = PaRuntime::new();
-    let mut ctx = rt.make_context("meeee");
+    let ctx = rt.make_context("ccdisplay cleanup");
+    let res = rt.run(async move {
+        ctx.connect().await?;
+        for mod_id in mod_ids {
+            if let Err(e) = ctx.unload_module(mod_id).await {
+                eprintln!("couldn't unload module {mod_id} on the way out: {e}");
+            }
+        }
+        Ok::<_, pa::Error>(())
+    });
+    if let Err(e) = res {
+        eprintln!("couldn't unload our modules on the way out: {e}");
+    }
+}
+
+/// whatever's currently carrying the source to the speakers
+enum Route {
+    Module(u32),
+    // only held so the streams stay up
+    Passthrough(#[allow(dead_code)] Passthrough),
+}
+
+// how long to wait before trying pulse again, doubling every time it fails
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+fn pulse_loop(params: AudioParams) {
+    let AudioParams {
+        done_ch: (finished_tx, done_rx),
+        mut config,
+        config_rx,
+        recorder,
+        rec_rx,
+        volume,
+        volume_rx,
+        meters,
+        stats,
+    } = params;
+    let rt = PaRuntime::new();
+    let handle = rt.handle();
     rt.run(async move {
-        connect(&mut ctx).await?;
-        let mut events = subscribe(&mut ctx, context::subscribe::InterestMaskSet::SOURCE);
-        let mut module_id = None;
-        let module_loop = async {
-            loop {
-                let source_index = loop {
-                    match find_and_load_module(&mut ctx.introspect(), audname.clone()).await {
-                        Ok(Some((mod_id, idx))) => {
-                            module_id = Some(mod_id);
-                            break Some(idx);
-                        }
-                        Ok(None) => break None,
-                        Err(e) => {
-                            eprintln!("error setting up audio {e}");
-                        }
+        // the context that's connected right now, if any
+        let connected = Rc::new(RefCell::new(None::<pa::Context>));
+        // the volume can change whether or not there's anything playing
+        let volume = Rc::new(Cell::new(volume));
+        handle.spawn({
+            let connected = connected.clone();
+            let volume = volume.clone();
+            async move {
+                while let Ok(new) = volume_rx.recv_async().await {
+                    volume.set(new);
+                    let ctx = connected.borrow().clone();
+                    if let Some(ctx) = ctx {
+                        set_volume(&ctx, new).await;
                     }
-                };
+                }
+            }
+        });
+        let mut recording = false;
+        let mut retry = RETRY_MIN;
+        loop {
+            stats.set_audio(AudioStatus::Connecting);
+            let ctx = handle.make_context("meeee");
+            let mut route = None;
+            let session = async {
+                if let Err(e) = ctx.connect().await {
+                    return e;
+                }
+                retry = RETRY_MIN;
+                *connected.borrow_mut() = Some(ctx.clone());
+                unload_orphans(&ctx).await;
+                let events = ctx.subscribe(context::subscribe::InterestMaskSet::SOURCE);
+                // these two are only kept around so they get dropped at the right times
+                let mut _rec_stream = None;
+                let mut _meter_stream = None;
                 loop {
-                    let ev = futures_util::select_biased! {
-                        new_src = audname_rx.recv_async() => {
-                            match new_src {
-                                Ok(new) if new == audname => continue,
-                                Err(_) => continue,
-                                Ok(new) => audname = new,
+                    _meter_stream = None;
+                    meters.clear();
+                    let (source_index, source_name) = loop {
+                        match start_route(&ctx, &config, &recorder).await {
+                            Ok(Some((r, idx, name))) => {
+                                stats.set_audio(match &r {
+                                    Route::Module(module) => AudioStatus::Looping {
+                                        source: name.clone(),
+                                        module: *module,
+                                    },
+                                    Route::Passthrough(_) => AudioStatus::Passthrough {
+                                        source: name.clone(),
+                                        latency_ms: config.latency_ms,
+                                    },
+                                });
+                                route = Some(r);
+                                // a fresh module or stream starts out at 100%
+                                set_volume(&ctx, volume.get()).await;
+                                break (Some(idx), name);
                             }
-                            if let Some(mod_id) = module_id.take() {
-                                unload_module(&mut ctx.introspect(), mod_id).await;
+                            Ok(None) => {
+                                stats.set_audio(AudioStatus::NoSource(config.source.to_string()));
+                                break (None, String::new());
+                            }
+                            Err(e) => {
+                                eprintln!("error setting up audio {e}");
+                                stats.set_audio(AudioStatus::Error(e.to_string()));
+                                handle.sleep(Duration::from_secs(1)).await;
                             }
-                            break;
                         }
-                        ev = events.next() => ev.unwrap(),
                     };
-                    let (_facility, op, index) = ev;
-                    match op {
-                        context::subscribe::Operation::New if source_index.is_none() => break,
-                        context::subscribe::Operation::Removed if Some(index) == source_index => {
-                            module_id = None;
-                            break;
+                    // passthrough already hands everything it hears to the recorder
+                    let needs_rec_stream = |recording, route: &Option<Route>| {
+                        recording && matches!(route, Some(Route::Module(_)))
+                    };
+                    if needs_rec_stream(recording, &route) {
+                        _rec_stream = record_source(&ctx, &source_name, &recorder).await;
+                    }
+                    if source_index.is_some() {
+                        _meter_stream = meter_source(&ctx, &source_name, &meters).await;
+                    }
+                    loop {
+                        let ev = futures_util::select_biased! {
+                            new_config = config_rx.recv_async() => {
+                                let new = match new_config {
+                                    Ok(new) if new != config => new,
+                                    _ => continue,
+                                };
+                                // nudging the a/v offset shouldn't cut the audio out
+                                // if we can help it
+                                if let Some(Route::Passthrough(p)) = &route {
+                                    let same = AudioConfig {
+                                        delay_ms: config.delay_ms,
+                                        ..new.clone()
+                                    };
+                                    if same == config {
+                                        p.set_delay(new.delay_ms);
+                                        config = new;
+                                        continue;
+                                    }
+                                }
+                                config = new;
+                                stop_route(&ctx, route.take()).await;
+                                _rec_stream = None;
+                                break;
+                            }
+                            rec = rec_rx.recv_async() => {
+                                recording = rec.unwrap_or(false);
+                                _rec_stream = None;
+                                if needs_rec_stream(recording, &route) {
+                                    _rec_stream = record_source(&ctx, &source_name, &recorder).await;
+                                }
+                                continue;
+                            }
+                            // the callback only goes away with the subscription
+                            ev = events.next().fuse() => ev.unwrap(),
+                        };
+                        let (_facility, op, index) = ev;
+                        match op {
+                            context::subscribe::Operation::New if source_index.is_none() => break,
+                            context::subscribe::Operation::Removed
+                                if Some(index) == source_index =>
+                            {
+                                // the module goes away along with its source, but our
+                                // own streams need dropping
+                                forget_module(&route);
+                                route = None;
+                                _rec_stream = None;
+                                stats.set_audio(AudioStatus::NoSource(config.source.to_string()));
+                                break;
+                            }
+                            _ => {}
                         }
-                        _ => {}
                     }
                 }
-                // let mod_id =
-            }
-        };
-        futures_util::select_biased! {
-            _ = done_ch.1.recv_async() => {},
-            x = module_loop.fuse() => {
-                enum Never {}
-                let x: Never = x;
-                match x {}
-            }
-        }
-        if let Some(mod_id) = module_id {
-            if !unload_module(&mut ctx.introspect(), mod_id).await {
-                eprintln!("failed unloading module {mod_id}")
+            };
+            let lost = futures_util::select_biased! {
+                _ = done_rx.recv_async() => None,
+                e = ctx.closed().fuse() => Some(e),
+                e = session.fuse() => Some(e),
+            };
+            *connected.borrow_mut() = None;
+            let e = match lost {
+                Some(e) => e,
+                None => {
+                    stop_route(&ctx, route).await;
+                    break;
+                }
+            };
+            // the module and our streams went with the server
+            forget_module(&route);
+            drop(route);
+            meters.clear();
+            eprintln!("lost pulse: {e}, trying again in {}s", retry.as_secs());
+            stats.set_audio(AudioStatus::Reconnecting {
+                error: e.to_string(),
+                retry_secs: retry.as_secs(),
+            });
+            // keep track of what the ui wants in the meantime
+            let wait = handle.sleep(retry).fuse();
+            futures_util::pin_mut!(wait);
+            loop {
+                futures_util::select_biased! {
+                    _ = done_rx.recv_async() => return,
+                    new_config = config_rx.recv_async() => {
+                        if let Ok(new) = new_config {
+                            config = new;
+                        }
+                    }
+                    rec = rec_rx.recv_async() => recording = rec.unwrap_or(false),
+                    _ = wait => break,
+                }
             }
+            retry = (retry * 2).min(RETRY_MAX);
         }
-        let _ = done_ch.0.try_send(());
-        Ok::<_, PAErr>(())
-    })
-    .unwrap()
-}
-
-async fn find_and_load_module(
-    introspect: &mut introspect::Introspector,
-    audname: String,
-) -> anyhow::Result<Option<(u32, u32)>> {
-    let source_info = get_source_info_list(introspect, move |info| {
-        let name = info.name.as_deref()?;
-        (name == audname).then(|| info.index)
-    })
-    .await;
-    let index = match source_info.into_iter().next() {
+    });
+    let _ = finished_tx.try_send(());
+}
+
+/// find the source and start playing it however `config` says to. gives back
+/// the source's index so we can tell when it goes away, and its name
+async fn start_route(
+    ctx: &pa::Context,
+    config: &AudioConfig,
+    recorder: &Recorder,
+) -> anyhow::Result<Option<(Route, u32, String)>> {
+    let source = config.source.clone();
+    let source_info = ctx
+        .source_info_list(move |info| {
+            let name = info.name.as_deref()?;
+            source.matches(info).then(|| (info.index, name.to_owned()))
+        })
+        .await?;
+    let (index, name) = match source_info.into_iter().next() {
         Some(x) => x,
         None => {
             eprintln!("couldn't find suitable audio device");
@@ -101,226 +551,712 @@ async fn find_and_load_module(
         }
     };
 
-    let mod_id = load_module(
-        introspect,
-        "module-loopback",
-        &format!(
-            r#"source={index} source_dont_move=true sink_input_properties="media.software=ccdisplay""#
-        ),
-    )
-    .await;
-
-    Ok(Some((mod_id, index)))
-}
-
-pub(crate) async fn connect(ctx: &mut context::Context) -> Result<(), PAErr> {
-    connect_ex(ctx, None, context::FlagSet::NOFLAGS, None).await
-}
-
-pub(crate) async fn connect_ex(
-    ctx: &mut context::Context,
-    server: Option<&str>,
-    flags: context::FlagSet,
-    api: Option<&pulse::def::SpawnApi>,
-) -> Result<(), PAErr> {
-    ctx.connect(server, flags, api)?;
-    future::poll_fn(|cx| match ctx.get_state() {
-        context::State::Ready => {
-            ctx.set_state_callback(None);
-            Poll::Ready(Ok(()))
+    let route = match config.mode {
+        AudioMode::Loopback => {
+            let mod_id = ctx
+                .load_module("module-loopback", &loopback_args(index, config))
+                .await?;
+            remember_module(mod_id);
+            Route::Module(mod_id)
         }
-        context::State::Failed | context::State::Terminated => {
-            ctx.set_state_callback(None);
-            Poll::Ready(Err(ctx.errno()))
+        AudioMode::Passthrough => {
+            Route::Passthrough(Passthrough::connect(ctx, &name, config, recorder.clone()).await?)
         }
-        _ => {
-            let waker = cx.waker().clone();
-            ctx.set_state_callback(Some(Box::new(move || waker.wake_by_ref())));
-            Poll::Pending
+    };
+
+    Ok(Some((route, index, name)))
+}
+
+fn loopback_args(source_index: u32, config: &AudioConfig) -> String {
+    let opts = &config.loopback;
+    let mut args = format!(
+        r#"source={source_index} source_dont_move=true latency_msec={} adjust_time={} sink_input_properties="media.software=ccdisplay {PID_PROP}={}""#,
+        config.latency_ms + config.delay_ms,
+        opts.adjust_time,
+        std::process::id()
+    );
+    if let Some(sink) = &config.sink {
+        args += &format!(r#" sink="{sink}" sink_dont_move=true"#);
+    }
+    if let Some(map) = &opts.channel_map {
+        // the config and the settings window check it too, but it can't be
+        // quoted, so anything with a space in it would mess up the rest
+        match check_channel_map(map) {
+            Ok(()) => args += &format!(" channel_map={map}"),
+            Err(e) => eprintln!("ignoring the channel map: {e}"),
         }
-    })
-    .await
-}
-
-pub async fn get_source_info_list<
-    F: FnMut(&introspect::SourceInfo) -> Option<U> + 'static,
-    U: 'static,
->(
-    introspect: &introspect::Introspector,
-    mut f: F,
-) -> Vec<U> {
-    let v = Rc::new(RefCell::new(Vec::new()));
-    let v2 = v.clone();
-    let mut op = introspect.get_source_info_list(move |l| {
-        if let pulse::callbacks::ListResult::Item(x) = l {
-            if let Some(x) = f(x) {
-                v2.borrow_mut().push(x)
+    }
+    if let Some(rate) = opts.rate {
+        args += &format!(" rate={rate}");
+    }
+    args
+}
+
+// what pa_channel_map_parse knows, besides aux0 to aux31
+const CHANNEL_NAMES: &[&str] = &[
+    "mono",
+    "front-left",
+    "front-right",
+    "front-center",
+    "left",
+    "right",
+    "center",
+    "rear-center",
+    "rear-left",
+    "rear-right",
+    "lfe",
+    "subwoofer",
+    "front-left-of-center",
+    "front-right-of-center",
+    "side-left",
+    "side-right",
+    "top-center",
+    "top-front-left",
+    "top-front-right",
+    "top-front-center",
+    "top-rear-left",
+    "top-rear-right",
+    "top-rear-center",
+];
+// and whole maps it knows by name
+const CHANNEL_MAPS: &[&str] = &[
+    "stereo",
+    "surround-21",
+    "surround-40",
+    "surround-41",
+    "surround-50",
+    "surround-51",
+    "surround-71",
+];
+
+/// make sure pulse will understand a channel map, like `front-left,front-right`
+pub(crate) fn check_channel_map(map: &str) -> anyhow::Result<()> {
+    if CHANNEL_MAPS.contains(&map) {
+        return Ok(());
+    }
+    let channels = map.split(',').collect::<Vec<_>>();
+    anyhow::ensure!(channels.len() <= 32, "pulse only goes up to 32 channels");
+    for ch in channels {
+        let aux = (0..32).any(|n| ch == format!("aux{n}"));
+        anyhow::ensure!(
+            aux || CHANNEL_NAMES.contains(&ch),
+            "{ch:?} isn't a channel pulse knows, try something like front-left,front-right"
+        );
+    }
+    Ok(())
+}
+
+// loopbacks get tagged with the pid that loaded them, so we can tell our own
+// (and other running instances') apart from leftovers
+const PID_PROP: &str = "ccdisplay.pid";
+
+/// unload loopbacks left over from a ccdisplay that got killed before it could
+/// clean up after itself. ones from before the pid tag count as leftovers too
+async fn unload_orphans(ctx: &pa::Context) {
+    let orphans = ctx
+        .module_info_list(|info| {
+            let arg = info.argument.as_deref()?;
+            if info.name.as_deref() != Some("module-loopback")
+                || !arg.contains("media.software=ccdisplay")
+            {
+                return None;
             }
+            is_orphan(arg).then(|| info.index)
+        })
+        .await;
+    let orphans = match orphans {
+        Ok(orphans) => orphans,
+        Err(e) => {
+            eprintln!("couldn't look for leftover loopbacks: {e}");
+            return;
         }
-    });
-    let mut v = Some(v);
-    wake_on_op(&mut op, move |_| match Rc::try_unwrap(v.take().unwrap()) {
-        Ok(x) => Poll::Ready(x.into_inner()),
-        Err(x) => {
-            v = Some(x);
-            Poll::Pending
-        }
-    })
-    .await
-}
-
-fn subscribe(
-    ctx: &mut context::Context,
-    mask: context::subscribe::InterestMaskSet,
-) -> impl FusedStream<
-    Item = (
-        context::subscribe::Facility,
-        context::subscribe::Operation,
-        u32,
-    ),
-> {
-    ctx.subscribe(mask, |_| {});
-    let (tx, rx) = flume::bounded(32);
-    ctx.set_subscribe_callback(Some(Box::new(move |facility, op, idx| {
-        // TODO: cancel somehow?
-        let _ = tx.try_send((facility.unwrap(), op.unwrap(), idx));
-    })));
-    rx.into_stream()
-}
-
-struct ReturnSlot<T> {
-    slot: Rc<Cell<Option<T>>>,
-}
-impl<T> ReturnSlot<T> {
-    fn new() -> Self {
-        Self {
-            slot: Rc::new(Cell::new(None)),
+    };
+    for mod_id in orphans {
+        eprintln!("unloading leftover loopback module {mod_id}");
+        if let Err(e) = ctx.unload_module(mod_id).await {
+            eprintln!("failed unloading module {mod_id}: {e}")
         }
     }
-    fn callback(&self) -> impl FnMut(T) {
-        let mut slot = Some(self.slot.clone());
-        move |val| match slot.take() {
-            Some(slot) => slot.set(Some(val)),
-            None => eprintln!("return cb called multiple times"),
+}
+
+/// whether a loopback's arguments say it was loaded by a ccdisplay that's gone
+fn is_orphan(arg: &str) -> bool {
+    let pid = arg
+        .split_once(&format!("{PID_PROP}="))
+        .and_then(|(_, rest)| rest.split('"').next()?.parse::<u32>().ok());
+    // pids get reused after a crash, so whatever has it now has to be us too
+    let comm = |pid: &str| std::fs::read_to_string(format!("/proc/{pid}/comm")).ok();
+    match pid {
+        Some(pid) if pid == std::process::id() => false,
+        Some(pid) => {
+            let theirs = comm(&pid.to_string());
+            theirs.is_none() || theirs != comm("self")
         }
+        None => true,
     }
-    async fn wait(self, mut op: pulse::operation::Operation<impl ?Sized>) -> T {
-        wake_on_op(&mut op, |_| match self.slot.take() {
-            Some(val) => Poll::Ready(val),
-            None => Poll::Pending,
-        })
-        .await
+}
+
+/// whether a sink input is one of ours, going by the properties both the
+/// loopback and passthrough tag it with. another ccdisplay's are left alone
+fn is_ours(software: Option<&str>, pid: Option<&str>) -> bool {
+    software == Some("ccdisplay") && pid.and_then(|p| p.parse().ok()) == Some(std::process::id())
+}
+
+/// set the volume on our sink inputs
+async fn set_volume(ctx: &pa::Context, volume: Volume) {
+    let res = async {
+        let inputs = ctx
+            .sink_input_info_list(|info| {
+                let props = &info.proplist;
+                let ours = is_ours(
+                    props.get_str("media.software").as_deref(),
+                    props.get_str(PID_PROP).as_deref(),
+                );
+                ours.then(|| (info.index, info.volume))
+            })
+            .await?;
+        for (index, mut channels) in inputs {
+            let norm = pulse::volume::Volume::NORMAL.0 as u64;
+            let level = pulse::volume::Volume((norm * volume.percent as u64 / 100) as u32);
+            channels.set(channels.len(), level);
+            ctx.set_sink_input_volume(index, &channels).await?;
+            ctx.set_sink_input_mute(index, volume.muted).await?;
+        }
+        Ok::<_, pa::Error>(())
+    };
+    if let Err(e) = res.await {
+        eprintln!("couldn't set the volume to {volume}: {e}");
     }
 }
 
-async fn load_module(introspect: &mut introspect::Introspector, name: &str, argument: &str) -> u32 {
-    let ret = ReturnSlot::new();
-    let op = introspect.load_module(name, argument, ret.callback());
-    ret.wait(op).await
+async fn stop_route(ctx: &pa::Context, route: Option<Route>) {
+    forget_module(&route);
+    if let Some(Route::Module(mod_id)) = route {
+        if let Err(e) = ctx.unload_module(mod_id).await {
+            eprintln!("failed unloading module {mod_id}: {e}")
+        }
+    }
 }
-async fn unload_module(introspect: &mut introspect::Introspector, id: u32) -> bool {
-    let ret = ReturnSlot::new();
-    let op = introspect.unload_module(id, ret.callback());
-    ret.wait(op).await
+
+async fn record_source(
+    ctx: &pa::Context,
+    source: &str,
+    recorder: &Recorder,
+) -> Option<OwnedStream> {
+    let res = async {
+        let stream = OwnedStream::new(ctx, "ccdisplay recording")?;
+        let recorder = recorder.clone();
+        stream.on_read(move |data| recorder.audio_samples(data));
+        stream.0.borrow_mut().connect_record(
+            Some(source),
+            None,
+            pulse::stream::FlagSet::NOFLAGS,
+        )?;
+        stream.ready(ctx).await?;
+        Ok::<_, pa::Error>(stream)
+    };
+    match res.await {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            eprintln!("couldn't record audio from {source}: {e}");
+            None
+        }
+    }
 }
 
-async fn wake_on_op<T>(
-    op: &mut pulse::operation::Operation<impl ?Sized>,
-    mut f: impl FnMut(&mut Context<'_>) -> Poll<T>,
-) -> T {
-    future::poll_fn(|cx| {
-        let poll = f(cx);
-        if poll.is_ready() {
-            op.set_state_callback(None);
-        } else {
-            if op.get_state() == pulse::operation::State::Cancelled {
-                panic!("cancelled")
-            }
-            let waker = cx.waker().clone();
-            op.set_state_callback(Some(Box::new(move || waker.wake_by_ref())));
+/// a peak-detect stream on the source for the level meters. the server hands us
+/// the peak of every millisecond or so instead of the samples themselves
+async fn meter_source(ctx: &pa::Context, source: &str, meters: &Meters) -> Option<OwnedStream> {
+    let res = async {
+        let spec = pulse::sample::Spec {
+            format: pulse::sample::Format::F32le,
+            channels: 2,
+            rate: 1000,
+        };
+        let stream = OwnedStream::with_spec(ctx, "ccdisplay meters", &spec)?;
+        let meters = meters.clone();
+        stream.on_read(move |data| {
+            let peaks = data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            meters.push(&peaks);
+        });
+        let attr = BufferAttr {
+            maxlength: u32::MAX,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            // 10 peaks at a time
+            fragsize: 10 * 2 * 4,
+        };
+        stream.0.borrow_mut().connect_record(
+            Some(source),
+            Some(&attr),
+            pulse::stream::FlagSet::PEAK_DETECT
+                | pulse::stream::FlagSet::ADJUST_LATENCY
+                | pulse::stream::FlagSet::DONT_MOVE,
+        )?;
+        stream.ready(ctx).await?;
+        Ok::<_, pa::Error>(stream)
+    };
+    match res.await {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            eprintln!("couldn't meter {source}: {e}");
+            None
         }
-        poll
-    })
-    .await
+    }
+}
+
+fn bytes_for_ms(ms: u32) -> u32 {
+    record::AUDIO_RATE * ms / 1000 * record::AUDIO_BLOCK
 }
 
-struct PaWaker {
-    pipe: os_pipe::PipeWriter,
+/// plays the source ourselves: a record stream on it feeds a playback stream on
+/// the sink through a little jitter buffer, all on the mainloop
+struct Passthrough {
+    _record: OwnedStream,
+    _playback: OwnedStream,
+    buf: Rc<RefCell<JitterBuffer>>,
+    // the buffer's target before any a/v delay
+    base: usize,
 }
-impl Wake for PaWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_by_ref();
+
+impl Passthrough {
+    async fn connect(
+        ctx: &pa::Context,
+        source: &str,
+        config: &AudioConfig,
+        recorder: Recorder,
+    ) -> pa::Result<Self> {
+        // half the latency goes to our buffer, half to the sink's
+        let half = bytes_for_ms(config.latency_ms / 2);
+        let base = half as usize;
+        let target = base + bytes_for_ms(config.delay_ms) as usize;
+        let buf = Rc::new(RefCell::new(JitterBuffer::new(target)));
+
+        let record = OwnedStream::new(ctx, "ccdisplay capture")?;
+        record.on_read({
+            let buf = buf.clone();
+            move |data| {
+                buf.borrow_mut().push(data);
+                recorder.audio_samples(data);
+            }
+        });
+        let attr = BufferAttr {
+            maxlength: u32::MAX,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: bytes_for_ms(10),
+        };
+        record.0.borrow_mut().connect_record(
+            Some(source),
+            Some(&attr),
+            pulse::stream::FlagSet::ADJUST_LATENCY | pulse::stream::FlagSet::DONT_MOVE,
+        )?;
+        record.ready(ctx).await?;
+
+        let playback = OwnedStream::new(ctx, "ccdisplay")?;
+        playback.on_write({
+            let buf = buf.clone();
+            move |len| buf.borrow_mut().pull(len)
+        });
+        let attr = BufferAttr {
+            tlength: half,
+            fragsize: u32::MAX,
+            ..attr
+        };
+        let mut flags = pulse::stream::FlagSet::ADJUST_LATENCY;
+        if config.sink.is_some() {
+            flags |= pulse::stream::FlagSet::DONT_MOVE;
+        }
+        playback.0.borrow_mut().connect_playback(
+            config.sink.as_deref(),
+            Some(&attr),
+            flags,
+            None,
+            None,
+        )?;
+        playback.ready(ctx).await?;
+
+        Ok(Self {
+            _record: record,
+            _playback: playback,
+            buf,
+            base,
+        })
     }
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        let _ = (&self.pipe).write(b"X");
+    fn set_delay(&self, delay_ms: u32) {
+        let target = self.base + bytes_for_ms(delay_ms) as usize;
+        self.buf.borrow_mut().set_target(target);
     }
 }
 
-pub struct PaRuntime {
-    waker: Arc<PaWaker>,
-    wakee: os_pipe::PipeReader,
-    looop: Mainloop,
+/// samples on their way from the record stream to the playback stream
+struct JitterBuffer {
+    data: VecDeque<u8>,
+    target: usize,
+    // set once we've built up `target` bytes, cleared again if we run dry
+    primed: bool,
 }
-impl PaRuntime {
-    pub fn new() -> Self {
-        let (wakee, pipe) = os_pipe::pipe().unwrap();
-        let waker = Arc::new(PaWaker { pipe });
-        let looop = Mainloop::new().unwrap();
+
+impl JitterBuffer {
+    fn new(target: usize) -> Self {
         Self {
-            waker,
-            wakee,
-            looop,
+            data: VecDeque::with_capacity(target * 4),
+            target,
+            primed: false,
         }
     }
-    pub fn make_context(&self, name: &str) -> context::Context {
-        context::Context::new(&self.looop, name).unwrap()
+
+    /// pads with silence or skips ahead so the change is heard right away
+    fn set_target(&mut self, target: usize) {
+        if target > self.target {
+            for _ in 0..target - self.target {
+                self.data.push_front(0);
+            }
+        } else {
+            let cut = (self.target - target).min(self.data.len());
+            self.data.drain(..cut - cut % record::AUDIO_BLOCK as usize);
+        }
+        self.target = target;
     }
-    pub fn run<R: 'static>(mut self, fut: impl Future<Output = R> + 'static) -> R {
-        let mut fut = Box::pin(fut);
-        let ret = Rc::new(Cell::new(None));
-        let mut ret2 = Some(ret.clone());
-        let mut looop2 = Mainloop {
-            _inner: self.looop._inner.clone(),
+
+    fn push(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        // the two clocks drift apart eventually. skip ahead rather than letting
+        // the delay creep up forever
+        if self.data.len() > self.target * 3 {
+            let extra = self.data.len() - self.target;
+            self.data
+                .drain(..extra - extra % record::AUDIO_BLOCK as usize);
+        }
+    }
+
+    /// `len` bytes for the sink, padded out with silence if we don't have them
+    fn pull(&mut self, len: usize) -> Vec<u8> {
+        if self.data.len() >= self.target {
+            self.primed = true;
+        }
+        let mut out = Vec::with_capacity(len);
+        if self.primed {
+            let n = len.min(self.data.len());
+            out.extend(self.data.drain(..n));
+            if n < len {
+                self.primed = false;
+            }
+        }
+        out.resize(len, 0);
+        out
+    }
+}
+
+/// a stream that gets disconnected when it's dropped, tagged so it's easy to
+/// spot in pavucontrol
+struct OwnedStream(Rc<RefCell<Stream>>);
+
+impl OwnedStream {
+    /// a stream in the format the recorder wants
+    fn new(ctx: &pa::Context, name: &str) -> pa::Result<Self> {
+        let spec = pulse::sample::Spec {
+            format: pulse::sample::Format::S16le,
+            channels: record::AUDIO_CHANNELS,
+            rate: record::AUDIO_RATE,
         };
-        let waker = Waker::from(self.waker);
-        let ev = self.looop.new_deferred_event(Box::new(move |mut ev| {
-            ev.disable();
-            let mut cx = Context::from_waker(&waker);
-            let poll = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                fut.as_mut().poll(&mut cx)
-            })) {
-                Ok(poll) => poll,
-                Err(x) => {
-                    ret2.take().unwrap().set(Some(Err(x)));
-                    looop2.quit(pulse::def::Retval(111));
-                    return;
+        Self::with_spec(ctx, name, &spec)
+    }
+
+    fn with_spec(ctx: &pa::Context, name: &str, spec: &pulse::sample::Spec) -> pa::Result<Self> {
+        let mut props = Proplist::new().unwrap();
+        let _ = props.set_str("media.software", "ccdisplay");
+        let _ = props.set_str(PID_PROP, &std::process::id().to_string());
+        let stream = ctx
+            .with(|c| Stream::new_with_proplist(c, name, spec, None, &mut props))
+            .ok_or_else(|| ctx.errno())?;
+        Ok(Self(Rc::new(RefCell::new(stream))))
+    }
+
+    /// hand everything the (record) stream reads to `f`
+    fn on_read(&self, mut f: impl FnMut(&[u8]) + 'static) {
+        // weak so the stream doesn't keep itself alive through its own callback
+        let weak = Rc::downgrade(&self.0);
+        self.0
+            .borrow_mut()
+            .set_read_callback(Some(Box::new(move |_| {
+                let stream = match weak.upgrade() {
+                    Some(s) => s,
+                    None => return,
+                };
+                let mut stream = stream.borrow_mut();
+                loop {
+                    match stream.peek() {
+                        Ok(PeekResult::Data(data)) => f(data),
+                        Ok(PeekResult::Hole(_)) => {}
+                        Ok(PeekResult::Empty) | Err(_) => break,
+                    }
+                    let _ = stream.discard();
+                }
+            })));
+    }
+
+    /// fill the (playback) stream with whatever `f` gives back for the number
+    /// of bytes it wants
+    fn on_write(&self, mut f: impl FnMut(usize) -> Vec<u8> + 'static) {
+        let weak = Rc::downgrade(&self.0);
+        self.0
+            .borrow_mut()
+            .set_write_callback(Some(Box::new(move |len| {
+                let stream = match weak.upgrade() {
+                    Some(s) => s,
+                    None => return,
+                };
+                let data = f(len);
+                let res = stream
+                    .borrow_mut()
+                    .write(&data, None, 0, SeekMode::Relative);
+                if let Err(e) = res {
+                    eprintln!("couldn't write audio: {e}");
+                }
+            })));
+    }
+
+    async fn ready(&self, ctx: &pa::Context) -> pa::Result<()> {
+        future::poll_fn(|cx| {
+            let mut s = self.0.borrow_mut();
+            match s.get_state() {
+                pulse::stream::State::Ready => {
+                    s.set_state_callback(None);
+                    Poll::Ready(Ok(()))
+                }
+                pulse::stream::State::Failed | pulse::stream::State::Terminated => {
+                    s.set_state_callback(None);
+                    Poll::Ready(Err(ctx.errno().into()))
+                }
+                _ => {
+                    let waker = cx.waker().clone();
+                    s.set_state_callback(Some(Box::new(move || waker.wake_by_ref())));
+                    Poll::Pending
                 }
-            };
-            if let Poll::Ready(ret) = poll {
-                ret2.take().unwrap().set(Some(Ok(ret)));
-                looop2.quit(pulse::def::Retval(0));
             }
-        }));
-        let mut ev = ev.unwrap();
-        let _ev = self.looop.new_io_event(
-            self.wakee.as_raw_fd(),
-            mainloop::events::io::FlagSet::INPUT,
-            Box::new(move |_, _, _| {
-                let mut buf = [0u8; 16];
-                while let Ok(16) = self.wakee.read(&mut buf) {}
-                ev.enable();
-            }),
+        })
+        .await
+    }
+}
+impl Drop for OwnedStream {
+    fn drop(&mut self) {
+        let mut stream = self.0.borrow_mut();
+        stream.set_read_callback(None);
+        stream.set_write_callback(None);
+        let _ = stream.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_maps() {
+        for ok in [
+            "front-left,front-right",
+            "mono",
+            "surround-51",
+            "aux0,aux31,lfe",
+        ] {
+            assert!(check_channel_map(ok).is_ok(), "{ok}");
+        }
+        for bad in [
+            "",
+            "front-left, front-right",
+            "aux32",
+            "left right",
+            "left\"",
+            "x",
+        ] {
+            assert!(check_channel_map(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn bad_channel_maps_stay_out_of_the_arguments() {
+        let mut config = AudioConfig::for_test(AudioSource::Named("in".to_owned()));
+        config.loopback.channel_map = Some("left,right".to_owned());
+        assert!(loopback_args(3, &config).ends_with(" channel_map=left,right"));
+        config.loopback.channel_map = Some("left sink=evil".to_owned());
+        let args = loopback_args(3, &config);
+        assert!(
+            !args.contains("channel_map") && !args.contains("evil"),
+            "{args}"
+        );
+    }
+
+    #[test]
+    fn other_instances_volume_is_left_alone() {
+        let pid = std::process::id().to_string();
+        assert!(is_ours(Some("ccdisplay"), Some(&pid)));
+        assert!(!is_ours(Some("ccdisplay"), Some("1")));
+        assert!(!is_ours(Some("ccdisplay"), None));
+        assert!(!is_ours(Some("firefox"), Some(&pid)));
+        // what the loopback tags its sink input with has to match
+        let args = loopback_args(
+            0,
+            &AudioConfig::for_test(AudioSource::Named("in".to_owned())),
         );
-        // ev
-        self.looop.run().unwrap();
-        let res = Rc::try_unwrap(ret)
-            .unwrap_or_else(|_| panic!("aaaaa"))
-            .into_inner()
+        assert!(args.contains(&format!("{PID_PROP}={pid}\"")), "{args}");
+    }
+
+    #[test]
+    fn usb_matches_pulse_and_pipewire_props() {
+        let card = UsbMatch {
+            vendor_id: Some(0x534d),
+            product_id: Some(0x2109),
+            port_path: Some("1.4".to_owned()),
+        };
+        let props = |pairs: &'static [(&str, &str)]| {
+            move |key: &str| {
+                let value = pairs.iter().find(|(k, _)| *k == key)?.1;
+                Some(value.to_owned())
+            }
+        };
+        // pulse: no 0x, bus path with dots
+        let pulse = |path| {
+            card.matches(
+                props(path),
+                "device.vendor.id",
+                "device.product.id",
+                "device.bus_path",
+            )
+        };
+        assert!(pulse(&[
+            ("device.vendor.id", "534d"),
+            ("device.product.id", "2109"),
+            ("device.bus_path", "pci-0000:00:14.0-usb-0:1.4:1.2"),
+        ]));
+        assert!(!pulse(&[
+            ("device.vendor.id", "534d"),
+            ("device.product.id", "2109"),
+            ("device.bus_path", "pci-0000:00:14.0-usb-0:1.45:1.2"),
+        ]));
+        assert!(!pulse(&[
+            ("device.vendor.id", "534d"),
+            ("device.product.id", "2110"),
+            ("device.bus_path", "pci-0000:00:14.0-usb-0:1.4:1.2"),
+        ]));
+        // pipewire: 0x in front, bus-path with a dash, and sometimes no path
+        let pipewire = |path| {
+            card.matches(
+                props(path),
+                "device.vendor.id",
+                "device.product.id",
+                "device.bus-path",
+            )
+        };
+        assert!(pipewire(&[
+            ("device.vendor.id", "0x534d"),
+            ("device.product.id", "0x2109"),
+            ("device.bus-path", "pci-0000:00:14.0-usb-0:1.4:1.2"),
+        ]));
+        assert!(pipewire(&[
+            ("device.vendor.id", "0x534d"),
+            ("device.product.id", "0x2109"),
+        ]));
+        assert!(!pipewire(&[("device.vendor.id", "0x534e")]));
+        // nothing to go on matches nothing
+        assert!(!UsbMatch::default().matches(
+            props(&[("device.vendor.id", "534d")]),
+            "device.vendor.id",
+            "device.product.id",
+            "device.bus_path",
+        ));
+    }
+
+    #[test]
+    fn only_the_panicking_threads_modules_are_orphaned() {
+        let this = std::thread::current().id();
+        let other = std::thread::spawn(|| std::thread::current().id())
+            .join()
+            .unwrap();
+        let mut loaded = vec![(this, 1), (other, 2), (this, 3)];
+        assert_eq!(orphaned_by(&mut loaded, other, false), [2]);
+        assert_eq!(loaded, [(this, 1), (this, 3)]);
+        assert_eq!(orphaned_by(&mut loaded, other, false), []);
+        assert_eq!(orphaned_by(&mut loaded, other, true), [1, 3]);
+        assert!(loaded.is_empty());
+    }
+
+    #[test]
+    fn loopbacks_from_a_reused_pid_are_orphans() {
+        let arg = |pid: u32| {
+            format!("sink_input_properties=\"media.software=ccdisplay {PID_PROP}={pid}\"")
+        };
+        assert!(!is_orphan(&arg(std::process::id())));
+        // gone for good
+        assert!(is_orphan(&arg(u32::MAX)));
+        assert!(is_orphan("sink_input_properties=media.software=ccdisplay"));
+        // alive, but not a ccdisplay
+        let mut sleep = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
             .unwrap();
-        match res {
-            Ok(ret) => ret,
-            Err(e) => std::panic::resume_unwind(e),
+        let orphan = is_orphan(&arg(sleep.id()));
+        sleep.kill().unwrap();
+        sleep.wait().unwrap();
+        assert!(orphan);
+    }
+
+    fn with_pulse<R: 'static, F: future::Future<Output = pa::Result<R>> + 'static>(
+        f: impl FnOnce(Rc<pa::Context>) -> F,
+    ) -> R {
+        let rt = PaRuntime::new();
+        let ctx = Rc::new(rt.make_context("ccdisplay test"));
+        let fut = f(ctx.clone());
+        rt.run(async move {
+            ctx.connect().await?;
+            fut.await
+        })
+        .unwrap()
+    }
+
+    fn load_null_sink() -> u32 {
+        let args = format!("sink_name=ccdisplay-test-{}", std::process::id());
+        with_pulse(|ctx| async move { ctx.load_module("module-null-sink", &args).await })
+    }
+
+    fn module_loaded(mod_id: u32) -> bool {
+        with_pulse(|ctx| async move {
+            let found = ctx
+                .module_info_list(move |info| (info.index == mod_id).then_some(()))
+                .await?;
+            Ok(!found.is_empty())
+        })
+    }
+
+    /// needs a pulse server (pipewire-pulse works too)
+    #[test]
+    fn panic_hook_unloads_what_the_panicking_thread_loaded() {
+        if !pa::test_server() {
+            return eprintln!("no pulse server, skipping");
         }
+        static HOOK: std::sync::Once = std::sync::Once::new();
+        HOOK.call_once(install_panic_hook);
+        let (mine, theirs) = (load_null_sink(), load_null_sink());
+        remember_module(theirs);
+
+        let audio = std::thread::spawn(move || {
+            remember_module(mine);
+            panic!("an audio thread going down on purpose");
+        });
+        assert!(audio.join().is_err());
+        assert!(!module_loaded(mine));
+
+        let bystander = std::thread::spawn(|| panic!("some other thread going down on purpose"));
+        assert!(bystander.join().is_err());
+        assert!(module_loaded(theirs));
+
+        loaded_modules().retain(|&(_, m)| m != theirs);
+        with_pulse(move |ctx| async move { ctx.unload_module(theirs).await });
     }
 }
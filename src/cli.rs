@@ -0,0 +1,138 @@
+use clap::{Parser, Subcommand};
+
+use crate::format::{self, FormatChoice};
+use crate::pa::PaRuntime;
+use crate::{v4l2, video, VideoDevice};
+
+/// show a capture card in a window, audio and all. anything given here wins
+/// over the saved settings, for this run
+#[derive(Parser)]
+#[command(name = "ccdisplay", version)]
+pub(crate) struct Args {
+    /// saved profile to start with, instead of the last one used
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// video device: a uvc VID:PID like 534d:2109 (either half can be left
+    /// empty, and ,serial=SN or ,port=BUS-PORTS can pick between identical
+    /// ones), a v4l2 node like /dev/video0, or testpattern[:WxH@FPS]
+    #[arg(long, value_name = "DEVICE")]
+    pub video: Option<VideoDevice>,
+    /// pulse source to play, or "auto" to follow the video device
+    #[arg(long, value_name = "SOURCE")]
+    pub audio: Option<String>,
+    /// mode to use, like 1920x1080@60 or "1920x1080@60 mjpeg", or how to pick
+    /// one, like policy:fps,resolution
+    #[arg(long, value_parser = parse_format)]
+    pub format: Option<FormatChoice>,
+    /// start out fullscreen
+    #[arg(long)]
+    pub fullscreen: bool,
+    /// window title
+    #[arg(long)]
+    pub title: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// things to do instead of opening a window. --list-devices and
+/// --list-formats still work, that's how they were first spelled
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// print the video devices and pulse sources around
+    #[command(long_flag_alias = "list-devices")]
+    ListDevices,
+    /// print the modes a video device can do, best first
+    #[command(long_flag_alias = "list-formats")]
+    ListFormats {
+        /// same as --video, every device we can find if it's left out
+        device: Option<VideoDevice>,
+    },
+}
+
+// settings store pins as pin:MODE, but a bare mode is what you'd type
+fn parse_format(s: &str) -> anyhow::Result<FormatChoice> {
+    if s.contains('@') && !s.starts_with("pin:") {
+        return Ok(FormatChoice::Pinned(s.parse()?));
+    }
+    s.parse()
+}
+
+pub(crate) fn list_devices() {
+    println!("video devices:");
+    match video::uvc_devices() {
+        Ok(devs) => {
+            for dev in devs {
+                println!(
+                    "  {}  {} {}",
+                    VideoDevice::Uvc(dev.id),
+                    dev.desc.manufacturer.as_deref().unwrap_or(""),
+                    dev.desc.product.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Err(e) => eprintln!("couldn't list uvc devices: {e}"),
+    }
+    for node in v4l2::list_devices() {
+        println!("  {}  {}", node.path.display(), node.name);
+    }
+    println!("  testpattern");
+
+    println!("audio sources:");
+    let rt = PaRuntime::new();
+    let ctx = rt.make_context("ccdisplay");
+    let sources = rt.run(async move {
+        ctx.connect().await?;
+        ctx.source_info_list(|info| {
+            let name = info.name.as_deref()?.to_owned();
+            Some((name, info.description.as_deref().map(str::to_owned)))
+        })
+        .await
+    });
+    match sources {
+        Ok(sources) => {
+            for (name, desc) in sources {
+                println!("  {name}  {}", desc.unwrap_or_default());
+            }
+        }
+        Err(e) => eprintln!("couldn't list pulse sources: {e}"),
+    }
+}
+
+/// modes for `video`, or for every device we can find if it's None. best
+/// first, by the default policy
+pub(crate) fn list_formats(video: Option<&VideoDevice>) {
+    let devices = match video {
+        Some(dev) => vec![dev.clone()],
+        None => {
+            let uvc = video::uvc_devices().unwrap_or_else(|e| {
+                eprintln!("couldn't list uvc devices: {e}");
+                vec![]
+            });
+            let uvc = uvc.into_iter().map(|dev| VideoDevice::Uvc(dev.id));
+            let v4l2 = v4l2::list_devices()
+                .into_iter()
+                .map(|node| VideoDevice::V4l2(node.path));
+            uvc.chain(v4l2).collect()
+        }
+    };
+    for dev in &devices {
+        let modes = match dev {
+            VideoDevice::Uvc(id) => video::uvc_device_modes(id),
+            VideoDevice::V4l2(path) => v4l2::modes(path),
+            VideoDevice::TestPattern(params) => Ok(vec![params.mode()]),
+        };
+        let mut modes = match modes {
+            Ok(modes) => modes,
+            Err(e) => {
+                eprintln!("couldn't get modes for {dev}: {e:#}");
+                continue;
+            }
+        };
+        modes.sort_by(|a, b| format::compare(b, a, format::DEFAULT_POLICY));
+        modes.dedup();
+        println!("{dev}:");
+        for mode in modes {
+            println!("  {mode}");
+        }
+    }
+}
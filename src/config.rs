@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::{fs, io, iter};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use toml::value::{Table, Value};
+
+use crate::audio::{self, AudioBackend, AudioMode, Volume};
+use crate::convert::{YuvMatrix, YuvRange};
+use crate::extra::Layout;
+use crate::format::FormatChoice;
+use crate::settings::MAX_AV_OFFSET;
+use crate::{DeviceId, VideoDevice};
+
+/// bump this and add to MIGRATIONS whenever an old file wouldn't mean the same
+/// thing anymore
+const VERSION: i64 = 1;
+
+/// MIGRATIONS[n] takes a version n config to version n + 1. version 0 is the
+/// keys eframe's storage used to hold, from before there was a config file
+const MIGRATIONS: &[fn(&mut Table) -> anyhow::Result<()>] = &[from_storage_keys];
+
+/// ~/.config/ccdisplay/config.toml. see the readme for what goes in it
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct Config {
+    version: i64,
+    /// the profile in use, empty for the default one
+    pub profile: String,
+    pub general: General,
+    pub loopback: Loopback,
+    pub replay: Replay,
+    /// the default profile
+    pub default: Profile,
+    pub profiles: BTreeMap<String, Profile>,
+    /// ms, by video device. every card's a little different
+    pub av_offsets: BTreeMap<String, i32>,
+}
+
+/// what stays the same whichever profile's picked
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct General {
+    /// needs a restart to change
+    pub audio_backend: AudioBackend,
+    pub volume: u32,
+    pub muted: bool,
+    pub record_dir: PathBuf,
+    pub screenshot_dir: PathBuf,
+    pub latency_csv: bool,
+}
+
+/// module-loopback's knobs
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct Loopback {
+    /// seconds, 0 to never adjust
+    pub adjust_time: u32,
+    /// empty for the same as the source
+    pub channel_map: String,
+    /// left out for the same as the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct Replay {
+    pub enabled: bool,
+    pub secs: u32,
+    pub max_mb: u32,
+}
+
+/// the settings that go with a console rather than with ccdisplay as a whole
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct Profile {
+    pub title: String,
+    /// same as --video
+    #[serde(with = "string")]
+    pub video: VideoDevice,
+    /// what the device was called when it got picked, to show in the settings
+    /// window while it's unplugged
+    pub video_name: String,
+    /// same as the settings window's format picker, pin:MODE or policy:...
+    #[serde(with = "string")]
+    pub format: FormatChoice,
+    pub gpu_yuv: bool,
+    pub yuv_matrix: YuvMatrix,
+    pub yuv_range: YuvRange,
+    /// empty to follow the video device
+    pub audio_source: String,
+    /// empty for the default sink
+    pub audio_sink: String,
+    pub audio_mode: AudioMode,
+    /// ms
+    pub audio_latency: u32,
+    /// how to fit `extra` in alongside the main source
+    pub layout: Layout,
+    /// more sources to show at the same time, for a second console
+    pub extra: Vec<ExtraSource>,
+}
+
+/// another source shown alongside the profile's main one. it only gets video
+/// and audio, recording and screenshots and the rest stick with the main one
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExtraSource {
+    /// same as --video
+    #[serde(with = "string")]
+    pub video: VideoDevice,
+    /// like the profile's, for showing while it's unplugged
+    #[serde(default)]
+    pub video_name: String,
+    #[serde(with = "string", default)]
+    pub format: FormatChoice,
+    /// empty to follow the video device
+    #[serde(default)]
+    pub audio_source: String,
+}
+
+impl Default for ExtraSource {
+    fn default() -> Self {
+        Self {
+            video: VideoDevice::Uvc(DeviceId::default()),
+            video_name: String::new(),
+            format: FormatChoice::default(),
+            audio_source: String::new(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            profile: String::new(),
+            general: Default::default(),
+            loopback: Default::default(),
+            replay: Default::default(),
+            default: Default::default(),
+            profiles: BTreeMap::new(),
+            av_offsets: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for General {
+    fn default() -> Self {
+        Self {
+            audio_backend: AudioBackend::default(),
+            volume: 100,
+            muted: false,
+            record_dir: home_dir("Videos"),
+            screenshot_dir: home_dir("Pictures"),
+            latency_csv: false,
+        }
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Self {
+            adjust_time: 10,
+            channel_map: String::new(),
+            rate: None,
+        }
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secs: 30,
+            max_mb: 512,
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            title: "CCDisplay".to_owned(),
+            video: VideoDevice::Uvc(DeviceId::default()),
+            video_name: String::new(),
+            format: FormatChoice::default(),
+            gpu_yuv: false,
+            yuv_matrix: YuvMatrix::default(),
+            yuv_range: YuvRange::default(),
+            audio_source: String::new(),
+            audio_sink: String::new(),
+            audio_mode: AudioMode::default(),
+            audio_latency: 40,
+            layout: Layout::default(),
+            extra: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// the config file, or None if there isn't one yet
+    pub fn load() -> anyhow::Result<Option<Self>> {
+        let path = path();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("couldn't read {}", path.display())),
+        };
+        Self::parse(&text)
+            .with_context(|| format!("problem with {}", path.display()))
+            .map(Some)
+    }
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let table: Table = toml::from_str(text)?;
+        let version = match table.get("version") {
+            Some(Value::Integer(version)) => *version,
+            Some(_) => anyhow::bail!("version should be a number"),
+            None => anyhow::bail!("missing version = {VERSION} at the top"),
+        };
+        match version {
+            // straight from the text, so errors come with line numbers
+            VERSION => Self::check(toml::from_str(text)?),
+            v if (0..VERSION).contains(&v) => Self::migrate(table, v),
+            v => anyhow::bail!(
+                "version {v} is from a newer ccdisplay, this one goes up to {VERSION}"
+            ),
+        }
+    }
+    /// bring over the settings eframe was storing before there was a file
+    pub fn from_storage(storage: &dyn eframe::Storage) -> anyhow::Result<Self> {
+        Self::migrate(storage_keys(storage), 0)
+    }
+    fn migrate(mut table: Table, version: i64) -> anyhow::Result<Self> {
+        for (v, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            migration(&mut table)
+                .with_context(|| format!("couldn't update it from version {v}"))?;
+            table.insert("version".to_owned(), Value::Integer(v as i64 + 1));
+        }
+        Self::check(Value::Table(table).try_into()?)
+    }
+    /// the things serde can't tell are wrong
+    fn check(self) -> anyhow::Result<Self> {
+        fn in_range<T: PartialOrd + Display>(
+            name: &str,
+            value: T,
+            range: RangeInclusive<T>,
+        ) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                range.contains(&value),
+                "{name} should be between {} and {}, not {value}",
+                range.start(),
+                range.end()
+            );
+            Ok(())
+        }
+        in_range(
+            "general.volume",
+            self.general.volume,
+            0..=Volume::MAX_PERCENT,
+        )?;
+        in_range("loopback.adjust_time", self.loopback.adjust_time, 0..=60)?;
+        in_range("replay.secs", self.replay.secs, 1..=600)?;
+        in_range("replay.max_mb", self.replay.max_mb, 16..=16384)?;
+        let map = self.loopback.channel_map.trim();
+        if !map.is_empty() {
+            audio::check_channel_map(map).context("loopback.channel_map")?;
+        }
+        let profiles = self
+            .profiles
+            .iter()
+            .map(|(name, p)| (format!("profiles.{name}"), p));
+        for (name, profile) in iter::once(("default".to_owned(), &self.default)).chain(profiles) {
+            in_range(
+                &format!("{name}.audio_latency"),
+                profile.audio_latency,
+                10..=2000,
+            )?;
+        }
+        for (dev, &ms) in &self.av_offsets {
+            in_range(
+                &format!("av_offsets.{dev:?}"),
+                ms,
+                -MAX_AV_OFFSET..=MAX_AV_OFFSET,
+            )?;
+        }
+        Ok(self)
+    }
+    /// write it all out, complaining if that doesn't work
+    pub fn save(&self) {
+        let path = path();
+        if let Err(e) = self.write(&path) {
+            eprintln!("couldn't save settings to {}: {e:#}", path.display());
+        }
+    }
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let text = toml::to_string_pretty(self)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // so a crash halfway through can't leave half a file
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+    /// "" for the default profile, then the named ones
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        iter::once("").chain(self.profiles.keys().map(String::as_str))
+    }
+    /// the default profile for "" or one that doesn't exist
+    pub fn get_profile(&self, name: &str) -> &Profile {
+        self.profiles.get(name).unwrap_or(&self.default)
+    }
+}
+
+pub(crate) fn av_offset_key(dev: &VideoDevice) -> String {
+    match dev {
+        // no point keeping track of the pattern's mode
+        VideoDevice::TestPattern(_) => "testpattern".to_owned(),
+        dev => dev.to_string(),
+    }
+}
+
+fn path() -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home_dir(".config"))
+        .join("ccdisplay")
+        .join("config.toml")
+}
+
+fn home_dir(sub: &str) -> PathBuf {
+    std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(sub))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// for the types that already have a string form, like devices and formats
+mod string {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(d)?;
+        s.parse()
+            .map_err(|e| de::Error::custom(format!("{s:?}: {e}")))
+    }
+}
+
+// everything below is for carrying over the old eframe storage
+
+/// all the last release stored, from before there were profiles
+const STORAGE_KEYS: &[&str] = &["windowtitle", "pid", "vid", "vidname", "audname"];
+
+/// version 0: the ccdisplay.* strings, minus the ccdisplay. bit
+fn storage_keys(storage: &dyn eframe::Storage) -> Table {
+    let mut table = Table::new();
+    for key in STORAGE_KEYS {
+        if let Some(value) = storage.get_string(&format!("ccdisplay.{key}")) {
+            table.insert((*key).to_owned(), Value::String(value));
+        }
+    }
+    table
+}
+
+fn get<'a>(table: &'a Table, key: &str) -> Option<&'a str> {
+    match table.get(key) {
+        Some(Value::String(s)) if !s.is_empty() => Some(s),
+        _ => None,
+    }
+}
+
+/// version 0 to 1. the old code fell back to the defaults for anything it
+/// couldn't make sense of, so this leaves those out and lets them default
+fn from_storage_keys(table: &mut Table) -> anyhow::Result<()> {
+    let old = std::mem::take(table);
+    let string = |key: &str| get(&old, key).map(|s| Value::String(s.to_owned()));
+    // decimal, and if either was bad neither got used
+    let id = |key| get(&old, key).map(str::parse::<u16>).transpose();
+    let video = match (id("vid"), id("pid")) {
+        (Ok(vendor_id), Ok(product_id)) => VideoDevice::Uvc(DeviceId {
+            vendor_id,
+            product_id,
+            ..Default::default()
+        }),
+        _ => VideoDevice::Uvc(DeviceId::default()),
+    };
+    let fields = [
+        ("title", string("windowtitle")),
+        ("video", Some(Value::String(video.to_string()))),
+        ("video_name", string("vidname")),
+        ("audio_source", string("audname")),
+    ];
+    let fields = fields
+        .into_iter()
+        .filter_map(|(k, v)| Some((k.to_owned(), v?)));
+    table.insert("default".to_owned(), Value::Table(fields.collect()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(keys: &[(&str, &str)]) -> Table {
+        let keys = keys
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), Value::String((*v).to_owned())));
+        keys.collect()
+    }
+
+    #[test]
+    fn old_storage_comes_over() {
+        let config = Config::migrate(
+            storage(&[
+                ("windowtitle", "Switch"),
+                ("vid", "21325"),
+                ("pid", "8457"),
+                ("vidname", "USB Video"),
+                ("audname", "alsa_input.usb"),
+            ]),
+            0,
+        )
+        .unwrap();
+        assert_eq!(config.version, VERSION);
+        assert_eq!(config.default.title, "Switch");
+        assert!(config.default.video == "534d:2109".parse().unwrap());
+        assert_eq!(config.default.video_name, "USB Video");
+        assert_eq!(config.default.audio_source, "alsa_input.usb");
+        assert!(config.profiles.is_empty());
+    }
+
+    #[test]
+    fn bad_ids_are_dropped_together() {
+        let config = Config::migrate(storage(&[("vid", "21325"), ("pid", "nope")]), 0).unwrap();
+        assert!(config.default.video == VideoDevice::Uvc(DeviceId::default()));
+    }
+}
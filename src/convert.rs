@@ -0,0 +1,316 @@
+use std::time::Instant;
+
+use anyhow::Context;
+use egui::Color32;
+use serde::{Deserialize, Serialize};
+use zune_jpeg::zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PixelFormat {
+    /// packed 8-bit r, g, b
+    Rgb24,
+    /// packed 4:2:2, y0 u y1 v
+    Yuyv,
+    /// a full-size y plane followed by an interleaved half-size uv plane
+    Nv12,
+    /// each frame is a whole jpeg
+    Mjpeg,
+}
+
+impl PixelFormat {
+    /// how many bytes an uncompressed frame of this size should take up. odd
+    /// sizes round the chroma up, so the last column/row still gets its own
+    pub fn frame_len(self, width: usize, height: usize) -> Option<usize> {
+        let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+        match self {
+            PixelFormat::Rgb24 => Some(width * height * 3),
+            PixelFormat::Yuyv => Some(cw * 4 * height),
+            PixelFormat::Nv12 => Some(width * height + cw * 2 * ch),
+            PixelFormat::Mjpeg => None,
+        }
+    }
+}
+
+/// one frame as it came out of a source, before any conversion
+#[derive(Copy, Clone)]
+pub(crate) struct Frame<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub data: &'a [u8],
+    /// when the source handed it to us
+    pub captured: Instant,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum YuvMatrix {
+    /// what sd sources (and in practice most capture cards) use
+    #[default]
+    Bt601,
+    Bt709,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum YuvRange {
+    /// y in 16..=235, chroma in 16..=240
+    #[default]
+    Limited,
+    Full,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Colorimetry {
+    pub matrix: YuvMatrix,
+    pub range: YuvRange,
+}
+
+impl Colorimetry {
+    /// `rgb = matrix * (yuv - offset)`, everything normalized to 0..=1. the
+    /// shader uses this as-is and the cpu path is a fixed point version of it,
+    /// so the two should never disagree by more than rounding
+    pub fn transform(self) -> ([[f32; 3]; 3], [f32; 3]) {
+        let (kr, kb) = match self.matrix {
+            YuvMatrix::Bt601 => (0.299, 0.114),
+            YuvMatrix::Bt709 => (0.2126, 0.0722),
+        };
+        let kg = 1.0 - kr - kb;
+        let (y_off, y_scale, c_scale) = match self.range {
+            YuvRange::Limited => (16.0 / 255.0, 255.0 / 219.0, 255.0 / 224.0),
+            YuvRange::Full => (0.0, 1.0, 1.0),
+        };
+        let matrix = [
+            [y_scale, 0.0, c_scale * 2.0 * (1.0 - kr)],
+            [
+                y_scale,
+                c_scale * -2.0 * kb * (1.0 - kb) / kg,
+                c_scale * -2.0 * kr * (1.0 - kr) / kg,
+            ],
+            [y_scale, c_scale * 2.0 * (1.0 - kb), 0.0],
+        ];
+        (matrix, [y_off, 128.0 / 255.0, 128.0 / 255.0])
+    }
+}
+
+/// `Colorimetry::transform` in 8.8 fixed point
+struct YuvCoeffs {
+    y_off: i32,
+    y: i32,
+    rv: i32,
+    gu: i32,
+    gv: i32,
+    bu: i32,
+}
+impl From<Colorimetry> for YuvCoeffs {
+    fn from(c: Colorimetry) -> Self {
+        let (m, off) = c.transform();
+        let fix = |x: f32| (x * 256.0).round() as i32;
+        YuvCoeffs {
+            y_off: (off[0] * 255.0).round() as i32,
+            y: fix(m[0][0]),
+            rv: fix(m[0][2]),
+            gu: fix(-m[1][1]),
+            gv: fix(-m[1][2]),
+            bu: fix(m[2][1]),
+        }
+    }
+}
+
+pub(crate) fn to_color_image(
+    frame: &Frame<'_>,
+    colorimetry: Colorimetry,
+) -> anyhow::Result<egui::ColorImage> {
+    let Frame {
+        width,
+        height,
+        format,
+        data,
+        ..
+    } = *frame;
+    anyhow::ensure!(
+        width > 0 && height > 0,
+        "empty {format:?} frame ({width}x{height})"
+    );
+    if let Some(len) = format.frame_len(width, height) {
+        anyhow::ensure!(
+            data.len() >= len,
+            "short {format:?} frame ({} bytes, expected {len})",
+            data.len()
+        );
+    }
+    let k = YuvCoeffs::from(colorimetry);
+    let mut pixels = bytemuck::zeroed_vec::<Color32>(width * height);
+    let out: &mut [[u8; 4]] = bytemuck::cast_slice_mut(&mut pixels);
+    match format {
+        PixelFormat::Rgb24 => rgb24(data, out),
+        PixelFormat::Yuyv => yuyv(data, width, &k, out),
+        PixelFormat::Nv12 => nv12(data, width, &k, out),
+        PixelFormat::Mjpeg => mjpeg(data, width, height, bytemuck::cast_slice_mut(out))?,
+    }
+    Ok(egui::ColorImage {
+        size: [width, height],
+        pixels,
+    })
+}
+
+// compiles each fn twice on x86_64, once normally and once with avx2 enabled,
+// and picks at runtime. the loops are simple enough that llvm vectorizes them
+// either way, this just lets it use the wide registers when they're there
+macro_rules! simd_dispatch {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) $body:block)*) => {$(
+        fn $name($($arg: $ty),*) {
+            #[inline(always)]
+            fn imp($($arg: $ty),*) $body
+            #[cfg(target_arch = "x86_64")]
+            {
+                #[target_feature(enable = "avx2")]
+                unsafe fn avx2($($arg: $ty),*) {
+                    imp($($arg),*)
+                }
+                if is_x86_feature_detected!("avx2") {
+                    // SAFETY: we just checked the cpu supports avx2
+                    return unsafe { avx2($($arg),*) };
+                }
+            }
+            imp($($arg),*)
+        }
+    )*};
+}
+
+#[inline(always)]
+fn yuv_to_rgba(y: u8, u: i32, v: i32, k: &YuvCoeffs) -> [u8; 4] {
+    let c = (y as i32 - k.y_off) * k.y + 128;
+    let clamp = |x: i32| (x >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + k.rv * v),
+        clamp(c - k.gu * u - k.gv * v),
+        clamp(c + k.bu * u),
+        255,
+    ]
+}
+
+simd_dispatch! {
+    fn rgb24(src: &[u8], out: &mut [[u8; 4]]) {
+        for (src, out) in src.chunks_exact(3).zip(out) {
+            *out = [src[0], src[1], src[2], 255];
+        }
+    }
+
+    fn yuyv(src: &[u8], width: usize, k: &YuvCoeffs, out: &mut [[u8; 4]]) {
+        let line = width.div_ceil(2) * 4;
+        for (src, out) in src.chunks_exact(line).zip(out.chunks_exact_mut(width)) {
+            let mut pairs = out.chunks_exact_mut(2);
+            for (src, out) in src.chunks_exact(4).zip(&mut pairs) {
+                let (u, v) = (src[1] as i32 - 128, src[3] as i32 - 128);
+                out[0] = yuv_to_rgba(src[0], u, v, k);
+                out[1] = yuv_to_rgba(src[2], u, v, k);
+            }
+            // an odd width leaves the last y1 unused
+            if let [last] = pairs.into_remainder() {
+                let src = &src[line - 4..];
+                *last = yuv_to_rgba(src[0], src[1] as i32 - 128, src[3] as i32 - 128, k);
+            }
+        }
+    }
+
+    fn nv12(src: &[u8], width: usize, k: &YuvCoeffs, out: &mut [[u8; 4]]) {
+        let height = out.len() / width;
+        let uv_line = width.div_ceil(2) * 2;
+        let (y_plane, uv_plane) = src.split_at(width * height);
+        for (row, out) in out.chunks_exact_mut(width).enumerate() {
+            let y_row = &y_plane[row * width..][..width];
+            let uv_row = &uv_plane[row / 2 * uv_line..][..uv_line];
+            let mut pairs = out.chunks_exact_mut(2);
+            for ((y, uv), out) in y_row
+                .chunks_exact(2)
+                .zip(uv_row.chunks_exact(2))
+                .zip(&mut pairs)
+            {
+                let (u, v) = (uv[0] as i32 - 128, uv[1] as i32 - 128);
+                out[0] = yuv_to_rgba(y[0], u, v, k);
+                out[1] = yuv_to_rgba(y[1], u, v, k);
+            }
+            if let [last] = pairs.into_remainder() {
+                let uv = &uv_row[uv_line - 2..];
+                let (u, v) = (uv[0] as i32 - 128, uv[1] as i32 - 128);
+                *last = yuv_to_rgba(y_row[width - 1], u, v, k);
+            }
+        }
+    }
+}
+
+fn mjpeg(data: &[u8], width: usize, height: usize, out: &mut [u8]) -> anyhow::Result<()> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGBA);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(data, options);
+    decoder.decode_headers().context("bad mjpeg frame")?;
+    let dims = decoder.dimensions().context("bad mjpeg frame")?;
+    anyhow::ensure!(
+        dims == (width, height),
+        "mjpeg frame is {dims:?}, expected {:?}",
+        (width, height)
+    );
+    decoder.decode_into(out).context("bad mjpeg frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: usize, height: usize, format: PixelFormat, data: &[u8]) -> Frame<'_> {
+        Frame {
+            width,
+            height,
+            format,
+            data,
+            captured: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn empty_frames_are_rejected() {
+        for format in [PixelFormat::Yuyv, PixelFormat::Nv12, PixelFormat::Rgb24] {
+            for (w, h) in [(0, 0), (0, 4), (4, 0)] {
+                let frame = frame(w, h, format, &[0; 64]);
+                assert!(to_color_image(&frame, Colorimetry::default()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn short_frames_are_rejected() {
+        let frame = frame(4, 4, PixelFormat::Yuyv, &[0; 31]);
+        assert!(to_color_image(&frame, Colorimetry::default()).is_err());
+    }
+
+    #[test]
+    fn limited_range_black_and_white() {
+        // y0 u y1 v: black then white, no chroma
+        let data = [16, 128, 235, 128];
+        let image = to_color_image(&frame(2, 1, PixelFormat::Yuyv, &data), Default::default());
+        let pixels: Vec<_> = image.unwrap().pixels.iter().map(|p| p.to_array()).collect();
+        assert_eq!(pixels, [[0, 0, 0, 255], [255, 255, 255, 255]]);
+    }
+
+    #[test]
+    fn odd_widths_keep_the_last_column() {
+        // 3x1, the second pair's y1 is padding
+        let data = [16, 128, 16, 128, 235, 128, 0, 128];
+        let image = to_color_image(&frame(3, 1, PixelFormat::Yuyv, &data), Default::default());
+        assert_eq!(image.unwrap().pixels[2].to_array(), [255, 255, 255, 255]);
+        // 3x3 nv12 has a 2x2 chroma plane
+        let mut data = vec![235; 9];
+        data.extend([128; 8]);
+        let image = to_color_image(&frame(3, 3, PixelFormat::Nv12, &data), Default::default());
+        assert!(image
+            .unwrap()
+            .pixels
+            .iter()
+            .all(|p| p.to_array() == [255; 4]));
+        assert!(to_color_image(
+            &frame(3, 3, PixelFormat::Nv12, &data[..15]),
+            Default::default()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::iter;
+use std::sync::{Arc, Mutex};
+
+use eframe::glow;
+use egui::{Rect, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{AudioBackend, AudioConfig, AudioParams, Volume};
+use crate::gpu::GpuDisplay;
+use crate::record::Recorder;
+use crate::stats::Stats;
+use crate::video::{self, RenderOptions};
+use crate::{VideoConfig, TEXTURE_FILTER};
+
+/// where the extra sources go
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Layout {
+    /// everything the same size, main source first
+    #[default]
+    Grid,
+    /// the main source fills the window, the others go small in the corner
+    Pip,
+    /// the others get a window each, on top of the main source
+    Windows,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 3] = [Layout::Grid, Layout::Pip, Layout::Windows];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Layout::Grid => "Grid",
+            Layout::Pip => "Picture in picture",
+            Layout::Windows => "Separate windows",
+        }
+    }
+
+    /// where each of `n` sources goes in `full`, main one first. the windows
+    /// layout only places the main one
+    pub fn areas(self, full: Rect, n: usize) -> Vec<Rect> {
+        match self {
+            Layout::Grid => {
+                let cols = (n as f32).sqrt().ceil().max(1.0);
+                let rows = (n as f32 / cols).ceil().max(1.0);
+                let cell = egui::vec2(full.width() / cols, full.height() / rows);
+                let cols = cols as usize;
+                (0..n)
+                    .map(|i| {
+                        let (col, row) = ((i % cols) as f32, (i / cols) as f32);
+                        let min = full.min + egui::vec2(col * cell.x, row * cell.y);
+                        Rect::from_min_size(min, cell)
+                    })
+                    .collect()
+            }
+            Layout::Pip => {
+                // right to left along the bottom
+                let size = full.size() / 4.0;
+                let insets = (1..n).map(|i| {
+                    let right = full.right() - PIP_MARGIN - (i - 1) as f32 * (size.x + PIP_MARGIN);
+                    let bottom = full.bottom() - PIP_MARGIN;
+                    Rect::from_min_size(egui::pos2(right - size.x, bottom - size.y), size)
+                });
+                iter::once(full).chain(insets).collect()
+            }
+            Layout::Windows => vec![full],
+        }
+    }
+}
+
+const PIP_MARGIN: f32 = 16.0;
+
+/// what the extra sources should be doing
+pub(crate) struct Extras {
+    pub layout: Layout,
+    pub sources: Vec<Spec>,
+    pub render: RenderOptions,
+}
+
+/// one extra source's configs
+pub(crate) struct Spec {
+    /// for its window title
+    pub name: String,
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+}
+
+/// another capture card being shown, with its own video and audio threads.
+/// no recording, screenshots, latency or stats, those are the main one's
+pub(crate) struct Extra {
+    pub name: String,
+    texture: egui::TextureHandle,
+    gpu: GpuDisplay,
+    render_opts: Arc<Mutex<RenderOptions>>,
+    video: VideoConfig,
+    video_tx: flume::Sender<VideoConfig>,
+    audio: AudioConfig,
+    audio_tx: flume::Sender<AudioConfig>,
+    volume_tx: flume::Sender<Volume>,
+    done_tx: flume::Sender<()>,
+    finished_rx: flume::Receiver<()>,
+}
+
+impl Extra {
+    pub fn new(
+        ctx: &egui::Context,
+        gl: Option<&Arc<glow::Context>>,
+        spec: Spec,
+        render: RenderOptions,
+        backend: AudioBackend,
+        volume: Volume,
+    ) -> Self {
+        let texture = ctx.load_texture("extra", egui::ColorImage::example(), TEXTURE_FILTER);
+        let gpu = GpuDisplay::new(gl, Default::default());
+        let render_opts = Arc::new(Mutex::new(render));
+        // never started, but both threads want one
+        let (recorder, rec_rx) = Recorder::new();
+        let stats = Stats::default();
+
+        let (video_tx, video_rx) = flume::bounded(4);
+        video::run(video::CameraParams {
+            texture: texture.clone(),
+            ctx: ctx.clone(),
+            config_rx: video_rx,
+            config: spec.video.clone(),
+            modes: Default::default(),
+            gpu: gpu.frames().clone(),
+            render_opts: render_opts.clone(),
+            recorder: recorder.clone(),
+            grab: Default::default(),
+            latency: Default::default(),
+            stats: stats.clone(),
+        });
+
+        // room for one so taking it out never has to wait on the audio thread
+        let (done_tx, done_rx) = flume::bounded(1);
+        let (finished_tx, finished_rx) = flume::bounded(0);
+        let (audio_tx, audio_rx) = flume::bounded(4);
+        let (volume_tx, volume_rx) = flume::unbounded();
+        let params = AudioParams {
+            done_ch: (finished_tx, done_rx),
+            config: spec.audio.clone(),
+            config_rx: audio_rx,
+            recorder,
+            rec_rx,
+            volume,
+            volume_rx,
+            meters: Default::default(),
+            stats,
+        };
+        std::thread::spawn(move || backend.run(params));
+
+        Self {
+            name: spec.name,
+            texture,
+            gpu,
+            render_opts,
+            video: spec.video,
+            video_tx,
+            audio: spec.audio,
+            audio_tx,
+            volume_tx,
+            done_tx,
+            finished_rx,
+        }
+    }
+
+    /// pass on whatever's different about `spec`
+    pub fn update(&mut self, spec: Spec, render: RenderOptions) {
+        self.name = spec.name;
+        if spec.video != self.video {
+            self.video = spec.video;
+            let _ = self.video_tx.try_send(self.video.clone());
+        }
+        if spec.audio != self.audio {
+            self.audio = spec.audio;
+            let _ = self.audio_tx.try_send(self.audio.clone());
+        }
+        *self.render_opts.lock().unwrap() = render;
+    }
+
+    pub fn set_volume(&self, volume: Volume) {
+        let _ = self.volume_tx.send(volume);
+    }
+
+    /// the size of what it's showing, to fit it somewhere
+    pub fn size(&self) -> Vec2 {
+        self.gpu
+            .frames()
+            .size()
+            .unwrap_or_else(|| self.texture.size_vec2())
+    }
+
+    pub fn paint(&self, ui: &egui::Ui, rect: Rect) {
+        let colorimetry = self.render_opts.lock().unwrap().colorimetry;
+        crate::paint_source(ui, rect, &self.gpu, &self.texture, colorimetry);
+    }
+
+    /// shut it down. the video thread goes when `video_tx` does. `wait` for
+    /// the audio to be unplugged, on the way out
+    pub fn stop(self, gl: Option<&glow::Context>, wait: bool) {
+        if let Some(gl) = gl {
+            self.gpu.destroy(gl);
+        }
+        let _ = self.done_tx.try_send(());
+        if wait {
+            let _ = self.finished_rx.recv();
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// a mode a device can stream in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Mode {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub format: ModeFormat,
+}
+
+/// the distinction the uvc descriptors actually make
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ModeFormat {
+    Any,
+    Uncompressed,
+    Mjpeg,
+}
+
+impl From<uvc::StreamFormat> for Mode {
+    fn from(f: uvc::StreamFormat) -> Self {
+        Mode {
+            width: f.width,
+            height: f.height,
+            fps: f.fps,
+            format: match f.format {
+                uvc::FrameFormat::Any => ModeFormat::Any,
+                uvc::FrameFormat::MJPEG | uvc::FrameFormat::Compressed => ModeFormat::Mjpeg,
+                _ => ModeFormat::Uncompressed,
+            },
+        }
+    }
+}
+impl From<Mode> for uvc::StreamFormat {
+    fn from(m: Mode) -> Self {
+        uvc::StreamFormat {
+            width: m.width,
+            height: m.height,
+            fps: m.fps,
+            format: match m.format {
+                ModeFormat::Any => uvc::FrameFormat::Any,
+                ModeFormat::Uncompressed => uvc::FrameFormat::Uncompressed,
+                ModeFormat::Mjpeg => uvc::FrameFormat::MJPEG,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.format {
+            ModeFormat::Any => "any",
+            ModeFormat::Uncompressed => "uncompressed",
+            ModeFormat::Mjpeg => "mjpeg",
+        };
+        write!(f, "{}x{}@{} {format}", self.width, self.height, self.fps)
+    }
+}
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, format) = s.trim().split_once(' ').unwrap_or((s.trim(), "any"));
+        let format = match format.trim() {
+            "any" => ModeFormat::Any,
+            "uncompressed" | "yuyv" | "raw" => ModeFormat::Uncompressed,
+            "mjpeg" | "mjpg" => ModeFormat::Mjpeg,
+            other => anyhow::bail!("unknown pixel format {other:?}"),
+        };
+        let (size, fps) = mode
+            .split_once('@')
+            .ok_or_else(|| anyhow::anyhow!("expected WIDTHxHEIGHT@FPS, got {s:?}"))?;
+        let (width, height) = size
+            .split_once('x')
+            .ok_or_else(|| anyhow::anyhow!("expected WIDTHxHEIGHT@FPS, got {s:?}"))?;
+        Ok(Mode {
+            width: width.parse()?,
+            height: height.parse()?,
+            fps: fps.parse()?,
+            format,
+        })
+    }
+}
+
+impl Mode {
+    /// whether this mode satisfies `pin`, treating `Any` as a wildcard
+    fn matches(&self, pin: &Mode) -> bool {
+        (self.width, self.height, self.fps) == (pin.width, pin.height, pin.fps)
+            && (pin.format == ModeFormat::Any || self.format == pin.format)
+    }
+}
+
+/// one thing to rank modes by
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Criterion {
+    Fps,
+    Resolution,
+    Uncompressed,
+    AspectRatio,
+}
+
+const TARGET_RATIO: f32 = 16.0 / 9.0;
+
+impl Criterion {
+    pub const ALL: [Criterion; 4] = [
+        Criterion::Fps,
+        Criterion::Resolution,
+        Criterion::Uncompressed,
+        Criterion::AspectRatio,
+    ];
+
+    fn compare(self, a: &Mode, b: &Mode) -> cmp::Ordering {
+        macro_rules! prefer {
+            ($f:expr) => {{
+                let f: fn(&Mode) -> _ = $f;
+                f(a).cmp(&f(b))
+            }};
+        }
+        match self {
+            Criterion::Fps => prefer!(|x| x.fps),
+            Criterion::Resolution => prefer!(|x| x.width as u64 * x.height as u64),
+            Criterion::Uncompressed => prefer!(|x| x.format == ModeFormat::Uncompressed),
+            Criterion::AspectRatio => prefer!(|x| {
+                let ratio = x.width as f32 / x.height as f32;
+                let diff = (TARGET_RATIO - ratio).abs();
+                cmp::Reverse(ordered_float::OrderedFloat(diff))
+            }),
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Criterion::Fps => "Highest framerate",
+            Criterion::Resolution => "Highest resolution",
+            Criterion::Uncompressed => "Uncompressed",
+            Criterion::AspectRatio => "Closest to 16:9",
+        }
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            Criterion::Fps => "fps",
+            Criterion::Resolution => "resolution",
+            Criterion::Uncompressed => "uncompressed",
+            Criterion::AspectRatio => "aspect",
+        }
+    }
+}
+
+/// fps, then uncompressed, then closeness to 16:9
+pub(crate) const DEFAULT_POLICY: &[Criterion] = &[
+    Criterion::Fps,
+    Criterion::Uncompressed,
+    Criterion::AspectRatio,
+];
+
+pub(crate) fn compare(a: &Mode, b: &Mode, policy: &[Criterion]) -> cmp::Ordering {
+    policy
+        .iter()
+        .map(|c| c.compare(a, b))
+        .find(|ord| ord.is_ne())
+        .unwrap_or(cmp::Ordering::Equal)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FormatChoice {
+    /// take whatever ranks best, comparing by each criterion in turn
+    Policy(Vec<Criterion>),
+    /// always use this mode if the device has it
+    Pinned(Mode),
+}
+
+impl Default for FormatChoice {
+    fn default() -> Self {
+        FormatChoice::Policy(DEFAULT_POLICY.to_vec())
+    }
+}
+
+impl FormatChoice {
+    pub fn choose<T>(&self, modes: Vec<T>, mode: impl Fn(&T) -> Mode) -> Option<T> {
+        let policy = match self {
+            FormatChoice::Policy(policy) => policy,
+            FormatChoice::Pinned(pin) => {
+                if let Some(i) = modes.iter().position(|m| mode(m).matches(pin)) {
+                    return modes.into_iter().nth(i);
+                }
+                eprintln!("pinned format {pin} isn't available, picking automatically");
+                DEFAULT_POLICY
+            }
+        };
+        modes.into_iter().reduce(|a, b| {
+            if compare(&mode(&a), &mode(&b), policy).is_lt() {
+                b
+            } else {
+                a
+            }
+        })
+    }
+}
+
+impl fmt::Display for FormatChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatChoice::Pinned(mode) => write!(f, "pin:{mode}"),
+            FormatChoice::Policy(policy) => {
+                let keys = policy.iter().map(|c| c.key()).collect::<Vec<_>>();
+                write!(f, "policy:{}", keys.join(","))
+            }
+        }
+    }
+}
+impl FromStr for FormatChoice {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(mode) = s.strip_prefix("pin:") {
+            return Ok(FormatChoice::Pinned(mode.parse()?));
+        }
+        let policy = s.strip_prefix("policy:").unwrap_or(s);
+        let policy = policy
+            .split(',')
+            .filter(|k| !k.is_empty())
+            .map(|k| {
+                Criterion::ALL
+                    .into_iter()
+                    .find(|c| c.key() == k.trim())
+                    .ok_or_else(|| anyhow::anyhow!("unknown format criterion {k:?}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(FormatChoice::Policy(policy))
+    }
+}
+
+/// what the current device can do and what we picked, filled in by the video
+/// thread whenever it opens one
+#[derive(Default)]
+pub(crate) struct DeviceModes {
+    pub available: Vec<Mode>,
+    pub current: Option<Mode>,
+}
+
+pub(crate) type ModeList = Arc<Mutex<DeviceModes>>;
@@ -0,0 +1,496 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use eframe::egui_glow;
+use eframe::glow::{self, HasContext};
+
+use crate::convert::{Colorimetry, Frame, PixelFormat};
+
+/// a raw yuv frame waiting to be uploaded
+struct YuvFrame {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Shared {
+    enabled: bool,
+    pending: Option<YuvFrame>,
+    // the last uploaded frame's buffer, so the video thread doesn't have to
+    // allocate a new one every frame
+    spare: Vec<u8>,
+    // size of what's currently on screen, if it's us drawing it rather than
+    // the regular egui texture
+    size: Option<[usize; 2]>,
+}
+
+/// the handoff between the video thread and the paint callback
+#[derive(Clone, Default)]
+pub(crate) struct GpuFrames(Arc<Mutex<Shared>>);
+
+impl GpuFrames {
+    /// whether there's a renderer on the other end to send frames to
+    pub fn enabled(&self) -> bool {
+        self.0.lock().unwrap().enabled
+    }
+
+    pub fn supports(format: PixelFormat) -> bool {
+        matches!(format, PixelFormat::Yuyv | PixelFormat::Nv12)
+    }
+
+    pub fn push(&self, frame: &Frame<'_>) -> anyhow::Result<()> {
+        let len = frame.format.frame_len(frame.width, frame.height);
+        let data = len.and_then(|len| frame.data.get(..len)).ok_or_else(|| {
+            anyhow::anyhow!(
+                "short {:?} frame ({} bytes)",
+                frame.format,
+                frame.data.len()
+            )
+        })?;
+        let mut shared = self.0.lock().unwrap();
+        // a frame that never got painted won't be now, so its buffer's free too
+        let mut buf = match shared.pending.take() {
+            Some(skipped) => skipped.data,
+            None => mem::take(&mut shared.spare),
+        };
+        buf.clear();
+        buf.extend_from_slice(data);
+        shared.size = Some([frame.width, frame.height]);
+        shared.pending = Some(YuvFrame {
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+            data: buf,
+        });
+        Ok(())
+    }
+
+    /// go back to showing the egui texture
+    pub fn clear(&self) {
+        let mut shared = self.0.lock().unwrap();
+        shared.size = None;
+        shared.pending = None;
+    }
+
+    pub fn size(&self) -> Option<egui::Vec2> {
+        let size = self.0.lock().unwrap().size?;
+        Some(egui::vec2(size[0] as f32, size[1] as f32))
+    }
+}
+
+pub(crate) struct GpuDisplay {
+    frames: GpuFrames,
+    renderer: Option<Arc<Mutex<YuvRenderer>>>,
+}
+
+impl GpuDisplay {
+    pub fn new(gl: Option<&Arc<glow::Context>>, frames: GpuFrames) -> Self {
+        let renderer = gl.and_then(|gl| match unsafe { YuvRenderer::new(gl) } {
+            Ok(r) => Some(Arc::new(Mutex::new(r))),
+            Err(e) => {
+                eprintln!("couldn't set up gpu yuv conversion, falling back to cpu: {e:#}");
+                None
+            }
+        });
+        frames.0.lock().unwrap().enabled = renderer.is_some();
+        Self { frames, renderer }
+    }
+
+    pub fn frames(&self) -> &GpuFrames {
+        &self.frames
+    }
+
+    pub fn paint(&self, ui: &egui::Ui, rect: egui::Rect, colorimetry: Colorimetry) {
+        let renderer = match &self.renderer {
+            Some(r) => r.clone(),
+            None => return,
+        };
+        let frames = self.frames.clone();
+        let callback = egui::PaintCallback {
+            rect,
+            callback: Arc::new(egui_glow::CallbackFn::new(move |_info, painter| {
+                let pending = frames.0.lock().unwrap().pending.take();
+                let mut renderer = renderer.lock().unwrap();
+                unsafe {
+                    if let Some(frame) = pending {
+                        renderer.upload(painter.gl(), &frame);
+                        frames.0.lock().unwrap().spare = frame.data;
+                    }
+                    renderer.paint(painter.gl(), colorimetry);
+                }
+            })),
+        };
+        ui.painter().add(callback);
+    }
+
+    pub fn destroy(&self, gl: &glow::Context) {
+        if let Some(r) = &self.renderer {
+            unsafe { r.lock().unwrap().destroy(gl) }
+        }
+    }
+}
+
+const VERTEX_SHADER: &str = r#"
+out vec2 v_uv;
+void main() {
+    vec2 pos = vec2(float(gl_VertexID & 1), float((gl_VertexID >> 1) & 1));
+    v_uv = vec2(pos.x, 1.0 - pos.y);
+    gl_Position = vec4(pos * 2.0 - 1.0, 0.0, 1.0);
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+uniform int u_format;
+uniform ivec2 u_size;
+uniform sampler2D u_tex0;
+uniform sampler2D u_tex1;
+uniform mat3 u_matrix;
+uniform vec3 u_offset;
+in vec2 v_uv;
+out vec4 f_color;
+void main() {
+    vec3 yuv;
+    // the same texels the cpu path would use, so the two match
+    ivec2 px = min(ivec2(v_uv * vec2(u_size)), u_size - 1);
+    if (u_format == 0) {
+        // yuyv: each rgba texel is y0 u y1 v for two neighboring pixels
+        vec4 t = texelFetch(u_tex0, ivec2(px.x / 2, px.y), 0);
+        yuv = vec3((px.x & 1) == 0 ? t.r : t.b, t.g, t.a);
+    } else {
+        // nv12: full size y plane, half size interleaved uv plane
+        yuv = vec3(texelFetch(u_tex0, px, 0).r, texelFetch(u_tex1, px / 2, 0).rg);
+    }
+    f_color = vec4(clamp(u_matrix * (yuv - u_offset), 0.0, 1.0), 1.0);
+}
+"#;
+
+struct YuvRenderer {
+    program: glow::Program,
+    vao: glow::VertexArray,
+    textures: [glow::Texture; 2],
+    current: Option<(PixelFormat, [usize; 2])>,
+}
+
+impl YuvRenderer {
+    unsafe fn new(gl: &glow::Context) -> anyhow::Result<Self> {
+        let program = gl.create_program().map_err(anyhow::Error::msg)?;
+        let mut shaders = vec![];
+        for (ty, src) in [
+            (glow::VERTEX_SHADER, VERTEX_SHADER),
+            (glow::FRAGMENT_SHADER, FRAGMENT_SHADER),
+        ] {
+            let shader = gl.create_shader(ty).map_err(anyhow::Error::msg)?;
+            gl.shader_source(shader, &format!("#version 140\n{src}"));
+            gl.compile_shader(shader);
+            anyhow::ensure!(
+                gl.get_shader_compile_status(shader),
+                "shader didn't compile: {}",
+                gl.get_shader_info_log(shader)
+            );
+            gl.attach_shader(program, shader);
+            shaders.push(shader);
+        }
+        gl.link_program(program);
+        for shader in shaders {
+            gl.detach_shader(program, shader);
+            gl.delete_shader(shader);
+        }
+        anyhow::ensure!(
+            gl.get_program_link_status(program),
+            "shader didn't link: {}",
+            gl.get_program_info_log(program)
+        );
+
+        let vao = gl.create_vertex_array().map_err(anyhow::Error::msg)?;
+        let mut textures = [None; 2];
+        for tex in &mut textures {
+            let t = gl.create_texture().map_err(anyhow::Error::msg)?;
+            gl.bind_texture(glow::TEXTURE_2D, Some(t));
+            for (param, val) in [
+                (glow::TEXTURE_MIN_FILTER, glow::LINEAR),
+                (glow::TEXTURE_MAG_FILTER, glow::LINEAR),
+                (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+                (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            ] {
+                gl.tex_parameter_i32(glow::TEXTURE_2D, param, val as i32);
+            }
+            *tex = Some(t);
+        }
+        gl.bind_texture(glow::TEXTURE_2D, None);
+
+        Ok(Self {
+            program,
+            vao,
+            textures: textures.map(Option::unwrap),
+            current: None,
+        })
+    }
+
+    unsafe fn upload(&mut self, gl: &glow::Context, frame: &YuvFrame) {
+        let (w, h) = (frame.width, frame.height);
+        // chroma is shared between pairs of pixels, so odd sizes round it up
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+        let tex = |i: usize, internal: u32, w: usize, h: usize, format: u32, data: &[u8]| {
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.textures[i]));
+            gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                internal as i32,
+                w as i32,
+                h as i32,
+                0,
+                format,
+                glow::UNSIGNED_BYTE,
+                Some(data),
+            );
+        };
+        gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+        match frame.format {
+            PixelFormat::Yuyv => tex(0, glow::RGBA8, cw, h, glow::RGBA, &frame.data),
+            PixelFormat::Nv12 => {
+                let (y, uv) = frame.data.split_at(w * h);
+                tex(0, glow::R8, w, h, glow::RED, y);
+                tex(1, glow::RG8, cw, ch, glow::RG, uv);
+            }
+            _ => unreachable!("GpuFrames only takes yuv formats"),
+        }
+        gl.bind_texture(glow::TEXTURE_2D, None);
+        self.current = Some((frame.format, [w, h]));
+    }
+
+    unsafe fn paint(&self, gl: &glow::Context, colorimetry: Colorimetry) {
+        let (format, size) = match self.current {
+            Some(x) => x,
+            None => return,
+        };
+        let (matrix, offset) = colorimetry.transform();
+        let p = self.program;
+        gl.use_program(Some(p));
+        let format = match format {
+            PixelFormat::Nv12 => 1,
+            _ => 0,
+        };
+        gl.uniform_1_i32(gl.get_uniform_location(p, "u_format").as_ref(), format);
+        gl.uniform_2_i32(
+            gl.get_uniform_location(p, "u_size").as_ref(),
+            size[0] as i32,
+            size[1] as i32,
+        );
+        gl.uniform_matrix_3_f32_slice(
+            gl.get_uniform_location(p, "u_matrix").as_ref(),
+            true,
+            &matrix.concat(),
+        );
+        gl.uniform_3_f32(
+            gl.get_uniform_location(p, "u_offset").as_ref(),
+            offset[0],
+            offset[1],
+            offset[2],
+        );
+        for (i, tex) in self.textures.iter().enumerate() {
+            gl.active_texture(glow::TEXTURE0 + i as u32);
+            gl.bind_texture(glow::TEXTURE_2D, Some(*tex));
+            let name = format!("u_tex{i}");
+            gl.uniform_1_i32(gl.get_uniform_location(p, &name).as_ref(), i as i32);
+        }
+        gl.bind_vertex_array(Some(self.vao));
+        gl.draw_arrays(glow::TRIANGLE_STRIP, 0, 4);
+        gl.bind_vertex_array(None);
+        gl.active_texture(glow::TEXTURE0);
+    }
+
+    unsafe fn destroy(&self, gl: &glow::Context) {
+        gl.delete_program(self.program);
+        gl.delete_vertex_array(self.vao);
+        for tex in self.textures {
+            gl.delete_texture(tex);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{c_void, CString};
+    use std::ptr;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::convert::{self, YuvMatrix, YuvRange};
+
+    type Ptr = *mut c_void;
+
+    /// a surfaceless egl context, current on this thread. needs mesa (llvmpipe
+    /// is fine) and EGL_MESA_platform_surfaceless, and there's no cleaning up
+    #[allow(non_snake_case)]
+    unsafe fn headless_gl() -> Option<glow::Context> {
+        let lib = libloading::Library::new("libEGL.so.1").ok()?;
+        let lib = Box::leak(Box::new(lib));
+        macro_rules! egl {
+            ($name:ident: fn($($arg:ty),*) -> $ret:ty) => {
+                let $name = *lib
+                    .get::<unsafe extern "C" fn($($arg),*) -> $ret>(
+                        concat!(stringify!($name), "\0").as_bytes(),
+                    )
+                    .ok()?;
+            };
+        }
+        egl!(eglGetPlatformDisplay: fn(u32, Ptr, *const isize) -> Ptr);
+        egl!(eglInitialize: fn(Ptr, *mut i32, *mut i32) -> u32);
+        egl!(eglBindAPI: fn(u32) -> u32);
+        egl!(eglChooseConfig: fn(Ptr, *const i32, *mut Ptr, i32, *mut i32) -> u32);
+        egl!(eglCreateContext: fn(Ptr, Ptr, Ptr, *const i32) -> Ptr);
+        egl!(eglMakeCurrent: fn(Ptr, Ptr, Ptr, Ptr) -> u32);
+        egl!(eglGetProcAddress: fn(*const i8) -> *const c_void);
+
+        const PLATFORM_SURFACELESS_MESA: u32 = 0x31dd;
+        const OPENGL_API: u32 = 0x30a2;
+        const NONE: i32 = 0x3038;
+        let display =
+            eglGetPlatformDisplay(PLATFORM_SURFACELESS_MESA, ptr::null_mut(), ptr::null());
+        if display.is_null() || eglInitialize(display, ptr::null_mut(), ptr::null_mut()) == 0 {
+            return None;
+        }
+        if eglBindAPI(OPENGL_API) == 0 {
+            return None;
+        }
+        // EGL_SURFACE_TYPE: EGL_PBUFFER_BIT, EGL_RENDERABLE_TYPE: EGL_OPENGL_BIT
+        let attribs = [0x3033, 0x1, 0x3040, 0x8, NONE];
+        let (mut config, mut n) = (ptr::null_mut(), 0);
+        if eglChooseConfig(display, attribs.as_ptr(), &mut config, 1, &mut n) == 0 || n == 0 {
+            return None;
+        }
+        // 3.2 core, so #version 140 is there
+        let attribs = [0x3098, 3, 0x30fb, 2, 0x30fd, 0x1, NONE];
+        let context = eglCreateContext(display, config, ptr::null_mut(), attribs.as_ptr());
+        if context.is_null()
+            || eglMakeCurrent(display, ptr::null_mut(), ptr::null_mut(), context) == 0
+        {
+            return None;
+        }
+        Some(glow::Context::from_loader_function(|name| {
+            let name = CString::new(name).unwrap();
+            eglGetProcAddress(name.as_ptr())
+        }))
+    }
+
+    /// run the shader over `frame` into an offscreen texture and read it back,
+    /// top row first like a `ColorImage`
+    unsafe fn render(gl: &glow::Context, frame: &Frame<'_>, colorimetry: Colorimetry) -> Vec<u8> {
+        let (w, h) = (frame.width as i32, frame.height as i32);
+        let target = gl.create_texture().unwrap();
+        gl.bind_texture(glow::TEXTURE_2D, Some(target));
+        gl.tex_storage_2d(glow::TEXTURE_2D, 1, glow::RGBA8, w, h);
+        let fbo = gl.create_framebuffer().unwrap();
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(fbo));
+        gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(target),
+            0,
+        );
+        gl.viewport(0, 0, w, h);
+
+        let mut renderer = YuvRenderer::new(gl).unwrap();
+        let len = frame.format.frame_len(frame.width, frame.height).unwrap();
+        renderer.upload(
+            gl,
+            &YuvFrame {
+                width: frame.width,
+                height: frame.height,
+                format: frame.format,
+                data: frame.data[..len].to_vec(),
+            },
+        );
+        renderer.paint(gl, colorimetry);
+
+        let mut out = vec![0; (w * h * 4) as usize];
+        gl.pixel_store_i32(glow::PACK_ALIGNMENT, 1);
+        gl.read_pixels(
+            0,
+            0,
+            w,
+            h,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            glow::PixelPackData::Slice(&mut out),
+        );
+        renderer.destroy(gl);
+        gl.delete_framebuffer(fbo);
+        gl.delete_texture(target);
+        // gl's rows go bottom up
+        out.chunks_exact(w as usize * 4)
+            .rev()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn shader_matches_cpu() {
+        let gl = match unsafe { headless_gl() } {
+            Some(gl) => gl,
+            None => return eprintln!("no headless gl, skipping"),
+        };
+        // odd sizes, so the rounded up chroma gets checked too
+        let (width, height) = (37, 11);
+        let noise: Vec<u8> = (0..width * height * 3)
+            .map(|i: usize| (i.wrapping_mul(2654435761) >> 7) as u8)
+            .collect();
+        for format in [PixelFormat::Yuyv, PixelFormat::Nv12] {
+            for matrix in [YuvMatrix::Bt601, YuvMatrix::Bt709] {
+                for range in [YuvRange::Limited, YuvRange::Full] {
+                    let colorimetry = Colorimetry { matrix, range };
+                    let frame = Frame {
+                        width,
+                        height,
+                        format,
+                        data: &noise,
+                        captured: Instant::now(),
+                    };
+                    let cpu = convert::to_color_image(&frame, colorimetry).unwrap();
+                    let gpu = unsafe { render(&gl, &frame, colorimetry) };
+                    for (i, (c, g)) in cpu.pixels.iter().zip(gpu.chunks_exact(4)).enumerate() {
+                        let close = c.to_array().iter().zip(g).all(|(c, g)| c.abs_diff(*g) <= 2);
+                        assert!(
+                            close,
+                            "{format:?} {colorimetry:?} at ({}, {}): cpu {:?}, gpu {g:?}",
+                            i % width,
+                            i / width,
+                            c.to_array()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn skipped_frames_give_their_buffer_back() {
+        let frames = GpuFrames::default();
+        let frame = |data| Frame {
+            width: 2,
+            height: 2,
+            format: PixelFormat::Yuyv,
+            data,
+            captured: Instant::now(),
+        };
+        frames.push(&frame(&[1; 8])).unwrap();
+        let buf = frames
+            .0
+            .lock()
+            .unwrap()
+            .pending
+            .as_ref()
+            .unwrap()
+            .data
+            .as_ptr();
+        frames.push(&frame(&[2; 8])).unwrap();
+        let shared = frames.0.lock().unwrap();
+        let pending = shared.pending.as_ref().unwrap();
+        assert_eq!(pending.data.as_ptr(), buf);
+        assert_eq!(pending.data, [2; 8]);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+// how many frames the overlay numbers cover
+const WINDOW: usize = 600;
+
+/// timestamps for frames going through the pipeline: when the source gave it
+/// to us, when it got handed to egui (or the gpu), and when a repaint drew it
+#[derive(Clone, Default)]
+pub(crate) struct Latency(Arc<Mutex<Inner>>);
+
+#[derive(Default)]
+struct Inner {
+    enabled: bool,
+    // the newest frame that's been uploaded but not painted yet. if another
+    // one comes in first this one never made it to the screen, so it's fine
+    // to forget about it
+    pending: Option<(Instant, Instant)>,
+    samples: VecDeque<Sample>,
+    csv: Option<(Instant, BufWriter<File>)>,
+}
+
+#[derive(Copy, Clone)]
+struct Sample {
+    upload: Duration,
+    paint: Duration,
+}
+
+impl Latency {
+    pub fn enabled(&self) -> bool {
+        self.0.lock().unwrap().enabled
+    }
+
+    /// start measuring, logging every frame to a csv file in `csv_dir` if given
+    pub fn start(&self, csv_dir: Option<&Path>) -> anyhow::Result<Option<PathBuf>> {
+        let csv = csv_dir.map(open_csv).transpose()?;
+        let mut inner = self.0.lock().unwrap();
+        *inner = Inner {
+            enabled: true,
+            ..Default::default()
+        };
+        Ok(csv.map(|(path, file)| {
+            inner.csv = Some((Instant::now(), file));
+            path
+        }))
+    }
+
+    pub fn stop(&self) {
+        let mut inner = self.0.lock().unwrap();
+        if let Some((_, mut csv)) = inner.csv.take() {
+            let _ = csv.flush();
+        }
+        *inner = Inner::default();
+    }
+
+    /// called from the video thread once a frame has been handed off for display
+    pub fn uploaded(&self, captured: Instant) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.enabled {
+            inner.pending = Some((captured, Instant::now()));
+        }
+    }
+
+    /// called from the ui thread after drawing whatever frame is current
+    pub fn painted(&self) {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        let (captured, uploaded) = match inner.pending.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let sample = Sample {
+            upload: uploaded - captured,
+            paint: now - uploaded,
+        };
+        if inner.samples.len() == WINDOW {
+            inner.samples.pop_front();
+        }
+        inner.samples.push_back(sample);
+        if let Some((start, csv)) = &mut inner.csv {
+            let res = writeln!(
+                csv,
+                "{},{},{}",
+                captured.saturating_duration_since(*start).as_micros(),
+                sample.upload.as_micros(),
+                sample.paint.as_micros()
+            );
+            if let Err(e) = res {
+                eprintln!("couldn't write latency log, giving up on it: {e}");
+                inner.csv = None;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Option<Stats> {
+        let inner = self.0.lock().unwrap();
+        if inner.samples.is_empty() {
+            return None;
+        }
+        let summarize = |f: fn(&Sample) -> Duration| {
+            let mut xs = inner.samples.iter().map(f).collect::<Vec<_>>();
+            xs.sort_unstable();
+            Summary {
+                min: xs[0],
+                avg: xs.iter().sum::<Duration>() / xs.len() as u32,
+                p99: xs[(xs.len() * 99 / 100).min(xs.len() - 1)],
+            }
+        };
+        Some(Stats {
+            frames: inner.samples.len(),
+            upload: summarize(|s| s.upload),
+            paint: summarize(|s| s.paint),
+            total: summarize(|s| s.upload + s.paint),
+        })
+    }
+}
+
+fn open_csv(dir: &Path) -> anyhow::Result<(PathBuf, BufWriter<File>)> {
+    std::fs::create_dir_all(dir).with_context(|| format!("couldn't create {}", dir.display()))?;
+    let name = format!(
+        "ccdisplay-latency-{}.csv",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+    );
+    let path = dir.join(name);
+    let mut file = BufWriter::new(
+        File::create(&path).with_context(|| format!("couldn't create {}", path.display()))?,
+    );
+    writeln!(file, "captured_us,capture_to_upload_us,upload_to_paint_us")?;
+    Ok((path, file))
+}
+
+pub(crate) struct Summary {
+    pub min: Duration,
+    pub avg: Duration,
+    pub p99: Duration,
+}
+
+pub(crate) struct Stats {
+    pub frames: usize,
+    /// source callback to texture upload
+    pub upload: Summary,
+    /// texture upload to repaint
+    pub paint: Summary,
+    pub total: Summary,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testpattern::TestPattern;
+    use crate::video::{EguiTexture, RenderOptions, StreamEnd, VideoSource};
+
+    // these time real threads against the clock, so they're only worth
+    // anything on an idle machine. run them after touching the frame path with
+    // `cargo test latency -- --ignored --test-threads=1`
+
+    /// stream a small test pattern for a second, "painting" every couple of ms
+    fn measure(opts: RenderOptions) -> Stats {
+        let latency = Latency::default();
+        latency.start(None).unwrap();
+        let texture = EguiTexture::headless(opts, latency.clone());
+        let mut source = TestPattern::new("320x240@60".parse().unwrap(), Default::default());
+        let started = Instant::now();
+        let mut paint = || {
+            while started.elapsed() < Duration::from_secs(1) {
+                std::thread::sleep(Duration::from_millis(2));
+                latency.painted();
+            }
+            StreamEnd::Quit
+        };
+        source.stream(texture, &mut paint).unwrap();
+        latency.stats().expect("no frames made it through")
+    }
+
+    #[test]
+    #[ignore = "timing, run it by hand"]
+    fn test_pattern_gets_straight_through() {
+        let stats = measure(Default::default());
+        assert!(stats.frames >= 40, "only {} frames painted", stats.frames);
+        // converting a frame this small takes well under a millisecond, so
+        // anything close to a frame interval means something's queueing them up
+        let frame = Duration::from_secs(1) / 60;
+        assert!(
+            stats.upload.avg < frame / 2,
+            "upload avg {:?}",
+            stats.upload.avg
+        );
+        assert!(
+            stats.upload.p99 < frame * 2,
+            "upload p99 {:?}",
+            stats.upload.p99
+        );
+    }
+
+    #[test]
+    #[ignore = "timing, run it by hand"]
+    fn video_delay_shows_up_in_the_numbers() {
+        let delay = Duration::from_millis(100);
+        let stats = measure(RenderOptions {
+            video_delay: delay,
+            ..Default::default()
+        });
+        assert!(
+            stats.upload.min >= delay,
+            "upload min {:?}",
+            stats.upload.min
+        );
+        // the delay shouldn't hold frames back any longer than that
+        let frame = Duration::from_secs(1) / 60;
+        assert!(
+            stats.upload.avg < delay + frame * 2,
+            "upload avg {:?}",
+            stats.upload.avg
+        );
+    }
+}
@@ -1,40 +1,235 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use clap::Parser;
+use eframe::glow;
 use egui::{util::cache, Vec2};
 use ordered_float::OrderedFloat;
 
 mod audio;
+mod cli;
+mod config;
+mod convert;
+mod extra;
+mod format;
+mod gpu;
+mod latency;
+mod meter;
+mod pa;
+mod profile;
+#[cfg(feature = "pipewire")]
+mod pwaudio;
+mod record;
+mod screenshot;
 mod settings;
+mod stats;
+mod testpattern;
+mod v4l2;
 mod video;
 
 fn main() {
+    let args = cli::Args::parse();
+    match &args.command {
+        Some(cli::Command::ListDevices) => return cli::list_devices(),
+        Some(cli::Command::ListFormats { device }) => {
+            return cli::list_formats(device.as_ref().or(args.video.as_ref()))
+        }
+        None => {}
+    }
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    audio::install_panic_hook();
     eframe::run_native(
         "CCDisplay",
         Default::default(),
-        Box::new(|cc| Box::new(CCDisplay::new(cc))),
+        Box::new(|cc| Box::new(CCDisplay::new(cc, args, config))),
     );
 }
 
 struct CCDisplay {
     texture: egui::TextureHandle,
+    gpu: gpu::GpuDisplay,
+    render_opts: Arc<Mutex<video::RenderOptions>>,
+    /// for starting and stopping the extra sources' gpu bits
+    gl: Option<Arc<glow::Context>>,
+    /// the sources shown alongside the main one, from the profile
+    extras: Vec<extra::Extra>,
+    layout: extra::Layout,
+    /// what the extras were last told the volume was
+    extras_volume: audio::Volume,
     ctrl_c: Arc<AtomicBool>,
     display_size_cache: cache::FrameCache<Vec2, DisplaySizeComputer>,
     settings: settings::SettingsWindow,
+    recorder: record::Recorder,
+    modes: format::ModeList,
+    grab: screenshot::FrameGrab,
+    latency: latency::Latency,
+    stats: stats::Stats,
+    show_stats: bool,
+    meters: meter::Meters,
+    show_meters: bool,
+    toast_tx: flume::Sender<String>,
+    toast_rx: flume::Receiver<String>,
+    toast: Option<(String, std::time::Instant)>,
     done_tx: flume::Sender<()>,
     finished_rx: flume::Receiver<()>,
+    /// go fullscreen on the first frame, from --fullscreen
+    start_fullscreen: bool,
+    hotplug: Option<profile::Hotplug>,
 }
 
 const TEXTURE_FILTER: egui::TextureFilter = egui::TextureFilter::Linear;
+const TOAST_TIME: std::time::Duration = std::time::Duration::from_secs(3);
 
+/// which uvc device to use. whatever's None matches anything
 #[derive(Clone, Default, PartialEq, Eq)]
 struct DeviceId {
     vendor_id: Option<u16>,
     product_id: Option<u16>,
+    /// for telling identical cards apart, if they have one
+    serial: Option<String>,
+    /// where it's plugged in, as bus-ports like /sys/bus/usb/devices names
+    /// them, e.g. 3-1.4. for identical cards without serials
+    port_path: Option<String>,
+}
+
+impl DeviceId {
+    /// whether `dev`, the full id of something plugged in, is one we'd take
+    fn matches(&self, dev: &DeviceId) -> bool {
+        fn same<T: PartialEq>(ours: &Option<T>, theirs: &Option<T>) -> bool {
+            ours.is_none() || ours == theirs
+        }
+        same(&self.vendor_id, &dev.vendor_id)
+            && same(&self.product_id, &dev.product_id)
+            && same(&self.serial, &dev.serial)
+            && same(&self.port_path, &dev.port_path)
+    }
+}
+
+/// what the video thread should be showing
+#[derive(Clone, PartialEq, Eq)]
+enum VideoDevice {
+    Uvc(DeviceId),
+    V4l2(PathBuf),
+    TestPattern(testpattern::Params),
+}
+
+// --video and the config file both spell devices like this
+impl FromStr for VideoDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(params) = s.strip_prefix("testpattern") {
+            let params = match params.strip_prefix(':') {
+                Some(params) => params.parse()?,
+                None if params.is_empty() => Default::default(),
+                None => anyhow::bail!("expected testpattern or testpattern:WxH@FPS, got {s:?}"),
+            };
+            return Ok(VideoDevice::TestPattern(params));
+        }
+        let mut parts = s.split(',');
+        let ids = parts.next().unwrap_or_default();
+        if ids.contains('/') {
+            return Ok(VideoDevice::V4l2(PathBuf::from(s)));
+        }
+        let (vid, pid) = ids.split_once(':').ok_or_else(|| {
+            anyhow::anyhow!("expected VID:PID, a /dev/video path or testpattern, got {s:?}")
+        })?;
+        let hex = |id: &str| {
+            let id = id.trim_start_matches("0x");
+            (!id.is_empty())
+                .then(|| u16::from_str_radix(id, 16))
+                .transpose()
+        };
+        let mut id = DeviceId {
+            vendor_id: hex(vid)?,
+            product_id: hex(pid)?,
+            ..Default::default()
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("serial", serial)) => id.serial = Some(unescape(serial)?),
+                Some(("port", port)) => id.port_path = Some(unescape(port)?),
+                _ => anyhow::bail!("expected serial=SN or port=BUS-PORTS, got {part:?}"),
+            }
+        }
+        Ok(VideoDevice::Uvc(id))
+    }
+}
+
+impl fmt::Display for VideoDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |id: Option<u16>| id.map_or(String::new(), |id| format!("{id:04x}"));
+        match self {
+            VideoDevice::Uvc(id) => {
+                write!(f, "{}:{}", hex(id.vendor_id), hex(id.product_id))?;
+                if let Some(serial) = &id.serial {
+                    write!(f, ",serial={}", escape(serial))?;
+                }
+                if let Some(port) = &id.port_path {
+                    write!(f, ",port={}", escape(port))?;
+                }
+                Ok(())
+            }
+            VideoDevice::V4l2(path) => write!(f, "{}", path.display()),
+            VideoDevice::TestPattern(params) => write!(f, "testpattern:{params}"),
+        }
+    }
+}
+
+// serials are whatever the manufacturer felt like, so the characters that
+// separate the parts get %-escaped, like in a url
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | ',' | '=' => out += &format!("%{:02X}", c as u8),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> anyhow::Result<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let byte = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        out.push(byte.ok_or_else(|| anyhow::anyhow!("bad %-escape in {s:?}"))?);
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct VideoConfig {
+    dev: VideoDevice,
+    format: format::FormatChoice,
 }
 
 impl CCDisplay {
-    fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    fn new(
+        cc: &eframe::CreationContext<'_>,
+        args: cli::Args,
+        config: Option<config::Config>,
+    ) -> Self {
         let mut style = cc.egui_ctx.style();
         std::sync::Arc::make_mut(&mut style)
             .text_styles
@@ -45,21 +240,76 @@ impl CCDisplay {
         let texture =
             cc.egui_ctx
                 .load_texture("display", egui::ColorImage::example(), TEXTURE_FILTER);
-        let settings = settings::Settings::from_storage(cc.storage.unwrap());
-        let (devid_tx, devid_rx) = flume::bounded(4);
+        let mut migrate_error = None;
+        let config = config.unwrap_or_else(|| {
+            // first run since there's been a config file
+            match cc.storage.map(config::Config::from_storage).transpose() {
+                Ok(config) => {
+                    let config = config.unwrap_or_default();
+                    config.save();
+                    config
+                }
+                Err(e) => {
+                    // no file yet, so the next start gets another go at it
+                    eprintln!("couldn't carry the old settings over: {e:#}");
+                    migrate_error = Some(format!("Couldn't bring the old settings over: {e}"));
+                    Default::default()
+                }
+            }
+        });
+        let mut settings = settings::Settings::from_config(&config);
+        settings.apply_args(&args, &config);
+        let (config_tx, config_rx) = flume::bounded(4);
+        let modes = format::ModeList::default();
+        let gpu = gpu::GpuDisplay::new(cc.gl.as_ref(), Default::default());
+        let render_opts = Arc::new(Mutex::new(settings.render_options()));
+        let (recorder, rec_rx) = record::Recorder::new();
+        recorder.set_replay(settings.replay_limits());
+        let grab = screenshot::FrameGrab::default();
+        let latency = latency::Latency::default();
+        let stats = stats::Stats::default();
+        let meters = meter::Meters::default();
 
         video::run(video::CameraParams {
             texture: texture.clone(),
             ctx: cc.egui_ctx.clone(),
-            devid_rx,
-            devid: settings.devid.clone(),
+            config_rx,
+            config: settings.video_config(),
+            modes: modes.clone(),
+            gpu: gpu.frames().clone(),
+            render_opts: render_opts.clone(),
+            recorder: recorder.clone(),
+            grab: grab.clone(),
+            latency: latency.clone(),
+            stats: stats.clone(),
         });
 
         let (done_tx, done_rx) = flume::bounded(0);
         let (finished_tx, finished_rx) = flume::bounded(0);
-        let (audname_tx, audname_rx) = flume::bounded(4);
-        let audname = settings.audname.clone();
-        std::thread::spawn(|| audio::audio_loop((finished_tx, done_rx), audname, audname_rx));
+        let (audio_tx, audio_rx) = flume::bounded(4);
+        // unbounded so dragging the slider around can't lose the last value
+        let (volume_tx, volume_rx) = flume::unbounded();
+        let audio_params = audio::AudioParams {
+            done_ch: (finished_tx, done_rx),
+            config: settings.audio_config(),
+            config_rx: audio_rx,
+            recorder: recorder.clone(),
+            rec_rx,
+            volume: settings.volume,
+            volume_rx,
+            meters: meters.clone(),
+            stats: stats.clone(),
+        };
+        let backend = settings.audio_backend;
+        std::thread::spawn(move || backend.run(audio_params));
+
+        let (toast_tx, toast_rx) = flume::unbounded();
+        if let Some(msg) = migrate_error {
+            let _ = toast_tx.send(msg);
+        }
+        let hotplug = profile::Hotplug::new(cc.egui_ctx.clone())
+            .map_err(|e| eprintln!("no usb hotplug, profiles won't switch on their own: {e}"))
+            .ok();
 
         let ctrl_c = Arc::new(AtomicBool::new(false));
         let flag = ctrl_c.clone();
@@ -71,12 +321,286 @@ impl CCDisplay {
 
         Self {
             texture,
+            gpu,
+            render_opts: render_opts.clone(),
+            gl: cc.gl.clone(),
+            extras: Vec::new(),
+            layout: extra::Layout::default(),
+            extras_volume: settings.volume,
             ctrl_c,
             display_size_cache: Default::default(),
-            settings: settings::SettingsWindow::new(settings, devid_tx, audname_tx),
+            settings: settings::SettingsWindow::new(
+                config,
+                settings,
+                settings::Senders {
+                    video: config_tx,
+                    audio: audio_tx,
+                    volume: volume_tx,
+                },
+                render_opts,
+                modes.clone(),
+                recorder.clone(),
+            ),
+            recorder,
+            modes,
+            grab,
+            latency,
+            stats,
+            show_stats: false,
+            meters,
+            show_meters: false,
+            toast_tx,
+            toast_rx,
+            toast: None,
             done_tx,
             finished_rx,
+            start_fullscreen: args.fullscreen,
+            hotplug,
+        }
+    }
+}
+
+impl CCDisplay {
+    fn toggle_recording(&self) {
+        if self.recorder.elapsed().is_some() {
+            self.recorder.stop();
+            return;
+        }
+        let fps = self.modes.lock().unwrap().current.map_or(60, |m| m.fps);
+        let dir = &self.settings.settings().record_dir;
+        if let Err(e) = self.recorder.start(dir, fps) {
+            eprintln!("couldn't start recording: {e:#}");
+        }
+    }
+
+    fn toggle_latency(&self) {
+        if self.latency.enabled() {
+            self.latency.stop();
+            return;
+        }
+        let settings = self.settings.settings();
+        let csv_dir = settings.latency_csv.then(|| &*settings.record_dir);
+        match self.latency.start(csv_dir) {
+            Ok(Some(path)) => eprintln!("logging latency to {}", path.display()),
+            Ok(None) => {}
+            Err(e) => eprintln!("couldn't start latency log: {e:#}"),
+        }
+    }
+
+    fn show_latency(&self, ctx: &egui::Context) {
+        if !self.latency.enabled() {
+            return;
+        }
+        let text = match self.latency.stats() {
+            Some(stats) => {
+                let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+                let mut text = format!("latency over {} frames (ms)\n", stats.frames);
+                text += "                min    avg    p99\n";
+                for (name, s) in [
+                    ("capture→upload", &stats.upload),
+                    ("upload→paint  ", &stats.paint),
+                    ("total         ", &stats.total),
+                ] {
+                    text += &format!(
+                        "{name} {:6.1} {:6.1} {:6.1}\n",
+                        ms(s.min),
+                        ms(s.avg),
+                        ms(s.p99)
+                    );
+                }
+                text
+            }
+            None => "latency: waiting for frames".to_owned(),
+        };
+        egui::Area::new("latency")
+            .anchor(egui::Align2::LEFT_TOP, [16.0, 16.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style())
+                    .show(ui, |ui| ui.label(egui::RichText::new(text).monospace()));
+            });
+    }
+
+    fn show_stats(&self, ctx: &egui::Context) {
+        if !self.show_stats {
+            return;
+        }
+        let stats = self.stats.snapshot();
+        let mut text = match self.modes.lock().unwrap().current {
+            Some(mode) => format!("mode: {mode}\n"),
+            None => "mode: none\n".to_owned(),
+        };
+        if let Some((format, w, h)) = stats.frame_format {
+            text += &format!("frames: {format:?} {w}x{h}\n");
+        }
+        text += &format!(
+            "fps: {} in, {} shown\n",
+            stats.frames_in.per_sec(),
+            stats.frames_shown.per_sec()
+        );
+        text += &format!("dropped: {}  bad: {}\n", stats.dropped, stats.bad);
+        text += &format!("reconnects: {}\n", stats.reconnects);
+        text += &format!("audio: {}", stats.audio);
+        egui::Area::new("stats")
+            .anchor(egui::Align2::LEFT_BOTTOM, [16.0, -16.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style())
+                    .show(ui, |ui| ui.label(egui::RichText::new(text).monospace()));
+            });
+        // keep the fps numbers moving even if frames stop
+        ctx.request_repaint_after(std::time::Duration::from_millis(500));
+    }
+
+    fn show_meters(&self, ctx: &egui::Context) {
+        if !self.show_meters {
+            return;
         }
+        let levels = self.meters.levels();
+        let pipewire = self.stats.pipewire();
+        egui::Area::new("meters")
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-16.0, -16.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    for (peak, rms) in levels.peak.into_iter().zip(levels.rms) {
+                        let (rect, _) =
+                            ui.allocate_exact_size(egui::vec2(240.0, 10.0), egui::Sense::hover());
+                        let painter = ui.painter();
+                        painter.rect_filled(rect, 0.0, egui::Color32::from_gray(40));
+                        let x = |level| rect.left() + rect.width() * meter::meter_pos(level);
+                        let mut bar = rect;
+                        bar.set_right(x(rms));
+                        painter.rect_filled(bar, 0.0, egui::Color32::from_rgb(60, 180, 60));
+                        let color = if peak >= 0.99 {
+                            egui::Color32::RED
+                        } else {
+                            egui::Color32::YELLOW
+                        };
+                        painter.vline(x(peak), rect.y_range(), egui::Stroke::new(2.0, color));
+                    }
+                    if pipewire {
+                        ui.label("no meters with the PipeWire backend");
+                    } else if !levels.signal {
+                        ui.label("no signal");
+                    }
+                });
+            });
+        // meters are no good if they only move when a frame comes in
+        ctx.request_repaint_after(std::time::Duration::from_millis(33));
+    }
+
+    fn save_replay(&self, ctx: &egui::Context) {
+        let fps = self.modes.lock().unwrap().current.map_or(60, |m| m.fps);
+        let dir = &self.settings.settings().record_dir;
+        let toast_tx = self.toast_tx.clone();
+        let ctx = ctx.clone();
+        self.recorder.save_replay(dir, fps, move |res| {
+            let msg = match res {
+                Ok(path) => format!("Saved {}", path.display()),
+                Err(e) => {
+                    eprintln!("couldn't save replay: {e:#}");
+                    format!("Couldn't save replay: {e}")
+                }
+            };
+            let _ = toast_tx.send(msg);
+            ctx.request_repaint();
+        });
+    }
+
+    fn nudge_av_offset(&mut self, ms: i32) {
+        let offset = self.settings.nudge_av_offset(ms);
+        let msg = match offset {
+            0 => "A/V offset: 0 ms".to_owned(),
+            ms if ms > 0 => format!("A/V offset: +{ms} ms (audio held back)"),
+            ms => format!("A/V offset: {ms} ms (video held back)"),
+        };
+        let _ = self.toast_tx.send(msg);
+    }
+
+    fn change_volume(&mut self, f: impl FnOnce(&mut audio::Volume)) {
+        let volume = self.settings.set_volume(f);
+        let _ = self.toast_tx.send(format!("Volume: {volume}"));
+    }
+
+    fn cycle_profile(&mut self, frame: &mut eframe::Frame) {
+        let msg = match self.settings.cycle_profile(frame) {
+            Some(name) if name.is_empty() => "Profile: Default".to_owned(),
+            Some(name) => format!("Profile: {name}"),
+            None => "No profiles saved".to_owned(),
+        };
+        let _ = self.toast_tx.send(msg);
+    }
+
+    /// switch to the profile for whatever just got plugged in, if there is one
+    fn check_hotplug(&mut self, frame: &mut eframe::Frame) {
+        let plugged = match &self.hotplug {
+            Some(hotplug) => hotplug.rx.try_iter().collect::<Vec<_>>(),
+            None => return,
+        };
+        for dev in plugged {
+            if let Some(name) = self.settings.profile_for_device(&dev) {
+                self.settings.switch_profile(frame, &name);
+                let name = if name.is_empty() { "Default" } else { &name };
+                let _ = self.toast_tx.send(format!("Profile: {name}"));
+            }
+        }
+    }
+
+    /// start, stop or update the extra sources to match the settings
+    fn sync_extras(&mut self, ctx: &egui::Context) {
+        let settings = self.settings.settings();
+        let (backend, volume) = (settings.audio_backend, settings.volume);
+        if volume != self.extras_volume {
+            self.extras_volume = volume;
+            for extra in &self.extras {
+                extra.set_volume(volume);
+            }
+        }
+        let extras = match self.settings.extras_changed() {
+            Some(extras) => extras,
+            None => return,
+        };
+        self.layout = extras.layout;
+        let keep = extras.sources.len().min(self.extras.len());
+        for extra in self.extras.drain(keep..) {
+            extra.stop(self.gl.as_deref(), false);
+        }
+        for (i, spec) in extras.sources.into_iter().enumerate() {
+            match self.extras.get_mut(i) {
+                Some(extra) => extra.update(spec, extras.render),
+                None => self.extras.push(extra::Extra::new(
+                    ctx,
+                    self.gl.as_ref(),
+                    spec,
+                    extras.render,
+                    backend,
+                    volume,
+                )),
+            }
+        }
+    }
+
+    /// a little message at the bottom of the screen for a few seconds
+    fn show_toast(&mut self, ctx: &egui::Context) {
+        if let Some(msg) = self.toast_rx.try_iter().last() {
+            self.toast = Some((msg, std::time::Instant::now()));
+        }
+        let (msg, shown) = match &self.toast {
+            Some(x) => x,
+            None => return,
+        };
+        if shown.elapsed() > TOAST_TIME {
+            self.toast = None;
+            return;
+        }
+        egui::Area::new("toast")
+            .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -32.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| ui.label(msg));
+            });
+        ctx.request_repaint_after(TOAST_TIME);
     }
 }
 
@@ -85,27 +609,125 @@ impl eframe::App for CCDisplay {
         // do the texture rendering right away and everything else after. idk how
         // egui works but maybe this reduces latency?
         let window_info = frame.info().window_info;
+        if std::mem::take(&mut self.start_fullscreen) {
+            frame.set_fullscreen(true);
+        }
+        self.sync_extras(ctx);
         let response = egui::CentralPanel::default()
             .frame(egui::Frame::none())
             .show(ctx, |ui| {
-                let window_size = window_info.size;
-                let texture_size = self.texture.size_vec2();
-                let display_size = self
-                    .display_size_cache
-                    .get((window_size.into(), texture_size.into()));
-                ui.add(egui::Image::new(self.texture.id(), display_size));
+                let full = egui::Rect::from_min_size(egui::Pos2::ZERO, window_info.size);
+                let areas = self.layout.areas(full, 1 + self.extras.len());
+                let cache = &mut self.display_size_cache;
+                let mut place = |area: egui::Rect, size: Vec2| {
+                    let display_size = cache.get((area.size().into(), size.into()));
+                    if area == full {
+                        egui::Rect::from_min_size(area.min, display_size)
+                    } else {
+                        egui::Rect::from_center_size(area.center(), display_size)
+                    }
+                };
+                let texture_size = self
+                    .gpu
+                    .frames()
+                    .size()
+                    .unwrap_or_else(|| self.texture.size_vec2());
+                let colorimetry = self.render_opts.lock().unwrap().colorimetry;
+                let rect = place(areas[0], texture_size);
+                paint_source(ui, rect, &self.gpu, &self.texture, colorimetry);
+                for (extra, &area) in self.extras.iter().zip(&areas[1..]) {
+                    extra.paint(ui, place(area, extra.size()));
+                }
             });
+        if self.layout == extra::Layout::Windows {
+            for (i, extra) in self.extras.iter().enumerate() {
+                egui::Window::new(extra.name.as_str())
+                    .id(egui::Id::new("extrawindow").with(i))
+                    .default_size([480.0, 270.0])
+                    .resizable(true)
+                    .show(ctx, |ui| {
+                        let size = self
+                            .display_size_cache
+                            .get((ui.available_size().into(), extra.size().into()));
+                        let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+                        extra.paint(ui, rect);
+                    });
+            }
+        }
+        self.latency.painted();
+        self.stats.painted();
         if self.ctrl_c.load(Relaxed) {
             frame.close();
         }
+        self.check_hotplug(frame);
         if !self.settings.open {
-            let input = ctx.input();
-            if input.key_pressed(egui::Key::Escape) {
+            // don't hang on to the input lock, some of these end up calling request_repaint
+            let pressed = |key| ctx.input().key_pressed(key);
+            if pressed(egui::Key::Escape) {
                 frame.close();
             }
-            if input.key_pressed(egui::Key::F) {
+            if pressed(egui::Key::F) {
                 frame.set_fullscreen(!window_info.fullscreen);
             }
+            if pressed(egui::Key::R) {
+                self.toggle_recording();
+            }
+            if pressed(egui::Key::I) {
+                self.save_replay(ctx);
+            }
+            if pressed(egui::Key::O) {
+                self.show_stats = !self.show_stats;
+            }
+            if pressed(egui::Key::A) {
+                self.show_meters = !self.show_meters;
+            }
+            if pressed(egui::Key::L) {
+                self.toggle_latency();
+            }
+            if pressed(egui::Key::ArrowUp) {
+                self.change_volume(|v| v.percent += 5);
+            }
+            if pressed(egui::Key::ArrowDown) {
+                self.change_volume(|v| v.percent = v.percent.saturating_sub(5));
+            }
+            if pressed(egui::Key::ArrowLeft) {
+                self.nudge_av_offset(-10);
+            }
+            if pressed(egui::Key::ArrowRight) {
+                self.nudge_av_offset(10);
+            }
+            if pressed(egui::Key::M) {
+                self.change_volume(|v| v.muted = !v.muted);
+            }
+            if pressed(egui::Key::N) {
+                self.cycle_profile(frame);
+            }
+            if pressed(egui::Key::P) {
+                let dir = self.settings.settings().screenshot_dir.clone();
+                screenshot::take(&self.grab, dir, self.toast_tx.clone(), ctx.clone());
+            }
+        }
+        self.show_toast(ctx);
+        self.show_latency(ctx);
+        self.show_stats(ctx);
+        self.show_meters(ctx);
+        if let Some(elapsed) = self.recorder.elapsed() {
+            let secs = elapsed.as_secs();
+            // pipewire only links nodes, the audio never goes past us
+            let no_audio = if self.stats.pipewire() {
+                " (no audio with PipeWire)"
+            } else {
+                ""
+            };
+            egui::Area::new("rec")
+                .anchor(egui::Align2::RIGHT_TOP, [-16.0, 16.0])
+                .interactable(false)
+                .show(ctx, |ui| {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        format!("● REC {:02}:{:02}{no_audio}", secs / 60, secs % 60),
+                    );
+                });
         }
         let hide_cursor = ctx.animate_bool_with_time(
             egui::Id::new("pointerhover"),
@@ -118,9 +740,37 @@ impl eframe::App for CCDisplay {
         self.settings.update(ctx, frame);
     }
 
-    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+    fn on_exit(&mut self, gl: Option<&eframe::glow::Context>) {
+        self.recorder.stop();
+        if let Some(gl) = gl {
+            self.gpu.destroy(gl);
+        }
         let _ = self.done_tx.send(());
         let _ = self.finished_rx.recv();
+        for extra in self.extras.drain(..) {
+            extra.stop(gl, true);
+        }
+    }
+}
+
+/// draw a source into `rect`, off the gpu if that's where its frames are
+fn paint_source(
+    ui: &egui::Ui,
+    rect: egui::Rect,
+    gpu: &gpu::GpuDisplay,
+    texture: &egui::TextureHandle,
+    colorimetry: convert::Colorimetry,
+) {
+    if gpu.frames().size().is_some() {
+        gpu.paint(ui, rect, colorimetry);
+    } else {
+        let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+        ui.painter().add(egui::Shape::image(
+            texture.id(),
+            rect,
+            uv,
+            egui::Color32::WHITE,
+        ));
     }
 }
 
@@ -165,3 +815,69 @@ impl cache::ComputerMut<(OrderedVec2, OrderedVec2), Vec2> for DisplaySizeCompute
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_flags_still_work() {
+        use clap::CommandFactory;
+        let parse = |args: &[&str]| cli::Args::try_parse_from(args).unwrap();
+        let args = parse(&["ccdisplay", "--list-devices"]);
+        assert!(matches!(args.command, Some(cli::Command::ListDevices)));
+        let args = parse(&["ccdisplay", "--video", "testpattern", "--list-formats"]);
+        assert!(matches!(
+            args.command,
+            Some(cli::Command::ListFormats { device: None })
+        ));
+        assert!(args.video.is_some());
+        let args = parse(&["ccdisplay", "list-formats", "/dev/video0"]);
+        assert!(matches!(
+            args.command,
+            Some(cli::Command::ListFormats { device: Some(_) })
+        ));
+        // the subcommands are what gets shown
+        let help = cli::Args::command().render_help().to_string();
+        assert!(!help.contains("--list"), "{help}");
+    }
+
+    #[test]
+    fn video_devices_round_trip() {
+        let serial = DeviceId {
+            vendor_id: Some(0x534d),
+            product_id: Some(0x2109),
+            serial: Some("a,b=c%d".to_owned()),
+            port_path: Some("3-1.4".to_owned()),
+        };
+        let devices = [
+            VideoDevice::Uvc(serial),
+            VideoDevice::Uvc(DeviceId::default()),
+            VideoDevice::V4l2("/dev/video0".into()),
+            VideoDevice::TestPattern("320x240@30".parse().unwrap()),
+        ];
+        for dev in devices {
+            let s = dev.to_string();
+            assert!(s.parse::<VideoDevice>().unwrap() == dev, "{s}");
+        }
+        let s = VideoDevice::Uvc(DeviceId {
+            serial: Some("a,b=c%d".to_owned()),
+            ..Default::default()
+        })
+        .to_string();
+        assert_eq!(s, ":,serial=a%2Cb%3Dc%25d");
+    }
+
+    #[test]
+    fn bad_video_devices() {
+        for bad in [
+            "534d",
+            "zz:2109",
+            "534d:2109,serial",
+            "534d:2109,serial=%2",
+            "testpatternx",
+        ] {
+            assert!(bad.parse::<VideoDevice>().is_err(), "{bad}");
+        }
+    }
+}
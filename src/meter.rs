@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how much history the rms covers
+const WINDOW: Duration = Duration::from_millis(300);
+// -60 dBFS, anything quieter than this counts as nothing
+const SILENCE: f32 = 0.001;
+/// how long it has to be quiet before we call it "no signal"
+pub(crate) const NO_SIGNAL_AFTER: Duration = Duration::from_secs(3);
+
+/// levels from the audio thread's peak-detect stream, for the meter overlay
+#[derive(Clone, Default)]
+pub(crate) struct Meters(Arc<Mutex<Inner>>);
+
+#[derive(Default)]
+struct Inner {
+    peaks: VecDeque<(Instant, [f32; 2])>,
+    last_sound: Option<Instant>,
+}
+
+pub(crate) struct Levels {
+    pub peak: [f32; 2],
+    /// rms of the peaks rather than the samples, so it reads a little hot.
+    /// good enough to tell music from silence
+    pub rms: [f32; 2],
+    pub signal: bool,
+}
+
+impl Meters {
+    /// interleaved left/right peaks, straight off the stream
+    pub fn push(&self, peaks: &[f32]) {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        for frame in peaks.chunks_exact(2) {
+            let frame = [frame[0].abs(), frame[1].abs()];
+            if frame.iter().any(|&p| p > SILENCE) {
+                inner.last_sound = Some(now);
+            }
+            inner.peaks.push_back((now, frame));
+        }
+        inner.trim(now);
+    }
+
+    /// the source went away, so there's nothing to show
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = Inner::default();
+    }
+
+    pub fn levels(&self) -> Levels {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        inner.trim(now);
+        let mut peak = [0.0f32; 2];
+        let mut sum = [0.0f32; 2];
+        for (_, frame) in &inner.peaks {
+            for ((max, sum), &p) in peak.iter_mut().zip(&mut sum).zip(frame) {
+                *max = max.max(p);
+                *sum += p * p;
+            }
+        }
+        let n = inner.peaks.len().max(1) as f32;
+        Levels {
+            peak,
+            rms: sum.map(|s| (s / n).sqrt()),
+            signal: inner
+                .last_sound
+                .map_or(false, |t| now.duration_since(t) < NO_SIGNAL_AFTER),
+        }
+    }
+}
+
+impl Inner {
+    fn trim(&mut self, now: Instant) {
+        while let Some(&(t, _)) = self.peaks.front() {
+            if now.duration_since(t) <= WINDOW {
+                break;
+            }
+            self.peaks.pop_front();
+        }
+    }
+}
+
+/// where on a -60..0 dBFS meter `level` goes, from 0 to 1
+pub(crate) fn meter_pos(level: f32) -> f32 {
+    if level <= SILENCE {
+        return 0.0;
+    }
+    ((20.0 * level.log10() + 60.0) / 60.0).clamp(0.0, 1.0)
+}
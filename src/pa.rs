@@ -0,0 +1,660 @@
+//! a little executor on top of pulse's standard mainloop, and async versions of
+//! the context calls we use
+//!
+//! everything here is single-threaded: tasks, contexts and streams all live on
+//! the thread that calls [`PaRuntime::run`]. the only thing that crosses
+//! threads is the waker, which pokes the mainloop through a pipe
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{self, Future};
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll, Wake, Waker};
+use std::time::Duration;
+
+use pulse::callbacks::ListResult;
+use pulse::context::{self, introspect, subscribe};
+use pulse::error::PAErr;
+use pulse::mainloop::standard::Mainloop;
+use pulse::mainloop::{self, api::Mainloop as _};
+use pulse::operation::{self, Operation};
+use pulse::time::MonotonicTs;
+use pulse::volume::ChannelVolumes;
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// the server (or libpulse) said no
+    Pulse(PAErr),
+    /// the operation got cancelled before it finished, which mostly means the
+    /// context went away under it
+    Cancelled,
+}
+
+pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Pulse(e) => write!(f, "{e}"),
+            Error::Cancelled => f.write_str("operation cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<PAErr> for Error {
+    fn from(e: PAErr) -> Self {
+        Error::Pulse(e)
+    }
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// ids of tasks that got woken, and the pipe that tells the mainloop about them
+struct ReadyQueue {
+    ids: Mutex<Vec<usize>>,
+    pipe: os_pipe::PipeWriter,
+}
+
+impl ReadyQueue {
+    fn push(&self, id: usize) {
+        let mut ids = self.ids.lock().unwrap();
+        // the mainloop's already been poked if there's anything in there
+        if ids.is_empty() {
+            let _ = (&self.pipe).write(b"X");
+        }
+        ids.push(id);
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.push(self.id);
+    }
+}
+
+struct Shared {
+    tasks: RefCell<HashMap<usize, Task>>,
+    next_id: Cell<usize>,
+    queue: Arc<ReadyQueue>,
+    looop: RefCell<Mainloop>,
+    // a task panicked, we pass it on once the mainloop's stopped
+    panic: Cell<Option<Box<dyn std::any::Any + Send>>>,
+}
+
+impl Shared {
+    fn poll_ready(&self) {
+        // anything that gets woken while we're at it waits for the next round,
+        // so a busy task can't keep the mainloop from doing its thing
+        let ready = mem::take(&mut *self.queue.ids.lock().unwrap());
+        for id in ready {
+            // not there if it's already finished, or got woken twice
+            let mut fut = match self.tasks.borrow_mut().remove(&id) {
+                Some(fut) => fut,
+                None => continue,
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                queue: self.queue.clone(),
+            }));
+            let mut cx = task::Context::from_waker(&waker);
+            match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut cx))) {
+                Ok(Poll::Pending) => {
+                    self.tasks.borrow_mut().insert(id, fut);
+                }
+                Ok(Poll::Ready(())) => {}
+                Err(e) => {
+                    self.panic.set(Some(e));
+                    self.looop.borrow_mut().quit(pulse::def::Retval(111));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// for spawning more tasks onto a runtime, from inside or outside of it
+#[derive(Clone)]
+pub(crate) struct Handle(Rc<Shared>);
+
+impl Handle {
+    /// run `fut` alongside everything else. it only gets polled while the
+    /// runtime's running, and gets dropped when the main future finishes
+    pub fn spawn<T: 'static>(&self, fut: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+        let (tx, rx) = flume::bounded(1);
+        let id = self.0.next_id.get();
+        self.0.next_id.set(id + 1);
+        let task = Box::pin(async move {
+            let _ = tx.send(fut.await);
+        });
+        self.0.tasks.borrow_mut().insert(id, task);
+        self.0.queue.push(id);
+        JoinHandle { rx }
+    }
+
+    /// wait on a mainloop timer
+    pub async fn sleep(&self, dur: Duration) {
+        let (tx, rx) = flume::bounded(1);
+        // dropping the event cancels it, so it has to stick around till it fires
+        let _timer = self.0.looop.borrow_mut().new_timer_event_rt(
+            MonotonicTs::now() + dur,
+            Box::new(move |_| {
+                let _ = tx.try_send(());
+            }),
+        );
+        let _ = rx.recv_async().await;
+    }
+
+    /// a new context on this runtime's mainloop, not connected yet
+    pub fn make_context(&self, name: &str) -> Context {
+        let mut ctx = context::Context::new(&*self.0.looop.borrow(), name).unwrap();
+        let state_waiters = Rc::new(RefCell::new(Vec::<Waker>::new()));
+        ctx.set_state_callback(Some(Box::new({
+            let waiters = state_waiters.clone();
+            move || {
+                for waker in waiters.borrow_mut().drain(..) {
+                    waker.wake();
+                }
+            }
+        })));
+        Context {
+            inner: Rc::new(RefCell::new(ctx)),
+            state_waiters,
+        }
+    }
+}
+
+/// the other end of a spawned task. dropping it just detaches the task
+pub(crate) struct JoinHandle<T> {
+    rx: flume::Receiver<T>,
+}
+
+impl<T> JoinHandle<T> {
+    /// wait for the task's result. it's `Cancelled` if the task got dropped
+    /// before it finished
+    pub async fn join(self) -> Result<T> {
+        self.rx.recv_async().await.map_err(|_| Error::Cancelled)
+    }
+}
+
+pub(crate) struct PaRuntime {
+    handle: Handle,
+    wakee: os_pipe::PipeReader,
+    looop: Mainloop,
+}
+
+impl PaRuntime {
+    pub fn new() -> Self {
+        let (wakee, pipe) = os_pipe::pipe().unwrap();
+        let looop = Mainloop::new().unwrap();
+        let shared = Shared {
+            tasks: RefCell::default(),
+            next_id: Cell::new(0),
+            queue: Arc::new(ReadyQueue {
+                ids: Mutex::default(),
+                pipe,
+            }),
+            looop: RefCell::new(Mainloop {
+                _inner: looop._inner.clone(),
+            }),
+            panic: Cell::new(None),
+        };
+        Self {
+            handle: Handle(Rc::new(shared)),
+            wakee,
+            looop,
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    pub fn make_context(&self, name: &str) -> Context {
+        self.handle.make_context(name)
+    }
+
+    /// run the mainloop until `fut` is done. any other tasks still going at
+    /// that point get dropped, and a panic in any of them comes out of here
+    pub fn run<R: 'static>(mut self, fut: impl Future<Output = R> + 'static) -> R {
+        let shared = self.handle.0.clone();
+        let ret = Rc::new(Cell::new(None));
+        self.handle.spawn({
+            let ret = ret.clone();
+            let shared = shared.clone();
+            async move {
+                ret.set(Some(fut.await));
+                shared.looop.borrow_mut().quit(pulse::def::Retval(0));
+            }
+        });
+        let ev = self.looop.new_deferred_event(Box::new({
+            let shared = shared.clone();
+            move |mut ev| {
+                ev.disable();
+                shared.poll_ready();
+            }
+        }));
+        let mut ev = ev.unwrap();
+        let mut wakee = self.wakee;
+        let io = self.looop.new_io_event(
+            wakee.as_raw_fd(),
+            mainloop::events::io::FlagSet::INPUT,
+            Box::new(move |_, _, _| {
+                // it's readable, so this won't block
+                let _ = wakee.read(&mut [0u8; 64]);
+                ev.enable();
+            }),
+        );
+        let _ = self.looop.run();
+        drop(io);
+        // the tasks hang on to handles, which hang on to the tasks
+        let tasks = mem::take(&mut *shared.tasks.borrow_mut());
+        drop(tasks);
+        if let Some(e) = shared.panic.take() {
+            panic::resume_unwind(e);
+        }
+        ret.take()
+            .expect("mainloop stopped before the future finished")
+    }
+}
+
+/// a pulse context that can be shared between tasks
+#[derive(Clone)]
+pub(crate) struct Context {
+    inner: Rc<RefCell<context::Context>>,
+    // everything waiting on the state to change, since the context only takes
+    // the one callback
+    state_waiters: Rc<RefCell<Vec<Waker>>>,
+}
+
+/// what changed, how, and the index of the thing it happened to
+pub(crate) type Event = (subscribe::Facility, subscribe::Operation, u32);
+
+macro_rules! info_list {
+    ($($(#[$attr:meta])* $name:ident => $get:ident($info:ty);)*) => {$(
+        $(#[$attr])*
+        pub async fn $name<U: 'static>(
+            &self,
+            mut f: impl FnMut(&$info) -> Option<U> + 'static,
+        ) -> Result<Vec<U>> {
+            let v = Rc::new(RefCell::new(Vec::new()));
+            let v2 = v.clone();
+            let op = self.inner.borrow().introspect().$get(move |l| {
+                if let ListResult::Item(x) = l {
+                    if let Some(x) = f(x) {
+                        v2.borrow_mut().push(x)
+                    }
+                }
+            });
+            wait_for_list(op, v).await
+        }
+    )*};
+}
+
+impl Context {
+    pub async fn connect(&self) -> Result<()> {
+        self.inner
+            .borrow_mut()
+            .connect(None, context::FlagSet::NOFLAGS, None)?;
+        let state = self
+            .wait_state(|s| {
+                matches!(
+                    s,
+                    context::State::Ready | context::State::Failed | context::State::Terminated
+                )
+            })
+            .await;
+        match state {
+            context::State::Ready => Ok(()),
+            _ => Err(self.errno().into()),
+        }
+    }
+
+    /// done once the connection's gone, whether it got refused, the server went
+    /// away, or something else. gives back why
+    pub async fn closed(&self) -> Error {
+        self.wait_state(|s| matches!(s, context::State::Failed | context::State::Terminated))
+            .await;
+        self.errno().into()
+    }
+
+    async fn wait_state(&self, done: impl Fn(context::State) -> bool) -> context::State {
+        future::poll_fn(|cx| {
+            let state = self.inner.borrow().get_state();
+            if done(state) {
+                return Poll::Ready(state);
+            }
+            let mut waiters = self.state_waiters.borrow_mut();
+            if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// get at the context itself, for making streams and such
+    pub fn with<R>(&self, f: impl FnOnce(&mut context::Context) -> R) -> R {
+        f(&mut self.inner.borrow_mut())
+    }
+
+    pub fn errno(&self) -> PAErr {
+        self.inner.borrow().errno()
+    }
+
+    info_list! {
+        source_info_list => get_source_info_list(introspect::SourceInfo);
+        sink_info_list => get_sink_info_list(introspect::SinkInfo);
+        sink_input_info_list => get_sink_input_info_list(introspect::SinkInputInfo);
+        module_info_list => get_module_info_list(introspect::ModuleInfo);
+    }
+
+    pub async fn load_module(&self, name: &str, argument: &str) -> Result<u32> {
+        let ret = ReturnSlot::new();
+        let op = self
+            .inner
+            .borrow()
+            .introspect()
+            .load_module(name, argument, ret.callback());
+        match ret.wait(op).await? {
+            // PA_INVALID_INDEX
+            u32::MAX => Err(self.errno().into()),
+            id => Ok(id),
+        }
+    }
+
+    pub async fn unload_module(&self, id: u32) -> Result<()> {
+        let ret = ReturnSlot::new();
+        let op = self
+            .inner
+            .borrow()
+            .introspect()
+            .unload_module(id, ret.callback());
+        self.check(ret.wait(op).await?)
+    }
+
+    pub async fn set_sink_input_volume(&self, index: u32, volume: &ChannelVolumes) -> Result<()> {
+        let ret = ReturnSlot::new();
+        let op = self.inner.borrow().introspect().set_sink_input_volume(
+            index,
+            volume,
+            Some(Box::new(ret.callback())),
+        );
+        self.check(ret.wait(op).await?)
+    }
+
+    pub async fn set_sink_input_mute(&self, index: u32, mute: bool) -> Result<()> {
+        let ret = ReturnSlot::new();
+        let op = self.inner.borrow().introspect().set_sink_input_mute(
+            index,
+            mute,
+            Some(Box::new(ret.callback())),
+        );
+        self.check(ret.wait(op).await?)
+    }
+
+    // the success callbacks just say whether it worked, the why is in errno
+    fn check(&self, success: bool) -> Result<()> {
+        match success {
+            true => Ok(()),
+            false => Err(self.errno().into()),
+        }
+    }
+
+    /// get told about changes to the things in `mask`. a context only has the
+    /// one subscription, so this replaces any earlier one
+    pub fn subscribe(&self, mask: subscribe::InterestMaskSet) -> Subscription {
+        let (tx, rx) = flume::bounded(32);
+        let mut ctx = self.inner.borrow_mut();
+        ctx.set_subscribe_callback(Some(Box::new(move |facility, op, index| {
+            if let (Some(facility), Some(op)) = (facility, op) {
+                let _ = tx.try_send((facility, op, index));
+            }
+        })));
+        ctx.subscribe(mask, |_| {});
+        Subscription {
+            ctx: self.clone(),
+            rx,
+        }
+    }
+}
+
+/// events from [`Context::subscribe`]. dropping it unsubscribes
+pub(crate) struct Subscription {
+    ctx: Context,
+    rx: flume::Receiver<Event>,
+}
+
+impl Subscription {
+    /// the next event, or None if the context's gone
+    pub async fn next(&self) -> Option<Event> {
+        self.rx.recv_async().await.ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // might be getting dropped from inside one of the context's callbacks
+        let mut ctx = match self.ctx.inner.try_borrow_mut() {
+            Ok(ctx) => ctx,
+            Err(_) => return,
+        };
+        ctx.set_subscribe_callback(None);
+        if ctx.get_state() == context::State::Ready {
+            ctx.subscribe(subscribe::InterestMaskSet::NULL, |_| {});
+        }
+    }
+}
+
+// the list is done once the op lets go of its callback, and with it the other rc
+async fn wait_for_list<U>(
+    mut op: Operation<impl ?Sized>,
+    v: Rc<RefCell<Vec<U>>>,
+) -> Result<Vec<U>> {
+    let mut v = Some(v);
+    wake_on_op(&mut op, move || match Rc::try_unwrap(v.take().unwrap()) {
+        Ok(x) => Poll::Ready(x.into_inner()),
+        Err(x) => {
+            v = Some(x);
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+struct ReturnSlot<T> {
+    slot: Rc<Cell<Option<T>>>,
+}
+
+impl<T> ReturnSlot<T> {
+    fn new() -> Self {
+        Self {
+            slot: Rc::new(Cell::new(None)),
+        }
+    }
+
+    fn callback(&self) -> impl FnMut(T) {
+        let mut slot = Some(self.slot.clone());
+        move |val| match slot.take() {
+            Some(slot) => slot.set(Some(val)),
+            None => eprintln!("return cb called multiple times"),
+        }
+    }
+
+    async fn wait(self, mut op: Operation<impl ?Sized>) -> Result<T> {
+        wake_on_op(&mut op, || match self.slot.take() {
+            Some(val) => Poll::Ready(val),
+            None => Poll::Pending,
+        })
+        .await
+    }
+}
+
+async fn wake_on_op<T>(
+    op: &mut Operation<impl ?Sized>,
+    mut f: impl FnMut() -> Poll<T>,
+) -> Result<T> {
+    future::poll_fn(|cx| {
+        // a cancelled op drops its callback, which would look like an empty list
+        if op.get_state() == operation::State::Cancelled {
+            op.set_state_callback(None);
+            return Poll::Ready(Err(Error::Cancelled));
+        }
+        let poll = f();
+        if poll.is_ready() {
+            op.set_state_callback(None);
+        } else {
+            let waker = cx.waker().clone();
+            op.set_state_callback(Some(Box::new(move || waker.wake_by_ref())));
+        }
+        poll.map(Ok)
+    })
+    .await
+}
+
+/// whether there's a pulse server around to test against. goes by the socket,
+/// so tests can skip without libpulse getting involved at all
+#[cfg(test)]
+pub(crate) fn test_server() -> bool {
+    if std::env::var_os("PULSE_SERVER").is_some() {
+        return true;
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .is_some_and(|dir| std::path::Path::new(&dir).join("pulse/native").exists())
+}
+
+/// these need a pulse server (pipewire-pulse works too), and skip without one
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! need_server {
+        () => {
+            if !test_server() {
+                return eprintln!("no pulse server, skipping");
+            }
+        };
+    }
+
+    fn null_sink_args() -> String {
+        format!("sink_name=ccdisplay-pa-test-{}", std::process::id())
+    }
+
+    #[test]
+    fn spawned_tasks_run_alongside() {
+        need_server!();
+        let rt = PaRuntime::new();
+        let handle = rt.handle();
+        let sum = rt.run(async move {
+            let slow = handle.spawn({
+                let handle = handle.clone();
+                async move {
+                    handle.sleep(Duration::from_millis(20)).await;
+                    2
+                }
+            });
+            let quick = handle.spawn(async { 1 });
+            Ok::<_, Error>(quick.join().await? + slow.join().await?)
+        });
+        assert_eq!(sum.unwrap(), 3);
+    }
+
+    #[test]
+    fn unfinished_tasks_are_cancelled() {
+        need_server!();
+        let rt = PaRuntime::new();
+        let handle = rt.handle();
+        let forever = rt.run(async move {
+            handle.spawn({
+                let handle = handle.clone();
+                async move { handle.sleep(Duration::from_secs(3600)).await }
+            })
+        });
+        let res = PaRuntime::new().run(forever.join());
+        assert!(matches!(res, Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn task_panics_come_out_of_run() {
+        need_server!();
+        let rt = PaRuntime::new();
+        let handle = rt.handle();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            rt.run(async move {
+                let task = handle.spawn(async { panic!("a task going down on purpose") });
+                let _ = task.join().await;
+            })
+        }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn return_slots_carry_results_and_errors() {
+        need_server!();
+        let rt = PaRuntime::new();
+        let ctx = rt.make_context("ccdisplay test");
+        let res = rt.run(async move {
+            ctx.connect().await?;
+            let mod_id = ctx
+                .load_module("module-null-sink", &null_sink_args())
+                .await?;
+            ctx.unload_module(mod_id).await?;
+            // it's gone now, so this one should fail
+            Ok::<_, Error>(ctx.unload_module(mod_id).await)
+        });
+        assert!(matches!(res, Ok(Err(Error::Pulse(_)))), "{res:?}");
+    }
+
+    #[test]
+    fn subscriptions_see_sinks_come_and_go() {
+        need_server!();
+        let rt = PaRuntime::new();
+        let ctx = rt.make_context("ccdisplay test");
+        let res = rt.run(async move {
+            ctx.connect().await?;
+            let sub = ctx.subscribe(subscribe::InterestMaskSet::SINK);
+            // other sinks might be changing too, so skip anything else
+            let wait_for = |want: subscribe::Operation, index: Option<u32>| {
+                let sub = &sub;
+                async move {
+                    while let Some((facility, op, i)) = sub.next().await {
+                        if facility == subscribe::Facility::Sink
+                            && op == want
+                            && index.unwrap_or(i) == i
+                        {
+                            return Some(i);
+                        }
+                    }
+                    None
+                }
+            };
+            let mod_id = ctx
+                .load_module("module-null-sink", &null_sink_args())
+                .await?;
+            let added = wait_for(subscribe::Operation::New, None).await;
+            ctx.unload_module(mod_id).await?;
+            let removed = wait_for(subscribe::Operation::Removed, added).await;
+            Ok::<_, Error>((added, removed))
+        });
+        let (added, removed) = res.unwrap();
+        assert!(added.is_some());
+        assert_eq!(removed, added);
+    }
+}
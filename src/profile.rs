@@ -0,0 +1,58 @@
+use rusb::UsbContext;
+
+use crate::{video, DeviceId};
+
+/// usb devices getting plugged in, so we can switch to the profile that goes
+/// with them. serials come from sysfs, opening every device would be rude
+pub(crate) struct Hotplug {
+    _reg: rusb::Registration<rusb::Context>,
+    pub rx: flume::Receiver<DeviceId>,
+}
+
+impl Hotplug {
+    pub fn new(ctx: egui::Context) -> rusb::Result<Self> {
+        if !rusb::has_hotplug() {
+            return Err(rusb::Error::NotSupported);
+        }
+        let usb_ctx = rusb::Context::new()?;
+        let (tx, rx) = flume::unbounded();
+        struct Callback {
+            tx: flume::Sender<DeviceId>,
+            ctx: egui::Context,
+        }
+        impl rusb::Hotplug<rusb::Context> for Callback {
+            fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+                if let Ok(desc) = device.device_descriptor() {
+                    let port_path = video::port_path(&device);
+                    let _ = self.tx.send(DeviceId {
+                        vendor_id: Some(desc.vendor_id()),
+                        product_id: Some(desc.product_id()),
+                        serial: port_path.as_deref().and_then(sysfs_serial),
+                        port_path,
+                    });
+                    self.ctx.request_repaint();
+                }
+            }
+
+            fn device_left(&mut self, _: rusb::Device<rusb::Context>) {}
+        }
+        // no enumerate, whatever's plugged in already isn't news
+        let reg = rusb::HotplugBuilder::new().register(&usb_ctx, Box::new(Callback { tx, ctx }))?;
+        std::thread::spawn(move || loop {
+            if let Err(e) = usb_ctx.handle_events(None) {
+                eprintln!("libusb error? {e}")
+            }
+        });
+        Ok(Self { _reg: reg, rx })
+    }
+}
+
+/// usb device dirs are named bus-ports too. only there once the kernel's
+/// caught up, which it usually has by the time libusb tells us
+fn sysfs_serial(port_path: &str) -> Option<String> {
+    let path = std::path::Path::new("/sys/bus/usb/devices")
+        .join(port_path)
+        .join("serial");
+    let serial = std::fs::read_to_string(path).ok()?;
+    Some(serial.trim_end_matches('\n').to_owned()).filter(|s| !s.is_empty())
+}
@@ -0,0 +1,465 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use pipewire as pw;
+use pw::types::ObjectType;
+
+use crate::audio::{AudioConfig, AudioParams, AudioSource, UsbMatch};
+use crate::stats::{AudioStatus, Stats};
+
+struct Node {
+    name: String,
+    /// the device object it belongs to
+    device: Option<u32>,
+    is_source: bool,
+}
+
+struct Port {
+    node: u32,
+    output: bool,
+    channel: Option<String>,
+}
+
+/// the bits of the pipewire graph we care about
+#[derive(Default)]
+struct Graph {
+    nodes: HashMap<u32, Node>,
+    /// the usb-ish properties of device objects, for finding the video device's
+    /// audio
+    devices: HashMap<u32, HashMap<String, String>>,
+    /// Audio/Sink nodes, in case there's no default sink set
+    sinks: Vec<u32>,
+    ports: HashMap<u32, Port>,
+    default_sink: Option<String>,
+    // only None so the graph can be Default
+    source: Option<AudioSource>,
+    /// the sink picked in settings, if any
+    sink: Option<String>,
+    // the (output, input) port pairs we've linked, and the links themselves.
+    // they don't linger, so dropping the proxy removes the link
+    linked: Vec<(u32, u32)>,
+    links: Vec<pw::link::Link>,
+    metadata: Option<(pw::metadata::Metadata, pw::metadata::MetadataListener)>,
+}
+
+/// what the ui wants from the audio thread
+enum Event {
+    Done,
+    Config(AudioConfig),
+    Recording(bool),
+    Volume,
+}
+
+/// link the capture node's ports straight to the default sink's. hands the
+/// params back if we can't connect to pipewire at all
+pub(crate) fn audio_loop(params: AudioParams) -> Result<(), (anyhow::Error, AudioParams)> {
+    let setup = || -> anyhow::Result<_> {
+        pw::init();
+        let mainloop = pw::MainLoop::new()?;
+        let context = pw::Context::new(&mainloop)?;
+        let core = context.connect(None)?;
+        let registry = Rc::new(core.get_registry()?);
+        Ok((mainloop, context, core, registry))
+    };
+    let (mainloop, _context, core, registry) = match setup() {
+        Ok(x) => x,
+        Err(e) => return Err((e, params)),
+    };
+    let AudioParams {
+        done_ch: (finished_tx, done_rx),
+        config,
+        config_rx,
+        recorder: _,
+        rec_rx,
+        volume: _,
+        volume_rx,
+        meters: _,
+        stats,
+    } = params;
+    stats.set_audio(AudioStatus::NoSource(config.source.to_string()));
+    stats.set_pipewire();
+
+    let graph = Rc::new(RefCell::new(Graph {
+        source: Some(config.source),
+        sink: config.sink,
+        ..Default::default()
+    }));
+
+    let _listener = registry
+        .add_listener_local()
+        .global({
+            let graph = graph.clone();
+            let core = core.clone();
+            let stats = stats.clone();
+            let registry = Rc::downgrade(&registry);
+            move |global| {
+                let props = match global.props.as_ref() {
+                    Some(props) => props,
+                    None => return,
+                };
+                match global.type_ {
+                    ObjectType::Node => {
+                        let name = match props.get("node.name") {
+                            Some(name) => name.to_owned(),
+                            None => return,
+                        };
+                        let mut g = graph.borrow_mut();
+                        let class = props.get("media.class");
+                        if class == Some("Audio/Sink") {
+                            g.sinks.push(global.id);
+                        }
+                        let node = Node {
+                            name,
+                            device: props.get("device.id").and_then(|id| id.parse().ok()),
+                            is_source: class == Some("Audio/Source"),
+                        };
+                        g.nodes.insert(global.id, node);
+                    }
+                    ObjectType::Device => {
+                        let usb_props =
+                            ["device.vendor.id", "device.product.id", "device.bus-path"]
+                                .into_iter()
+                                .filter_map(|key| {
+                                    Some((key.to_owned(), props.get(key)?.to_owned()))
+                                })
+                                .collect();
+                        graph.borrow_mut().devices.insert(global.id, usb_props);
+                    }
+                    ObjectType::Port => {
+                        let node = match props.get("node.id").and_then(|id| id.parse().ok()) {
+                            Some(node) => node,
+                            None => return,
+                        };
+                        let port = Port {
+                            node,
+                            output: props.get("port.direction") == Some("out"),
+                            channel: props.get("audio.channel").map(str::to_owned),
+                        };
+                        graph.borrow_mut().ports.insert(global.id, port);
+                    }
+                    ObjectType::Metadata if props.get("metadata.name") == Some("default") => {
+                        let registry = match registry.upgrade() {
+                            Some(r) => r,
+                            None => return,
+                        };
+                        let metadata: pw::metadata::Metadata = match registry.bind(global) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                eprintln!("couldn't get the default sink from pipewire: {e}");
+                                return;
+                            }
+                        };
+                        let listener = metadata
+                            .add_listener_local()
+                            .property({
+                                let graph = graph.clone();
+                                let core = core.clone();
+                                let stats = stats.clone();
+                                move |_subject, key, _type, value| {
+                                    if key == Some("default.audio.sink") {
+                                        let mut g = graph.borrow_mut();
+                                        g.default_sink = value.and_then(json_name);
+                                        relink(&core, &mut g, &stats);
+                                    }
+                                    0
+                                }
+                            })
+                            .register();
+                        graph.borrow_mut().metadata = Some((metadata, listener));
+                        return;
+                    }
+                    _ => return,
+                }
+                relink(&core, &mut graph.borrow_mut(), &stats);
+            }
+        })
+        .global_remove({
+            let graph = graph.clone();
+            let core = core.clone();
+            let stats = stats.clone();
+            move |id| {
+                let mut g = graph.borrow_mut();
+                let known = g.nodes.remove(&id).is_some()
+                    || g.ports.remove(&id).is_some()
+                    || g.devices.remove(&id).is_some();
+                g.sinks.retain(|&sink| sink != id);
+                if known {
+                    relink(&core, &mut g, &stats);
+                }
+            }
+        })
+        .register();
+
+    // the pipewire loop can't wait on a flume channel, so a thread waits on
+    // them instead and wakes it up through a pipewire one
+    let (event_tx, event_rx) = pw::channel::channel();
+    std::thread::spawn(move || loop {
+        let event = flume::Selector::new()
+            .recv(&done_rx, |_| Event::Done)
+            .recv(&config_rx, |c| c.map_or(Event::Done, Event::Config))
+            .recv(&rec_rx, |on| on.map_or(Event::Done, Event::Recording))
+            .recv(&volume_rx, |v| v.map_or(Event::Done, |_| Event::Volume))
+            .wait();
+        let done = matches!(event, Event::Done);
+        if event_tx.send(event).is_err() || done {
+            break;
+        }
+    });
+    let _events = event_rx.attach(&mainloop, {
+        let mainloop = mainloop.clone();
+        let graph = graph.clone();
+        let core = core.clone();
+        let stats = stats.clone();
+        move |event| match event {
+            Event::Done => mainloop.quit(),
+            // links don't need a mode or a latency, there's nothing in between
+            Event::Config(config) => {
+                let mut g = graph.borrow_mut();
+                g.source = Some(config.source);
+                g.sink = config.sink;
+                relink(&core, &mut g, &stats);
+            }
+            // the ui says so already, this is just for the log
+            Event::Recording(true) => {
+                eprintln!("the pipewire backend can't record audio yet, recording video only");
+            }
+            Event::Recording(false) => {}
+            Event::Volume => eprintln!("the pipewire backend can't change the volume yet"),
+        }
+    });
+
+    mainloop.run();
+
+    graph.borrow_mut().links.clear();
+    let _ = finished_tx.try_send(());
+    Ok(())
+}
+
+/// make the links match what the graph looks like now
+fn relink(core: &pw::Core, g: &mut Graph, stats: &Stats) {
+    let find = |name: &str| {
+        g.nodes
+            .iter()
+            .find(|(_, n)| n.name == name)
+            .map(|(&id, _)| id)
+    };
+    let from_device = |usb: &UsbMatch| {
+        g.nodes
+            .iter()
+            .find(|(_, n)| {
+                let props = match n.device.and_then(|d| g.devices.get(&d)) {
+                    Some(props) if n.is_source => props,
+                    _ => return false,
+                };
+                usb.matches(
+                    |key| props.get(key).cloned(),
+                    "device.vendor.id",
+                    "device.product.id",
+                    "device.bus-path",
+                )
+            })
+            .map(|(&id, _)| id)
+    };
+    let source = match g.source.as_ref() {
+        Some(AudioSource::Named(name)) => find(name),
+        Some(AudioSource::SameDevice(usb)) => from_device(usb),
+        None => None,
+    };
+    let sink = g
+        .sink
+        .as_deref()
+        .and_then(find)
+        .or_else(|| g.default_sink.as_deref().and_then(find))
+        .or_else(|| g.sinks.first().copied());
+    let (source, sink) = match (source, sink) {
+        (Some(source), Some(sink)) => (source, sink),
+        _ => {
+            stats.set_audio(match source {
+                None => AudioStatus::NoSource(
+                    g.source.as_ref().map(|s| s.to_string()).unwrap_or_default(),
+                ),
+                Some(_) => AudioStatus::Error("nowhere to play it".to_owned()),
+            });
+            g.links.clear();
+            g.linked.clear();
+            return;
+        }
+    };
+    let pairs = port_pairs(g, source, sink);
+    if pairs == g.linked {
+        return;
+    }
+    g.links.clear();
+    g.linked.clear();
+    for &(output, input) in &pairs {
+        let props = pw::properties! {
+            "link.output.node" => source.to_string(),
+            "link.output.port" => output.to_string(),
+            "link.input.node" => sink.to_string(),
+            "link.input.port" => input.to_string(),
+            "object.linger" => "false",
+        };
+        match core.create_object::<pw::link::Link, _>("link-factory", &props) {
+            Ok(link) => {
+                g.links.push(link);
+                g.linked.push((output, input));
+            }
+            Err(e) => eprintln!("couldn't link port {output} to {input}: {e}"),
+        }
+    }
+    stats.set_audio(AudioStatus::Linked {
+        source: g.nodes[&source].name.clone(),
+        sink: g.nodes[&sink].name.clone(),
+    });
+}
+
+/// pair the source's outputs up with the sink's inputs by channel, falling back
+/// to the order they're in
+fn port_pairs(g: &Graph, source: u32, sink: u32) -> Vec<(u32, u32)> {
+    let ports = |node, output| {
+        let mut ports = g
+            .ports
+            .iter()
+            .filter(|(_, p)| p.node == node && p.output == output)
+            .map(|(&id, p)| (id, p.channel.as_deref()))
+            .collect::<Vec<_>>();
+        ports.sort_by_key(|&(id, _)| id);
+        ports
+    };
+    let (outputs, inputs) = (ports(source, true), ports(sink, false));
+    if let [(mono, _)] = outputs[..] {
+        return inputs.iter().map(|&(input, _)| (mono, input)).collect();
+    }
+    outputs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &(output, channel))| {
+            let same_channel = channel.and_then(|ch| inputs.iter().find(|(_, c)| *c == Some(ch)));
+            same_channel
+                .or_else(|| inputs.get(i))
+                .map(|&(input, _)| (output, input))
+        })
+        .collect()
+}
+
+/// the default sink metadata looks like `{ "name": "alsa_output.whatever" }`
+fn json_name(value: &str) -> Option<String> {
+    let rest = value.split_once("\"name\"")?.1;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    Some(rest[..rest.find('"')?].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn graph(ports: &[(u32, u32, bool, Option<&str>)]) -> Graph {
+        let ports = ports.iter().map(|&(id, node, output, channel)| {
+            let channel = channel.map(str::to_owned);
+            (
+                id,
+                Port {
+                    node,
+                    output,
+                    channel,
+                },
+            )
+        });
+        Graph {
+            ports: ports.collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ports_pair_up_by_channel() {
+        let g = graph(&[
+            (10, 1, true, Some("FR")),
+            (11, 1, true, Some("FL")),
+            (20, 2, false, Some("FL")),
+            (21, 2, false, Some("FR")),
+        ]);
+        assert_eq!(port_pairs(&g, 1, 2), [(10, 21), (11, 20)]);
+    }
+
+    #[test]
+    fn ports_fall_back_to_order() {
+        let g = graph(&[
+            (10, 1, true, None),
+            (11, 1, true, None),
+            (12, 1, true, None),
+            (20, 2, false, Some("FL")),
+            (21, 2, false, Some("FR")),
+        ]);
+        assert_eq!(port_pairs(&g, 1, 2), [(10, 20), (11, 21)]);
+        // mono goes to every input
+        let g = graph(&[
+            (10, 1, true, Some("MONO")),
+            (20, 2, false, None),
+            (21, 2, false, None),
+        ]);
+        assert_eq!(port_pairs(&g, 1, 2), [(10, 20), (10, 21)]);
+    }
+
+    #[test]
+    fn default_sink_name() {
+        let value = r#"{ "name": "alsa_output.pci-0000_00_1f.3.analog-stereo" }"#;
+        assert_eq!(
+            json_name(value).as_deref(),
+            Some("alsa_output.pci-0000_00_1f.3.analog-stereo")
+        );
+        assert_eq!(json_name(r#"{"name":"x"}"#).as_deref(), Some("x"));
+        assert_eq!(json_name("{}"), None);
+    }
+
+    fn wait_for(what: &str, mut f: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(
+                start.elapsed() < Duration::from_secs(2),
+                "timed out waiting for {what}"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// needs a pipewire daemon, but no devices
+    #[test]
+    fn ui_messages_wake_the_loop() {
+        let source = |name: &str| AudioSource::Named(name.to_owned());
+        let (params, ui) = AudioParams::for_test(source("ccdisplay-test-nothing"));
+        let pw = std::thread::spawn(move || audio_loop(params).map_err(|(e, _)| e));
+        let start = Instant::now();
+        while !ui.stats.pipewire() {
+            if pw.is_finished() {
+                let e = pw.join().unwrap().unwrap_err();
+                return eprintln!("no pipewire, skipping: {e:#}");
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(2),
+                "pipewire never connected"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let (params, _) = AudioParams::for_test(source("ccdisplay-test-other"));
+        ui.config_tx.send(params.config).unwrap();
+        wait_for(
+            "the new source",
+            || matches!(ui.stats.snapshot().audio, AudioStatus::NoSource(name) if name == "ccdisplay-test-other"),
+        );
+        // doesn't do anything, but mustn't take the loop down either
+        ui.volume_tx.send(Default::default()).unwrap();
+
+        let quit = Instant::now();
+        ui.done_tx.send(()).unwrap();
+        ui.finished_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        // the old 100ms poll would've taken up to this long
+        assert!(quit.elapsed() < Duration::from_millis(50));
+        pw.join().unwrap().unwrap();
+    }
+}
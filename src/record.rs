@@ -0,0 +1,759 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::convert::{Frame, PixelFormat};
+
+/// what we ask pulse for, s16le
+pub(crate) const AUDIO_RATE: u32 = 48000;
+pub(crate) const AUDIO_CHANNELS: u8 = 2;
+pub(crate) const AUDIO_BLOCK: u32 = AUDIO_CHANNELS as u32 * 2;
+
+// avi 1.0 falls over past 2GiB, so start a new file well before that
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+// about half a second of 60fps video plus the audio in between. past that the
+// disk isn't keeping up and we'd rather drop than stall the video thread
+const QUEUE_LEN: usize = 64;
+
+/// writes the frames and audio going past into avi files while it's on, and
+/// keeps the last little bit around for instant replay.
+/// mjpeg gets copied straight through, everything else is stored raw.
+/// all the writing happens on a thread of its own, the video and audio threads
+/// only ever copy what they've got into a channel
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    tx: flume::Sender<Msg>,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    // recording or keeping a replay, so frames and samples are wanted
+    active: AtomicBool,
+    // set while we're behind, so dropping shows up once in the log instead of every frame
+    dropping: AtomicBool,
+    // when the current recording started
+    started: Mutex<Option<Instant>>,
+}
+
+type SaveDone = Box<dyn FnOnce(anyhow::Result<PathBuf>) + Send>;
+
+enum Msg {
+    Video(BufferedFrame),
+    Audio(Instant, Arc<[u8]>),
+    Start(Recording),
+    Stop(flume::Sender<()>),
+    SetReplay(Option<ReplayLimits>),
+    SaveReplay(PathBuf, u32, SaveDone),
+}
+
+/// how much instant replay to hold on to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ReplayLimits {
+    pub secs: u32,
+    pub max_mb: u32,
+}
+
+impl Recorder {
+    /// the receiver tells the audio thread when to start and stop sending us samples
+    pub fn new() -> (Self, flume::Receiver<bool>) {
+        let (audio_tx, audio_rx) = flume::unbounded();
+        let (tx, rx) = flume::bounded(QUEUE_LEN);
+        let shared = Arc::new(Shared::default());
+        let writer = Writer {
+            recording: None,
+            replay: None,
+            audio_on: false,
+            audio_tx,
+            shared: shared.clone(),
+        };
+        std::thread::spawn(move || writer.run(rx));
+        (Self { tx, shared }, audio_rx)
+    }
+
+    pub fn start(&self, dir: &Path, fps: u32) -> anyhow::Result<()> {
+        let rec = Recording::new(dir, "ccdisplay", fps)?;
+        *self.shared.started.lock().unwrap() = Some(rec.started);
+        let _ = self.tx.send(Msg::Start(rec));
+        Ok(())
+    }
+
+    /// returns once the file's been finished
+    pub fn stop(&self) {
+        *self.shared.started.lock().unwrap() = None;
+        let (done_tx, done_rx) = flume::bounded(1);
+        if self.tx.send(Msg::Stop(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+
+    /// how long we've been recording for, if we are
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.shared.started.lock().unwrap().map(|t| t.elapsed())
+    }
+
+    /// start or stop keeping a replay buffer, throwing away what's in it if the
+    /// limits changed
+    pub fn set_replay(&self, limits: Option<ReplayLimits>) {
+        let _ = self.tx.send(Msg::SetReplay(limits));
+    }
+
+    /// write out what's in the replay buffer on another thread, and call
+    /// `done` with where it ended up
+    pub fn save_replay(
+        &self,
+        dir: &Path,
+        fps: u32,
+        done: impl FnOnce(anyhow::Result<PathBuf>) + Send + 'static,
+    ) {
+        let _ = self
+            .tx
+            .send(Msg::SaveReplay(dir.to_owned(), fps, Box::new(done)));
+    }
+
+    pub fn video_frame(&self, frame: &Frame<'_>) {
+        if !self.shared.active.load(Relaxed) {
+            return;
+        }
+        let data = match frame_data(frame) {
+            Some(data) => data,
+            // handle_frame will complain about it
+            None => return,
+        };
+        self.queue(Msg::Video(BufferedFrame {
+            at: frame.captured,
+            format: VideoFormat::of(frame),
+            data: data.into(),
+        }));
+    }
+
+    /// interleaved s16le at `AUDIO_RATE`
+    pub fn audio_samples(&self, data: &[u8]) {
+        if self.shared.active.load(Relaxed) {
+            self.queue(Msg::Audio(Instant::now(), data.into()));
+        }
+    }
+
+    fn queue(&self, msg: Msg) {
+        match self.tx.try_send(msg) {
+            Ok(()) => self.shared.dropping.store(false, Relaxed),
+            Err(_) => {
+                if !self.shared.dropping.swap(true, Relaxed) {
+                    eprintln!("recording can't keep up, dropping frames and audio");
+                }
+            }
+        }
+    }
+}
+
+/// owns everything that touches the disk
+struct Writer {
+    recording: Option<Recording>,
+    replay: Option<Replay>,
+    // what we last told the audio thread
+    audio_on: bool,
+    audio_tx: flume::Sender<bool>,
+    shared: Arc<Shared>,
+}
+
+impl Writer {
+    fn run(mut self, rx: flume::Receiver<Msg>) {
+        for msg in rx.iter() {
+            match msg {
+                Msg::Video(f) => self.video_frame(f),
+                Msg::Audio(at, data) => self.audio_samples(at, data),
+                Msg::Start(rec) => {
+                    self.finish();
+                    self.recording = Some(rec);
+                }
+                Msg::Stop(done) => {
+                    self.finish();
+                    let _ = done.send(());
+                }
+                Msg::SetReplay(limits) => {
+                    if self.replay.as_ref().map(|r| r.limits) != limits {
+                        self.replay = limits.map(Replay::new);
+                    }
+                }
+                Msg::SaveReplay(dir, fps, done) => self.save_replay(&dir, fps, done),
+            }
+            self.update_audio();
+        }
+        // every Recorder is gone
+        self.finish();
+    }
+
+    fn update_audio(&mut self) {
+        let on = self.recording.is_some() || self.replay.is_some();
+        self.shared.active.store(on, Relaxed);
+        if on != self.audio_on {
+            self.audio_on = on;
+            let _ = self.audio_tx.send(on);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(mut rec) = self.recording.take() {
+            if let Err(e) = rec.finish() {
+                eprintln!("couldn't finish recording: {e:#}");
+            }
+        }
+    }
+
+    fn save_replay(&self, dir: &Path, fps: u32, done: SaveDone) {
+        let snapshot = match &self.replay {
+            Some(replay) => replay.snapshot(),
+            None => return done(Err(anyhow::anyhow!("instant replay is turned off"))),
+        };
+        let rec = Recording::new(dir, "ccdisplay-replay", fps);
+        std::thread::spawn(move || done(rec.and_then(|rec| snapshot.write(rec))));
+    }
+
+    fn video_frame(&mut self, f: BufferedFrame) {
+        let rec = match &mut self.recording {
+            Some(rec) => rec.video_frame(&f.frame()),
+            None => Ok(()),
+        };
+        if let Err(e) = rec {
+            eprintln!("recording failed, stopping: {e:#}");
+            *self.shared.started.lock().unwrap() = None;
+            self.finish();
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.video_frame(f);
+        }
+    }
+
+    fn audio_samples(&mut self, at: Instant, data: Arc<[u8]>) {
+        if let Some(rec) = &mut self.recording {
+            // nothing to line it up against until the first frame shows up
+            if rec.file.is_some() {
+                rec.audio.extend_from_slice(&data);
+            }
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.audio_samples(data, at);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct BufferedFrame {
+    at: Instant,
+    format: VideoFormat,
+    data: Arc<[u8]>,
+}
+
+impl BufferedFrame {
+    fn frame(&self) -> Frame<'_> {
+        Frame {
+            width: self.format.width as usize,
+            height: self.format.height as usize,
+            format: self.format.pixels,
+            data: &self.data,
+            captured: self.at,
+        }
+    }
+}
+
+/// the last few seconds of frames and audio, as they came in
+struct Replay {
+    limits: ReplayLimits,
+    frames: VecDeque<BufferedFrame>,
+    audio: VecDeque<(Instant, Arc<[u8]>)>,
+    bytes: usize,
+}
+
+impl Replay {
+    fn new(limits: ReplayLimits) -> Self {
+        Self {
+            limits,
+            frames: VecDeque::new(),
+            audio: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    fn video_frame(&mut self, frame: BufferedFrame) {
+        let at = frame.at;
+        self.bytes += frame.data.len();
+        self.frames.push_back(frame);
+        self.evict(at);
+    }
+
+    fn audio_samples(&mut self, data: Arc<[u8]>, at: Instant) {
+        self.bytes += data.len();
+        self.audio.push_back((at, data));
+        self.evict(at);
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let max_age = Duration::from_secs(self.limits.secs.into());
+        let max_bytes = self.limits.max_mb as usize * 1024 * 1024;
+        while let Some(f) = self.frames.front() {
+            if now.duration_since(f.at) <= max_age && self.bytes <= max_bytes {
+                break;
+            }
+            self.bytes -= f.data.len();
+            self.frames.pop_front();
+        }
+        // audio from before the oldest frame would just get thrown away anyway
+        let oldest = self.frames.front().map_or(now, |f| f.at);
+        while let Some((at, data)) = self.audio.front() {
+            if *at >= oldest {
+                break;
+            }
+            self.bytes -= data.len();
+            self.audio.pop_front();
+        }
+    }
+
+    fn snapshot(&self) -> ReplaySnapshot {
+        ReplaySnapshot {
+            frames: self.frames.iter().cloned().collect(),
+            audio: self.audio.iter().cloned().collect(),
+        }
+    }
+}
+
+/// a copy of the replay buffer, cheap since it's all `Arc`s
+struct ReplaySnapshot {
+    frames: Vec<BufferedFrame>,
+    audio: Vec<(Instant, Arc<[u8]>)>,
+}
+
+impl ReplaySnapshot {
+    fn write(self, mut rec: Recording) -> anyhow::Result<PathBuf> {
+        anyhow::ensure!(!self.frames.is_empty(), "nothing in the replay buffer yet");
+        let mut audio = self.audio.into_iter().peekable();
+        for f in &self.frames {
+            while let Some((_, data)) = audio.next_if(|(at, _)| *at <= f.at) {
+                if rec.file.is_some() {
+                    rec.audio.extend_from_slice(&data);
+                }
+            }
+            rec.video_frame(&f.frame())?;
+        }
+        rec.finish()?;
+        Ok(rec.path(1))
+    }
+}
+
+/// the part of the frame that's actually picture
+fn frame_data<'a>(frame: &Frame<'a>) -> Option<&'a [u8]> {
+    match frame.format {
+        PixelFormat::Mjpeg => Some(frame.data),
+        f => f
+            .frame_len(frame.width, frame.height)
+            .and_then(|len| frame.data.get(..len)),
+    }
+}
+
+struct Recording {
+    dir: PathBuf,
+    name: String,
+    part: u32,
+    fps: u32,
+    started: Instant,
+    file: Option<AviWriter>,
+    // samples waiting for the next video frame, so the two stay interleaved
+    audio: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+impl Recording {
+    fn new(dir: &Path, prefix: &str, fps: u32) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("couldn't create {}", dir.display()))?;
+        let name = format!(
+            "{prefix}-{}",
+            chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+        );
+        Ok(Recording {
+            dir: dir.to_owned(),
+            name,
+            part: 0,
+            fps: fps.max(1),
+            started: Instant::now(),
+            file: None,
+            audio: vec![],
+            scratch: vec![],
+        })
+    }
+
+    fn path(&self, part: u32) -> PathBuf {
+        match part {
+            1 => self.dir.join(format!("{}.avi", self.name)),
+            n => self.dir.join(format!("{}-{n}.avi", self.name)),
+        }
+    }
+
+    fn video_frame(&mut self, frame: &Frame<'_>) -> anyhow::Result<()> {
+        let at = frame.captured;
+        let data = match frame_data(frame) {
+            Some(data) => data,
+            // handle_frame will complain about it
+            None => return Ok(()),
+        };
+        let format = VideoFormat::of(frame);
+        let new_file = match &self.file {
+            Some(avi) => avi.format != format || avi.pos > MAX_FILE_SIZE,
+            None => true,
+        };
+        if new_file {
+            self.finish()?;
+            self.part += 1;
+            let path = self.path(self.part);
+            eprintln!("recording to {}", path.display());
+            self.file = Some(
+                AviWriter::create(&path, format, self.fps, at)
+                    .with_context(|| format!("couldn't create {}", path.display()))?,
+            );
+        }
+        let avi = self.file.as_mut().unwrap();
+
+        // the file says it's constant framerate, so go by the clock: empty
+        // chunks (players repeat the last frame) for frames that never showed
+        // up, and drop ones that come in early
+        let elapsed = at.saturating_duration_since(avi.started);
+        let due = (elapsed.as_secs_f64() * self.fps as f64).round() as u64;
+        if avi.frames > due {
+            return Ok(());
+        }
+        avi.write_audio(&self.audio)?;
+        self.audio.clear();
+        while avi.frames < due {
+            avi.write_video(&[], false)?;
+        }
+        let data = match frame.format {
+            PixelFormat::Rgb24 => {
+                bottom_up_bgr(&mut self.scratch, data, frame.width);
+                &self.scratch[..]
+            }
+            _ => data,
+        };
+        avi.write_video(data, true)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        match self.file.take() {
+            Some(mut avi) => {
+                avi.write_audio(&self.audio)?;
+                self.audio.clear();
+                avi.finish()?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// dibs are bgr, upside down, and have their rows padded to 4 bytes
+fn bottom_up_bgr(out: &mut Vec<u8>, rgb: &[u8], width: usize) {
+    let stride = (width * 3 + 3) & !3;
+    out.clear();
+    for row in rgb.chunks_exact(width * 3).rev() {
+        out.extend(row.chunks_exact(3).flat_map(|px| [px[2], px[1], px[0]]));
+        out.resize(out.len() + stride - width * 3, 0);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct VideoFormat {
+    width: u32,
+    height: u32,
+    pixels: PixelFormat,
+}
+
+impl VideoFormat {
+    fn of(frame: &Frame<'_>) -> Self {
+        Self {
+            width: frame.width as u32,
+            height: frame.height as u32,
+            pixels: frame.format,
+        }
+    }
+}
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIF_ISINTERLEAVED: u32 = 0x100;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+struct IndexEntry {
+    id: [u8; 4],
+    flags: u32,
+    offset: u32,
+    size: u32,
+}
+
+/// a plain avi 1.0 file with one video and one pcm audio stream
+struct AviWriter {
+    out: BufWriter<File>,
+    format: VideoFormat,
+    fps: u32,
+    started: Instant,
+    pos: u64,
+    // where the 'movi' fourcc is, chunk offsets in the index are relative to it
+    movi_start: u64,
+    index: Vec<IndexEntry>,
+    frames: u64,
+    audio_bytes: u64,
+    max_chunk: u32,
+}
+
+impl AviWriter {
+    fn create(path: &Path, format: VideoFormat, fps: u32, started: Instant) -> io::Result<Self> {
+        let mut avi = Self {
+            out: BufWriter::new(File::create(path)?),
+            format,
+            fps,
+            started,
+            pos: 0,
+            movi_start: 0,
+            index: vec![],
+            frames: 0,
+            audio_bytes: 0,
+            max_chunk: 0,
+        };
+        // sizes and counts get filled in once we know them
+        let header = avi.header(0, 0);
+        avi.out.write_all(&header)?;
+        avi.pos = header.len() as u64;
+        avi.movi_start = avi.pos - 4;
+        Ok(avi)
+    }
+
+    fn write_video(&mut self, data: &[u8], keyframe: bool) -> io::Result<()> {
+        let flags = if keyframe { AVIIF_KEYFRAME } else { 0 };
+        self.write_chunk(*b"00dc", data, flags)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn write_audio(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = &data[..data.len() - data.len() % AUDIO_BLOCK as usize];
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.write_chunk(*b"01wb", data, AVIIF_KEYFRAME)?;
+        self.audio_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, id: [u8; 4], data: &[u8], flags: u32) -> io::Result<()> {
+        let size = data.len() as u32;
+        self.index.push(IndexEntry {
+            id,
+            flags,
+            offset: (self.pos - self.movi_start) as u32,
+            size,
+        });
+        self.out.write_all(&id)?;
+        self.out.write_all(&size.to_le_bytes())?;
+        self.out.write_all(data)?;
+        let pad = data.len() % 2;
+        if pad != 0 {
+            self.out.write_all(&[0])?;
+        }
+        self.pos += 8 + data.len() as u64 + pad as u64;
+        self.max_chunk = self.max_chunk.max(size);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let movi_size = self.pos - self.movi_start;
+        self.out.write_all(b"idx1")?;
+        self.out
+            .write_all(&(self.index.len() as u32 * 16).to_le_bytes())?;
+        for entry in &self.index {
+            self.out.write_all(&entry.id)?;
+            for x in [entry.flags, entry.offset, entry.size] {
+                self.out.write_all(&x.to_le_bytes())?;
+            }
+        }
+        // everything after the riff header itself, idx1 included
+        let riff_size = self.pos + self.index.len() as u64 * 16;
+        let header = self.header(riff_size as u32, movi_size as u32);
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.flush()
+    }
+
+    /// everything up to and including the 'movi' fourcc
+    fn header(&self, riff_size: u32, movi_size: u32) -> Vec<u8> {
+        let VideoFormat {
+            width,
+            height,
+            pixels,
+        } = self.format;
+        let (compression, bits) = match pixels {
+            PixelFormat::Mjpeg => (*b"MJPG", 24),
+            PixelFormat::Yuyv => (*b"YUY2", 16),
+            PixelFormat::Nv12 => (*b"NV12", 12),
+            PixelFormat::Rgb24 => ([0; 4], 24),
+        };
+        let frames = self.frames as u32;
+
+        let avih = words(&[
+            1_000_000 / self.fps,
+            0,
+            0,
+            AVIF_HASINDEX | AVIF_ISINTERLEAVED,
+            frames,
+            0,
+            2,
+            self.max_chunk,
+            width,
+            height,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        let vids = stream_header(StreamHeader {
+            kind: *b"vids",
+            handler: compression,
+            scale: 1,
+            rate: self.fps,
+            length: frames,
+            buffer_size: self.max_chunk,
+            sample_size: 0,
+            rect: [width, height],
+        });
+        let mut bitmapinfo = words(&[40, width, height]);
+        bitmapinfo.extend(1u16.to_le_bytes());
+        bitmapinfo.extend((bits as u16).to_le_bytes());
+        bitmapinfo.extend(compression);
+        bitmapinfo.extend(words(&[0, 0, 0, 0, 0]));
+
+        let auds = stream_header(StreamHeader {
+            kind: *b"auds",
+            handler: [0; 4],
+            scale: AUDIO_BLOCK,
+            rate: AUDIO_RATE * AUDIO_BLOCK,
+            length: (self.audio_bytes / AUDIO_BLOCK as u64) as u32,
+            buffer_size: self.max_chunk,
+            sample_size: AUDIO_BLOCK,
+            rect: [0, 0],
+        });
+        let mut waveformat = 1u16.to_le_bytes().to_vec();
+        waveformat.extend((AUDIO_CHANNELS as u16).to_le_bytes());
+        waveformat.extend(words(&[AUDIO_RATE, AUDIO_RATE * AUDIO_BLOCK]));
+        waveformat.extend((AUDIO_BLOCK as u16).to_le_bytes());
+        waveformat.extend(16u16.to_le_bytes());
+
+        let mut hdrl = b"hdrl".to_vec();
+        chunk(&mut hdrl, b"avih", &avih);
+        let mut strl = b"strl".to_vec();
+        chunk(&mut strl, b"strh", &vids);
+        chunk(&mut strl, b"strf", &bitmapinfo);
+        chunk(&mut hdrl, b"LIST", &strl);
+        let mut strl = b"strl".to_vec();
+        chunk(&mut strl, b"strh", &auds);
+        chunk(&mut strl, b"strf", &waveformat);
+        chunk(&mut hdrl, b"LIST", &strl);
+
+        let mut out = b"RIFF".to_vec();
+        out.extend(riff_size.to_le_bytes());
+        out.extend(b"AVI ");
+        chunk(&mut out, b"LIST", &hdrl);
+        out.extend(b"LIST");
+        out.extend(movi_size.to_le_bytes());
+        out.extend(b"movi");
+        out
+    }
+}
+
+struct StreamHeader {
+    kind: [u8; 4],
+    handler: [u8; 4],
+    scale: u32,
+    rate: u32,
+    length: u32,
+    buffer_size: u32,
+    sample_size: u32,
+    rect: [u32; 2],
+}
+
+fn stream_header(h: StreamHeader) -> Vec<u8> {
+    let mut out = h.kind.to_vec();
+    out.extend(h.handler);
+    // flags, priority + language, initial frames
+    out.extend(words(&[0, 0, 0]));
+    out.extend(words(&[h.scale, h.rate, 0, h.length, h.buffer_size]));
+    // quality, -1 is "default"
+    out.extend(u32::MAX.to_le_bytes());
+    out.extend(h.sample_size.to_le_bytes());
+    for x in [0, 0, h.rect[0], h.rect[1]] {
+        out.extend((x as u16).to_le_bytes());
+    }
+    out
+}
+
+fn words(xs: &[u32]) -> Vec<u8> {
+    xs.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend(id);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if !data.len().is_multiple_of(2) {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: &[u8], captured: Instant) -> Frame<'_> {
+        Frame {
+            width: 2,
+            height: 2,
+            format: PixelFormat::Rgb24,
+            data,
+            captured,
+        }
+    }
+
+    #[test]
+    fn records_on_its_own_thread() {
+        let dir = std::env::temp_dir().join(format!("ccdisplay-test-{}", std::process::id()));
+        let (rec, audio_rx) = Recorder::new();
+        let t0 = Instant::now();
+        rec.video_frame(&frame(&[0; 12], t0));
+        assert!(
+            rec.tx.is_empty(),
+            "nothing should get queued while it's off"
+        );
+
+        rec.start(&dir, 10).unwrap();
+        assert_eq!(audio_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+        for i in 0..3 {
+            rec.audio_samples(&[0; 16]);
+            rec.video_frame(&frame(&[i; 12], t0 + Duration::from_millis(100 * i as u64)));
+        }
+        rec.stop();
+        assert_eq!(rec.elapsed(), None);
+        assert_eq!(audio_rx.recv_timeout(Duration::from_secs(5)), Ok(false));
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let avi = std::fs::read(files[0].as_ref().unwrap().path()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&avi[..4], b"RIFF");
+        // avih's total frames
+        assert_eq!(avi[48..52], 3u32.to_le_bytes());
+        assert!(avi.windows(4).any(|w| w == b"01wb"));
+        assert!(avi.windows(4).any(|w| w == b"idx1"));
+    }
+}
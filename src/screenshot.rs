@@ -0,0 +1,72 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+
+/// lets the ui ask the video thread for a full resolution copy of the next frame
+#[derive(Clone, Default)]
+pub(crate) struct FrameGrab(Arc<Mutex<Option<flume::Sender<egui::ColorImage>>>>);
+
+impl FrameGrab {
+    pub fn request(&self) -> flume::Receiver<egui::ColorImage> {
+        let (tx, rx) = flume::bounded(1);
+        *self.0.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// where to send the next frame, if anyone wants it
+    pub fn take(&self) -> Option<flume::Sender<egui::ColorImage>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// grab the next frame and write it out as a png in `dir`, reporting how it
+/// went to `toast_tx`
+pub(crate) fn take(
+    grab: &FrameGrab,
+    dir: PathBuf,
+    toast_tx: flume::Sender<String>,
+    ctx: egui::Context,
+) {
+    let rx = grab.request();
+    std::thread::spawn(move || {
+        let msg = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(image) => match save(&image, &dir) {
+                Ok(path) => format!("Saved {}", path.display()),
+                Err(e) => {
+                    eprintln!("couldn't save screenshot: {e:#}");
+                    format!("Couldn't save screenshot: {e}")
+                }
+            },
+            Err(_) => "No frame to take a screenshot of".to_owned(),
+        };
+        let _ = toast_tx.send(msg);
+        ctx.request_repaint();
+    });
+}
+
+fn save(image: &egui::ColorImage, dir: &Path) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("couldn't create {}", dir.display()))?;
+    let name = format!(
+        "ccdisplay-{}.png",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S%.3f")
+    );
+    let path = dir.join(name);
+    let file = BufWriter::new(File::create(&path)?);
+    let [width, height] = image.size;
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let rgb = image
+        .pixels
+        .iter()
+        .flat_map(|c| [c.r(), c.g(), c.b()])
+        .collect::<Vec<_>>();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&rgb)?;
+    writer.finish()?;
+    Ok(path)
+}
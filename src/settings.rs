@@ -1,53 +1,360 @@
 use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::DeviceId;
+use crate::audio::{
+    self, AudioBackend, AudioConfig, AudioMode, AudioSource, LoopbackOptions, UsbMatch, Volume,
+};
+use crate::config::{self, Config, ExtraSource};
+use crate::convert::{Colorimetry, YuvMatrix, YuvRange};
+use crate::extra::{self, Extras, Layout};
+use crate::format::{self, Criterion, FormatChoice, ModeList};
+use crate::pa::{self, PaRuntime};
+use crate::record::{Recorder, ReplayLimits};
+use crate::video::{self, RenderOptions, UvcDevice};
+use crate::{cli, testpattern, v4l2, DeviceId, VideoConfig, VideoDevice};
 
 pub(crate) struct Settings {
+    /// empty for the default profile
+    pub profile: String,
     window_title: String,
     pub devid: DeviceId,
     vidname: String,
+    v4l2path: Option<PathBuf>,
+    testpattern: Option<testpattern::Params>,
+    format: FormatChoice,
+    gpu_yuv: bool,
+    colorimetry: Colorimetry,
+    /// empty to follow the video device
     pub audname: String,
+    /// empty for the default sink
+    audio_sink: String,
+    pub audio_backend: AudioBackend,
+    audio_mode: AudioMode,
+    audio_latency: u32,
+    adjust_time: u32,
+    channel_map: String,
+    audio_rate: Option<u32>,
+    pub volume: Volume,
+    /// ms, positive holds the audio back and negative holds the video back
+    av_offset: i32,
+    pub record_dir: PathBuf,
+    pub screenshot_dir: PathBuf,
+    replay_enabled: bool,
+    replay: ReplayLimits,
+    pub latency_csv: bool,
+    layout: Layout,
+    extra: Vec<ExtraSource>,
 }
 impl Settings {
-    pub fn from_storage(storage: &dyn eframe::Storage) -> Self {
-        let parse = |s: &str| (!s.is_empty()).then(|| s.parse::<u16>()).transpose();
-        let devid = || {
-            let (product_id, vendor_id) = (
-                storage.get_string("ccdisplay.pid").unwrap_or_default(),
-                storage.get_string("ccdisplay.vid").unwrap_or_default(),
-            );
-            Some(DeviceId {
-                product_id: parse(&product_id).ok()?,
-                vendor_id: parse(&vendor_id).ok()?,
-            })
+    pub fn from_config(config: &Config) -> Self {
+        Self::load(config, &config.profile)
+    }
+    pub fn load(config: &Config, profile: &str) -> Self {
+        let p = config.get_profile(profile);
+        let (general, loopback, replay) = (&config.general, &config.loopback, &config.replay);
+        let mut settings = Self {
+            profile: profile.to_owned(),
+            window_title: p.title.clone(),
+            devid: DeviceId::default(),
+            vidname: p.video_name.clone(),
+            v4l2path: None,
+            testpattern: None,
+            format: p.format.clone(),
+            gpu_yuv: p.gpu_yuv,
+            colorimetry: Colorimetry {
+                matrix: p.yuv_matrix,
+                range: p.yuv_range,
+            },
+            audname: p.audio_source.clone(),
+            audio_sink: p.audio_sink.clone(),
+            audio_backend: general.audio_backend,
+            audio_mode: p.audio_mode,
+            audio_latency: p.audio_latency,
+            adjust_time: loopback.adjust_time,
+            channel_map: loopback.channel_map.clone(),
+            audio_rate: loopback.rate,
+            volume: Volume {
+                percent: general.volume,
+                muted: general.muted,
+            },
+            av_offset: 0,
+            record_dir: general.record_dir.clone(),
+            screenshot_dir: general.screenshot_dir.clone(),
+            replay_enabled: replay.enabled,
+            replay: ReplayLimits {
+                secs: replay.secs,
+                max_mb: replay.max_mb,
+            },
+            latency_csv: general.latency_csv,
+            layout: p.layout,
+            extra: p.extra.clone(),
         };
-        Self {
-            window_title: storage
-                .get_string("ccdisplay.windowtitle")
-                .unwrap_or_else(|| "CCDisplay".to_owned()),
-            devid: devid().unwrap_or_default(),
-            vidname: storage.get_string("ccdisplay.vidname").unwrap_or_default(),
-            audname: storage.get_string("ccdisplay.audname").unwrap_or_default(),
-        }
-    }
-    fn save(&self, storage: &mut dyn eframe::Storage) {
-        let s = |s: Option<_>| s.as_ref().map(ToString::to_string).unwrap_or_default();
-        storage.set_string("ccdisplay.windowtitle", self.window_title.clone());
-        storage.set_string("ccdisplay.pid", s(self.devid.product_id));
-        storage.set_string("ccdisplay.vid", s(self.devid.vendor_id));
-        storage.set_string("ccdisplay.vidname", self.vidname.clone());
-        storage.set_string("ccdisplay.audname", self.audname.clone());
+        settings.set_video_device(&p.video);
+        settings.load_av_offset(config);
+        settings
+    }
+    /// whatever was given on the command line, on top of what's stored
+    pub fn apply_args(&mut self, args: &cli::Args, config: &Config) {
+        match &args.profile {
+            Some(profile) if config.profiles.contains_key(profile) => {
+                *self = Settings::load(config, profile);
+            }
+            Some(profile) => eprintln!("no profile called {profile:?}, sticking with the last one"),
+            None => {}
+        }
+        if let Some(title) = &args.title {
+            self.window_title = title.clone();
+        }
+        if let Some(dev) = &args.video {
+            self.set_video_device(dev);
+            self.vidname.clear();
+            // a different device has its own offset
+            self.load_av_offset(config);
+        }
+        if let Some(audio) = &args.audio {
+            self.audname = match &**audio {
+                "auto" => String::new(),
+                name => name.to_owned(),
+            };
+        }
+        if let Some(format) = &args.format {
+            self.format = format.clone();
+        }
+    }
+    /// what'd stop these from being saved, if anything
+    fn problem(&self) -> Option<String> {
+        let map = self.channel_map.trim();
+        if !map.is_empty() {
+            if let Err(e) = audio::check_channel_map(map) {
+                return Some(format!("Channel map: {e}"));
+            }
+        }
+        None
+    }
+    pub fn replay_limits(&self) -> Option<ReplayLimits> {
+        self.replay_enabled.then(|| self.replay)
+    }
+    pub fn audio_config(&self) -> AudioConfig {
+        AudioConfig {
+            source: match &*self.audname {
+                "" => AudioSource::SameDevice(self.usb_match()),
+                name => AudioSource::Named(name.to_owned()),
+            },
+            sink: (!self.audio_sink.is_empty()).then(|| self.audio_sink.clone()),
+            mode: self.audio_mode,
+            latency_ms: self.audio_latency,
+            delay_ms: self.av_offset.max(0) as u32,
+            loopback: LoopbackOptions {
+                adjust_time: self.adjust_time,
+                channel_map: (!self.channel_map.trim().is_empty())
+                    .then(|| self.channel_map.trim().to_owned()),
+                rate: self.audio_rate,
+            },
+        }
+    }
+    pub fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            gpu_yuv: self.gpu_yuv,
+            colorimetry: self.colorimetry,
+            video_delay: Duration::from_millis(self.av_offset.min(0).unsigned_abs().into()),
+        }
+    }
+    pub fn video_config(&self) -> VideoConfig {
+        VideoConfig {
+            dev: self.video_device(),
+            format: self.format.clone(),
+        }
+    }
+    /// what the extra sources should be doing. they get the main source's
+    /// audio and display settings, but no a/v offset, that's per device
+    pub fn extras(&self) -> Extras {
+        let sources = self.extra.iter().map(|src| extra::Spec {
+            name: match &*src.video_name {
+                "" => src.video.to_string(),
+                name => name.to_owned(),
+            },
+            video: VideoConfig {
+                dev: src.video.clone(),
+                format: src.format.clone(),
+            },
+            audio: AudioConfig {
+                source: match &*src.audio_source {
+                    "" => AudioSource::SameDevice(usb_match(&src.video)),
+                    name => AudioSource::Named(name.to_owned()),
+                },
+                delay_ms: 0,
+                ..self.audio_config()
+            },
+        });
+        Extras {
+            layout: self.layout,
+            sources: sources.collect(),
+            render: RenderOptions {
+                video_delay: Duration::ZERO,
+                ..self.render_options()
+            },
+        }
+    }
+    fn usb_match(&self) -> UsbMatch {
+        usb_match(&self.video_device())
+    }
+    /// whether `plugged` is the video device these settings pick. uvc ids get
+    /// the whole id compared, serial included, v4l2 paths only what sysfs says
+    fn uses_device(&self, plugged: &DeviceId) -> bool {
+        let usb = match self.video_device() {
+            VideoDevice::Uvc(id) => return id.vendor_id.is_some() && id.matches(plugged),
+            dev => usb_match(&dev),
+        };
+        let ports = plugged.port_path.as_deref().and_then(|p| p.split_once('-'));
+        usb.vendor_id.is_some()
+            && usb.vendor_id == plugged.vendor_id
+            && usb
+                .product_id
+                .map_or(true, |id| Some(id) == plugged.product_id)
+            && usb
+                .port_path
+                .map_or(true, |port| Some(&*port) == ports.map(|p| p.1))
+    }
+    fn video_device(&self) -> VideoDevice {
+        match (self.testpattern, &self.v4l2path) {
+            (Some(params), _) => VideoDevice::TestPattern(params),
+            (None, Some(path)) => VideoDevice::V4l2(path.clone()),
+            (None, None) => VideoDevice::Uvc(self.devid.clone()),
+        }
+    }
+    fn set_video_device(&mut self, dev: &VideoDevice) {
+        self.testpattern = None;
+        self.v4l2path = None;
+        match dev {
+            VideoDevice::Uvc(id) => self.devid = id.clone(),
+            VideoDevice::V4l2(path) => self.v4l2path = Some(path.clone()),
+            VideoDevice::TestPattern(params) => self.testpattern = Some(*params),
+        }
+    }
+    fn save(&self, config: &mut Config) {
+        config.profile = self.profile.clone();
+        let profile = config::Profile {
+            title: self.window_title.clone(),
+            video: self.video_device(),
+            video_name: self.vidname.clone(),
+            format: self.format.clone(),
+            gpu_yuv: self.gpu_yuv,
+            yuv_matrix: self.colorimetry.matrix,
+            yuv_range: self.colorimetry.range,
+            audio_source: self.audname.clone(),
+            audio_sink: self.audio_sink.clone(),
+            audio_mode: self.audio_mode,
+            audio_latency: self.audio_latency,
+            layout: self.layout,
+            extra: self.extra.clone(),
+        };
+        match &*self.profile {
+            "" => config.default = profile,
+            name => {
+                config.profiles.insert(name.to_owned(), profile);
+            }
+        }
+        config.general = config::General {
+            audio_backend: self.audio_backend,
+            volume: self.volume.percent,
+            muted: self.volume.muted,
+            record_dir: self.record_dir.clone(),
+            screenshot_dir: self.screenshot_dir.clone(),
+            latency_csv: self.latency_csv,
+        };
+        config.loopback = config::Loopback {
+            adjust_time: self.adjust_time,
+            channel_map: self.channel_map.clone(),
+            rate: self.audio_rate,
+        };
+        config.replay = config::Replay {
+            enabled: self.replay_enabled,
+            secs: self.replay.secs,
+            max_mb: self.replay.max_mb,
+        };
+        self.save_av_offset(config);
     }
 }
 
+impl Settings {
+    // separate so the hotkeys can save it without saving whatever's half
+    // edited in the window
+    fn save_volume(&self, config: &mut Config) {
+        config.general.volume = self.volume.percent;
+        config.general.muted = self.volume.muted;
+    }
+    fn av_offset_key(&self) -> String {
+        config::av_offset_key(&self.video_device())
+    }
+    fn load_av_offset(&mut self, config: &Config) {
+        self.av_offset = config
+            .av_offsets
+            .get(&self.av_offset_key())
+            .copied()
+            .unwrap_or(0);
+    }
+    fn save_av_offset(&self, config: &mut Config) {
+        let key = self.av_offset_key();
+        if self.av_offset == 0 {
+            config.av_offsets.remove(&key);
+        } else {
+            config.av_offsets.insert(key, self.av_offset);
+        }
+    }
+}
+
+fn usb_match(dev: &VideoDevice) -> UsbMatch {
+    match dev {
+        VideoDevice::Uvc(id) => UsbMatch {
+            vendor_id: id.vendor_id,
+            product_id: id.product_id,
+            // that one doesn't have the bus
+            port_path: id
+                .port_path
+                .as_deref()
+                .and_then(|path| Some(path.split_once('-')?.1.to_owned())),
+        },
+        VideoDevice::V4l2(path) => v4l2::usb_match(path),
+        VideoDevice::TestPattern(_) => UsbMatch::default(),
+    }
+}
+
+// past this the video delay queue starts eating a lot of memory
+pub(crate) const MAX_AV_OFFSET: i32 = 1000;
+
 pub(crate) struct SettingsWindow {
     pub open: bool,
-    devid_tx: flume::Sender<DeviceId>,
-    audname_tx: flume::Sender<String>,
+    config: Config,
+    tx: Senders,
+    render_opts: Arc<Mutex<RenderOptions>>,
+    modes: ModeList,
+    recorder: Recorder,
     settings: Settings,
     first_render: bool,
-    vid_list: Option<(Vec<uvc::DeviceDescription>, usize)>,
+    vid_list: Option<(Vec<VideoChoice>, usize)>,
+    testpattern_text: String,
+    record_dir_text: String,
+    screenshot_dir_text: String,
     audio_list: Option<(Vec<AudioDescr>, usize)>,
+    sink_list: Option<(Vec<AudioDescr>, usize)>,
+    new_profile_text: String,
+    /// why the last Save didn't
+    save_error: Option<String>,
+    /// the extra sources need redoing, see `extras_changed`
+    extras_changed: bool,
+}
+/// where changes go to take effect
+pub(crate) struct Senders {
+    pub video: flume::Sender<VideoConfig>,
+    pub audio: flume::Sender<AudioConfig>,
+    pub volume: flume::Sender<Volume>,
+}
+enum VideoChoice {
+    Uvc(UvcDevice),
+    V4l2(v4l2::Node),
+    TestPattern,
 }
 #[derive(Debug)]
 struct AudioDescr {
@@ -55,29 +362,153 @@ struct AudioDescr {
     desc: Option<String>,
 }
 
-impl DeviceId {
-    fn matches_device(&self, desc: &uvc::DeviceDescription) -> bool {
-        self.vendor_id.map_or(true, |id| desc.vendor_id == id)
-            && self.product_id.map_or(true, |id| desc.product_id == id)
+impl VideoChoice {
+    /// whether this is the choice that gets you `dev`
+    fn is(&self, dev: &VideoDevice) -> bool {
+        match (self, dev) {
+            (VideoChoice::Uvc(choice), VideoDevice::Uvc(devid)) => devid.matches(&choice.id),
+            (VideoChoice::V4l2(node), VideoDevice::V4l2(path)) => node.path == *path,
+            (VideoChoice::TestPattern, VideoDevice::TestPattern(_)) => true,
+            _ => false,
+        }
+    }
+    /// the device to remember for this choice. `list` is what it was picked
+    /// from, `params` in case it's the test pattern
+    fn device(&self, list: &[VideoChoice], params: testpattern::Params) -> VideoDevice {
+        match self {
+            VideoChoice::Uvc(dev) => VideoDevice::Uvc(distinct_id(dev, list)),
+            VideoChoice::V4l2(node) => VideoDevice::V4l2(node.path.clone()),
+            VideoChoice::TestPattern => VideoDevice::TestPattern(params),
+        }
+    }
+}
+
+/// just enough of `dev`'s id to tell it apart from the identical cards in
+/// `list`. a card that's the only one of its kind then still gets found after
+/// moving it to another port
+fn distinct_id(dev: &UvcDevice, list: &[VideoChoice]) -> DeviceId {
+    let mut id = DeviceId {
+        vendor_id: dev.id.vendor_id,
+        product_id: dev.id.product_id,
+        ..Default::default()
+    };
+    let twins = list
+        .iter()
+        .filter_map(|choice| match choice {
+            VideoChoice::Uvc(other) if id.matches(&other.id) => Some(&other.id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if twins.len() > 1 {
+        // cheap cards tend to all have the same serial, if any
+        let same_serial = twins.iter().filter(|twin| twin.serial == dev.id.serial);
+        if dev.id.serial.is_some() && same_serial.count() == 1 {
+            id.serial = dev.id.serial.clone();
+        } else {
+            id.port_path = dev.id.port_path.clone();
+        }
     }
+    id
 }
 
 impl SettingsWindow {
     pub fn new(
+        config: Config,
         settings: Settings,
-        devid_tx: flume::Sender<DeviceId>,
-        audname_tx: flume::Sender<String>,
+        tx: Senders,
+        render_opts: Arc<Mutex<RenderOptions>>,
+        modes: ModeList,
+        recorder: Recorder,
     ) -> Self {
         Self {
             open: false,
-            devid_tx,
-            audname_tx,
+            config,
+            tx,
+            render_opts,
+            modes,
+            recorder,
+            testpattern_text: settings.testpattern.unwrap_or_default().to_string(),
+            record_dir_text: settings.record_dir.display().to_string(),
+            screenshot_dir_text: settings.screenshot_dir.display().to_string(),
             settings,
             first_render: true,
             vid_list: None,
             audio_list: None,
+            sink_list: None,
+            new_profile_text: String::new(),
+            save_error: None,
+            // so they get started at all
+            extras_changed: true,
         }
     }
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+    /// what the extra sources should be doing now, if that's changed since
+    /// last time. they're run from outside since they need drawing
+    pub fn extras_changed(&mut self) -> Option<Extras> {
+        mem::take(&mut self.extras_changed).then(|| self.settings.extras())
+    }
+    /// move the a/v offset by `ms` and apply it right away
+    pub fn nudge_av_offset(&mut self, ms: i32) -> i32 {
+        let settings = &mut self.settings;
+        settings.av_offset = (settings.av_offset + ms).clamp(-MAX_AV_OFFSET, MAX_AV_OFFSET);
+        self.render_opts.lock().unwrap().video_delay = settings.render_options().video_delay;
+        let _ = self.tx.audio.try_send(settings.audio_config());
+        settings.save_av_offset(&mut self.config);
+        self.config.save();
+        settings.av_offset
+    }
+    /// change the volume right away and remember it
+    pub fn set_volume(&mut self, f: impl FnOnce(&mut Volume)) -> Volume {
+        let volume = &mut self.settings.volume;
+        f(volume);
+        volume.percent = volume.percent.min(Volume::MAX_PERCENT);
+        let _ = self.tx.volume.try_send(*volume);
+        self.settings.save_volume(&mut self.config);
+        self.config.save();
+        self.settings.volume
+    }
+    /// load a profile ("" for the default one) and start using it right away
+    pub fn switch_profile(&mut self, frame: &mut eframe::Frame, name: &str) {
+        self.config.profile = name.to_owned();
+        self.config.save();
+        self.settings = Settings::load(&self.config, name);
+        let settings = &self.settings;
+        frame.set_window_title(&settings.window_title);
+        let _ = self.tx.video.try_send(settings.video_config());
+        let _ = self.tx.audio.try_send(settings.audio_config());
+        *self.render_opts.lock().unwrap() = settings.render_options();
+        self.extras_changed = true;
+        self.testpattern_text = settings.testpattern.unwrap_or_default().to_string();
+        // the lists have the old profile's picks selected
+        self.vid_list = None;
+        self.audio_list = None;
+        self.sink_list = None;
+    }
+    /// switch to the next profile, default included. gives back its name, or
+    /// None if there aren't any profiles to switch between
+    pub fn cycle_profile(&mut self, frame: &mut eframe::Frame) -> Option<String> {
+        if self.config.profiles.is_empty() {
+            return None;
+        }
+        let names = self.config.profile_names().collect::<Vec<_>>();
+        let i = names.iter().position(|name| *name == self.settings.profile);
+        let next = names[i.map_or(0, |i| (i + 1) % names.len())].to_owned();
+        self.switch_profile(frame, &next);
+        Some(next)
+    }
+    /// the profile for a usb device that just got plugged in, unless the one
+    /// we're on already uses it
+    pub fn profile_for_device(&self, plugged: &DeviceId) -> Option<String> {
+        if self.settings.uses_device(plugged) {
+            return None;
+        }
+        self.config
+            .profile_names()
+            .find(|name| Settings::load(&self.config, name).uses_device(plugged))
+            .map(str::to_owned)
+    }
     pub fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         if mem::take(&mut self.first_render) {
             frame.set_window_title(&self.settings.window_title);
@@ -92,93 +523,422 @@ impl SettingsWindow {
             self.open = false;
         }
         let mut close = false;
+        let mut new_volume = None;
+        // switching swaps out self.settings, so it has to wait for the window
+        let mut switch_to = None;
+        let mut delete_profile = false;
         egui::Window::new("Settings")
             .open(&mut self.open)
             .collapsible(false)
             .auto_sized()
             .show(ctx, |ui| {
                 let settings = &mut self.settings;
+                ui.horizontal(|ui| {
+                    ui.label("Profile");
+                    let name = |name: &str| match name {
+                        "" => "Default".to_owned(),
+                        name => name.to_owned(),
+                    };
+                    egui::ComboBox::from_id_source("profile")
+                        .selected_text(name(&settings.profile))
+                        .show_ui(ui, |ui| {
+                            for profile in self.config.profile_names() {
+                                let current = profile == settings.profile;
+                                if ui.selectable_label(current, name(profile)).clicked() && !current
+                                {
+                                    switch_to = Some(profile.to_owned());
+                                }
+                            }
+                        });
+                    if !settings.profile.is_empty() && ui.button("Delete").clicked() {
+                        delete_profile = true;
+                    }
+                });
                 ui.horizontal(|ui| {
                     ui.label("Window title");
                     ui.text_edit_singleline(&mut settings.window_title);
                 });
                 let (vidlist, v_i) = self.vid_list.get_or_insert_with(|| {
-                    let list = uvc::Context::new()
-                        .unwrap()
-                        .devices()
-                        .unwrap()
-                        .map(|dev| dev.description().unwrap())
-                        .collect::<Vec<_>>();
+                    let mut list = match video::uvc_devices() {
+                        Ok(devs) => devs.into_iter().map(VideoChoice::Uvc).collect(),
+                        Err(e) => {
+                            eprintln!("couldn't list uvc devices: {e}");
+                            vec![]
+                        }
+                    };
+                    list.extend(v4l2::list_devices().into_iter().map(VideoChoice::V4l2));
+                    list.push(VideoChoice::TestPattern);
+                    let current = settings.video_device();
                     let i = list
                         .iter()
-                        .position(|desc| settings.devid.matches_device(desc))
+                        .position(|choice| choice.is(&current))
                         .unwrap_or(usize::MAX);
                     (list, i)
                 });
-                let vidname = |x: &uvc::DeviceDescription| {
-                    format!(
-                        "{} {}",
-                        x.manufacturer.as_deref().unwrap_or(""),
-                        x.product.as_deref().unwrap_or("")
-                    )
+                let vidlist: &[VideoChoice] = vidlist;
+                let vidname = |x: &VideoChoice| match x {
+                    VideoChoice::Uvc(x) => {
+                        let name = format!(
+                            "{} {}",
+                            x.desc.manufacturer.as_deref().unwrap_or(""),
+                            x.desc.product.as_deref().unwrap_or("")
+                        );
+                        let id = distinct_id(x, vidlist);
+                        match (id.serial, id.port_path) {
+                            (Some(serial), _) => format!("{name} (serial {serial})"),
+                            (None, Some(port)) => format!("{name} (port {port})"),
+                            (None, None) => name,
+                        }
+                    }
+                    VideoChoice::V4l2(node) => {
+                        format!("{} (v4l2 {})", node.name, node.path.display())
+                    }
+                    VideoChoice::TestPattern => "Test pattern".to_owned(),
                 };
                 source_dropdown(ui, "Video source", vidlist, v_i, &settings.vidname, vidname);
-                let (audlist, a_i) = self.audio_list.get_or_insert_with(|| {
-                    let list = {
-                        let rt = super::audio::PaRuntime::new();
-                        let mut ctx = rt.make_context("getlist");
-                        rt.run(async move {
-                            super::audio::connect(&mut ctx).await.unwrap();
-                            super::audio::get_source_info_list(&ctx.introspect(), |info| {
+                let testpattern = vidlist
+                    .get(*v_i)
+                    .filter(|x| matches!(x, VideoChoice::TestPattern))
+                    .map(|_| {
+                        ui.horizontal(|ui| {
+                            ui.label("Test pattern mode");
+                            let parsed = self.testpattern_text.parse::<testpattern::Params>();
+                            if parsed.is_err() {
+                                ui.style_mut().visuals.override_text_color =
+                                    Some(egui::Color32::RED)
+                            }
+                            ui.text_edit_singleline(&mut self.testpattern_text);
+                            parsed
+                        })
+                        .inner
+                    });
+                if self.audio_list.is_none() || self.sink_list.is_none() {
+                    let rt = PaRuntime::new();
+                    let ctx = rt.make_context("getlist");
+                    let handle = rt.handle();
+                    let lists = rt.run(async move {
+                        ctx.connect().await?;
+                        let sources = handle.spawn({
+                            let ctx = ctx.clone();
+                            async move {
+                                ctx.source_info_list(|info| {
+                                    let name = info.name.as_deref()?;
+                                    Some(AudioDescr {
+                                        name: name.to_owned(),
+                                        desc: info.description.as_deref().map(str::to_owned),
+                                    })
+                                })
+                                .await
+                            }
+                        });
+                        let sinks = ctx
+                            .sink_info_list(|info| {
                                 let name = info.name.as_deref()?;
                                 Some(AudioDescr {
                                     name: name.to_owned(),
                                     desc: info.description.as_deref().map(str::to_owned),
                                 })
                             })
-                            .await
-                        })
+                            .await?;
+                        Ok::<_, pa::Error>((sources.join().await??, sinks))
+                    });
+                    let (mut sources, mut sinks) = lists.unwrap_or_else(|e| {
+                        eprintln!("couldn't list audio devices: {e}");
+                        Default::default()
+                    });
+                    sources.insert(
+                        0,
+                        AudioDescr {
+                            name: String::new(),
+                            desc: Some("Auto (same device)".to_owned()),
+                        },
+                    );
+                    sinks.insert(
+                        0,
+                        AudioDescr {
+                            name: String::new(),
+                            desc: Some("Default".to_owned()),
+                        },
+                    );
+                    let position = |list: &[AudioDescr], name: &str| {
+                        list.iter()
+                            .position(|desc| desc.name == name)
+                            .unwrap_or(usize::MAX)
                     };
-                    let i = list
-                        .iter()
-                        .position(|desc| desc.name == settings.audname)
-                        .unwrap_or(usize::MAX);
-                    (list, i)
+                    let a_i = position(&sources, &settings.audname);
+                    let s_i = position(&sinks, &settings.audio_sink);
+                    self.audio_list = Some((sources, a_i));
+                    self.sink_list = Some((sinks, s_i));
+                }
+                let (audlist, a_i) = self.audio_list.as_mut().unwrap();
+                let (sinklist, s_i) = self.sink_list.as_mut().unwrap();
+                let descr = |x: &AudioDescr| x.desc.clone().unwrap_or_else(|| x.name.clone());
+                source_dropdown(ui, "Audio source", audlist, a_i, &settings.audname, descr);
+                source_dropdown(ui, "Play it on", sinklist, s_i, &settings.audio_sink, descr);
+                ui.horizontal(|ui| {
+                    ui.label("Audio backend");
+                    let backend = &mut settings.audio_backend;
+                    egui::ComboBox::from_id_source("audiobackend")
+                        .selected_text(format!("{backend:?}"))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(backend, AudioBackend::Pulse, "Pulse");
+                            ui.selectable_value(backend, AudioBackend::PipeWire, "PipeWire");
+                        });
+                    ui.label("(needs a restart)");
                 });
-                source_dropdown(ui, "Audio source", audlist, a_i, &settings.audname, |x| {
-                    x.desc.clone().unwrap_or_else(|| x.name.clone())
+                if settings.audio_backend == AudioBackend::PipeWire {
+                    ui.label(
+                        "PipeWire can't record audio, change the volume or show meters yet, \
+                        use Pulse for those",
+                    );
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Play audio through");
+                    let mode = &mut settings.audio_mode;
+                    egui::ComboBox::from_id_source("audiomode")
+                        .selected_text(match mode {
+                            AudioMode::Loopback => "module-loopback",
+                            AudioMode::Passthrough => "ccdisplay",
+                        })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(mode, AudioMode::Loopback, "module-loopback");
+                            ui.selectable_value(mode, AudioMode::Passthrough, "ccdisplay");
+                        });
+                    ui.label("Latency");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.audio_latency)
+                            .clamp_range(10..=2000)
+                            .suffix(" ms"),
+                    );
                 });
-                // let product_id = settings.product_id.show(ui, "Product ID");
-                // let vendor_id = settings.vendor_id.show(ui, "Vendor ID");
-                // ui.horizontal(|ui| {
-                //     ui.label("Serial number");
-                //     ui.text_edit_singleline(&mut settings.serial_number);
-                // });
-                if ui.button("Save").clicked() {
-                    if *v_i != usize::MAX {
-                        let dev = &vidlist[*v_i];
-                        settings.vidname = vidname(dev);
-                        settings.devid = DeviceId {
-                            vendor_id: Some(dev.vendor_id),
-                            product_id: Some(dev.product_id),
+                ui.horizontal(|ui| {
+                    // this one takes effect right away, no need to hit save
+                    let mut volume = settings.volume;
+                    ui.label("Volume");
+                    let slider = ui.add(
+                        egui::Slider::new(&mut volume.percent, 0..=Volume::MAX_PERCENT).suffix("%"),
+                    );
+                    let mute = ui.checkbox(&mut volume.muted, "Mute");
+                    if slider.changed() || mute.changed() {
+                        new_volume = Some(volume);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("A/V offset");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.av_offset)
+                            .clamp_range(-MAX_AV_OFFSET..=MAX_AV_OFFSET)
+                            .suffix(" ms"),
+                    );
+                    ui.label("(+ holds the audio back, - holds the video back)");
+                });
+                if settings.audio_mode == AudioMode::Loopback {
+                    ui.horizontal(|ui| {
+                        ui.label("Adjust rate every");
+                        ui.add(
+                            egui::DragValue::new(&mut settings.adjust_time)
+                                .clamp_range(0..=60)
+                                .suffix(" s"),
+                        );
+                        ui.label("(0 = never)");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Channel map");
+                        let map = settings.channel_map.trim();
+                        if !map.is_empty() && audio::check_channel_map(map).is_err() {
+                            ui.style_mut().visuals.override_text_color = Some(egui::Color32::RED)
+                        }
+                        ui.add(
+                            egui::TextEdit::singleline(&mut settings.channel_map)
+                                .hint_text("same as source"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Sample rate");
+                        let rate = &mut settings.audio_rate;
+                        egui::ComboBox::from_id_source("audiorate")
+                            .selected_text(match rate {
+                                Some(rate) => format!("{rate} Hz"),
+                                None => "Same as source".to_owned(),
+                            })
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(rate, None, "Same as source");
+                                for hz in [44100, 48000, 96000] {
+                                    ui.selectable_value(rate, Some(hz), format!("{hz} Hz"));
+                                }
+                            });
+                    });
+                }
+                format_picker(ui, &mut settings.format, &self.modes);
+                ui.checkbox(&mut settings.gpu_yuv, "Convert YUV on the GPU");
+                ui.horizontal(|ui| {
+                    ui.label("YUV matrix");
+                    let matrix = &mut settings.colorimetry.matrix;
+                    egui::ComboBox::from_id_source("yuvmatrix")
+                        .selected_text(format!("{matrix:?}"))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(matrix, YuvMatrix::Bt601, "Bt601");
+                            ui.selectable_value(matrix, YuvMatrix::Bt709, "Bt709");
+                        });
+                    ui.label("Range");
+                    let range = &mut settings.colorimetry.range;
+                    egui::ComboBox::from_id_source("yuvrange")
+                        .selected_text(format!("{range:?}"))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(range, YuvRange::Limited, "Limited");
+                            ui.selectable_value(range, YuvRange::Full, "Full");
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Show other sources as");
+                    let layout = &mut settings.layout;
+                    egui::ComboBox::from_id_source("layout")
+                        .selected_text(layout.label())
+                        .show_ui(ui, |ui| {
+                            for choice in Layout::ALL {
+                                ui.selectable_value(layout, choice, choice.label());
+                            }
+                        });
+                });
+                let mut remove = None;
+                for (i, src) in settings.extra.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Source {}", i + 2));
+                        let current = vidlist.iter().position(|choice| choice.is(&src.video));
+                        let name = match current {
+                            Some(c) => vidname(&vidlist[c]),
+                            None if src.video_name.is_empty() => src.video.to_string(),
+                            None => src.video_name.clone(),
                         };
-                        let _ = self.devid_tx.try_send(settings.devid.clone());
+                        egui::ComboBox::from_id_source(egui::Id::new("extra").with(i))
+                            .width(160.0)
+                            .selected_text(name)
+                            .show_ui(ui, |ui| {
+                                for (j, choice) in vidlist.iter().enumerate() {
+                                    let label = vidname(choice);
+                                    if ui
+                                        .selectable_label(current == Some(j), label.as_str())
+                                        .clicked()
+                                    {
+                                        src.video = choice.device(vidlist, Default::default());
+                                        src.video_name = label;
+                                    }
+                                }
+                            });
+                        if ui.button("🗙").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = remove {
+                    settings.extra.remove(i);
+                }
+                if ui.button("+ Source").clicked() {
+                    settings.extra.push(ExtraSource::default());
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Recordings folder");
+                    ui.text_edit_singleline(&mut self.record_dir_text);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Screenshots folder");
+                    ui.text_edit_singleline(&mut self.screenshot_dir_text);
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut settings.replay_enabled, "Instant replay, last");
+                    ui.add(egui::DragValue::new(&mut settings.replay.secs).clamp_range(1..=600));
+                    ui.label("seconds, up to");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.replay.max_mb)
+                            .clamp_range(16..=16384)
+                            .speed(16),
+                    );
+                    ui.label("MB");
+                });
+                ui.checkbox(
+                    &mut settings.latency_csv,
+                    "Log latency measurements to the recordings folder",
+                );
+                let (save, save_as) = ui
+                    .horizontal(|ui| {
+                        let save = ui.button("Save").clicked();
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.new_profile_text)
+                                .hint_text("profile name"),
+                        );
+                        (save, ui.button("Save as profile").clicked())
+                    })
+                    .inner;
+                if let Some(e) = &self.save_error {
+                    ui.colored_label(egui::Color32::RED, e);
+                }
+                let save_as = save_as && !self.new_profile_text.trim().is_empty();
+                if save || save_as {
+                    self.save_error = match &testpattern {
+                        Some(Err(e)) => Some(format!("Test pattern mode: {e}")),
+                        _ => settings.problem(),
+                    };
+                }
+                if (save || save_as) && self.save_error.is_none() {
+                    if save_as {
+                        settings.profile = self.new_profile_text.trim().to_owned();
+                        self.new_profile_text.clear();
                     }
+                    let old_offset_key = settings.av_offset_key();
+                    if *v_i != usize::MAX {
+                        let choice = &vidlist[*v_i];
+                        settings.vidname = vidname(choice);
+                        // bad text stopped the save, so this only defaults when unused
+                        let params = testpattern.and_then(Result::ok).unwrap_or_default();
+                        settings.set_video_device(&choice.device(vidlist, params));
+                    }
+                    if settings.av_offset_key() != old_offset_key {
+                        // the offset in the window was for the old device
+                        self.config
+                            .av_offsets
+                            .insert(old_offset_key, settings.av_offset);
+                        settings.load_av_offset(&self.config);
+                    }
+                    let _ = self.tx.video.try_send(settings.video_config());
                     if *a_i != usize::MAX {
-                        let name = audlist[*a_i].name.clone();
-                        settings.audname = name.clone();
-                        let _ = self.audname_tx.try_send(name);
+                        settings.audname = audlist[*a_i].name.clone();
+                    }
+                    if *s_i != usize::MAX {
+                        settings.audio_sink = sinklist[*s_i].name.clone();
                     }
-                    settings.save(frame.storage_mut().unwrap());
+                    let _ = self.tx.audio.try_send(settings.audio_config());
+                    if !self.record_dir_text.trim().is_empty() {
+                        settings.record_dir = PathBuf::from(self.record_dir_text.trim());
+                    }
+                    if !self.screenshot_dir_text.trim().is_empty() {
+                        settings.screenshot_dir = PathBuf::from(self.screenshot_dir_text.trim());
+                    }
+                    *self.render_opts.lock().unwrap() = settings.render_options();
+                    self.recorder.set_replay(settings.replay_limits());
+                    self.extras_changed = true;
+                    settings.save(&mut self.config);
+                    self.config.save();
+                    frame.set_window_title(&settings.window_title);
                     close = true;
                 }
             });
+        if delete_profile {
+            self.config.profiles.remove(&self.settings.profile);
+            switch_to = Some(String::new());
+        }
+        if let Some(name) = switch_to {
+            self.switch_profile(frame, &name);
+        }
+        if let Some(volume) = new_volume {
+            self.set_volume(|v| *v = volume);
+        }
         if close {
             self.open = false;
         }
         if !self.open {
             self.vid_list = None;
             self.audio_list = None;
+            self.sink_list = None;
         }
     }
 }
@@ -238,6 +998,81 @@ fn source_dropdown<T>(
     });
 }
 
+fn format_picker(ui: &mut egui::Ui, choice: &mut FormatChoice, modes: &ModeList) {
+    let pinned = matches!(choice, FormatChoice::Pinned(_));
+    let mut modes = modes.lock().unwrap().available.clone();
+    modes.sort_by(|a, b| format::compare(b, a, format::DEFAULT_POLICY));
+    modes.dedup();
+    ui.horizontal(|ui| {
+        ui.label("Format");
+        if ui.radio(!pinned, "Automatic").clicked() && pinned {
+            *choice = FormatChoice::default();
+        }
+        if ui.radio(pinned, "Pinned").clicked() && !pinned {
+            if let Some(mode) = modes.first() {
+                *choice = FormatChoice::Pinned(*mode);
+            }
+        }
+    });
+    match choice {
+        FormatChoice::Policy(policy) => {
+            enum Action {
+                Up(usize),
+                Down(usize),
+                Remove(usize),
+                Add(Criterion),
+            }
+            let mut action = None;
+            for (i, crit) in policy.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}. {}", i + 1, crit.label()));
+                    if ui.add_enabled(i > 0, egui::Button::new("⬆")).clicked() {
+                        action = Some(Action::Up(i));
+                    }
+                    if ui
+                        .add_enabled(i + 1 < policy.len(), egui::Button::new("⬇"))
+                        .clicked()
+                    {
+                        action = Some(Action::Down(i));
+                    }
+                    if ui.button("🗙").clicked() {
+                        action = Some(Action::Remove(i));
+                    }
+                });
+            }
+            ui.horizontal(|ui| {
+                for crit in Criterion::ALL {
+                    if !policy.contains(&crit) && ui.button(format!("+ {}", crit.label())).clicked()
+                    {
+                        action = Some(Action::Add(crit));
+                    }
+                }
+            });
+            match action {
+                Some(Action::Up(i)) => policy.swap(i, i - 1),
+                Some(Action::Down(i)) => policy.swap(i, i + 1),
+                Some(Action::Remove(i)) => {
+                    policy.remove(i);
+                }
+                Some(Action::Add(crit)) => policy.push(crit),
+                None => {}
+            }
+        }
+        FormatChoice::Pinned(pin) => {
+            if modes.is_empty() {
+                ui.label("Connect the device to see the formats it supports");
+            }
+            egui::ComboBox::from_id_source("pinnedformat")
+                .selected_text(pin.to_string())
+                .show_ui(ui, |ui| {
+                    for mode in modes {
+                        ui.selectable_value(pin, mode, mode.to_string());
+                    }
+                });
+        }
+    }
+}
+
 // struct SettingStringField<T: FromStr> {
 //     s: String,
 //     _t: PhantomData<T>,
@@ -267,3 +1102,41 @@ fn source_dropdown<T>(
 //         t
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plugged_devices_need_the_whole_id() {
+        let mut settings = Settings::load(&Config::default(), "");
+        let plugged = DeviceId {
+            vendor_id: Some(0x534d),
+            product_id: Some(0x2109),
+            serial: Some("one".to_owned()),
+            port_path: Some("3-1.4".to_owned()),
+        };
+        // nothing picked means nothing to switch for
+        assert!(!settings.uses_device(&plugged));
+        settings.set_video_device(&VideoDevice::Uvc(DeviceId {
+            serial: None,
+            port_path: None,
+            ..plugged.clone()
+        }));
+        assert!(settings.uses_device(&plugged));
+        settings.set_video_device(&VideoDevice::Uvc(plugged.clone()));
+        assert!(settings.uses_device(&plugged));
+        let other = DeviceId {
+            serial: Some("two".to_owned()),
+            ..plugged.clone()
+        };
+        assert!(!settings.uses_device(&other));
+        let elsewhere = DeviceId {
+            port_path: Some("1-1.4".to_owned()),
+            ..plugged.clone()
+        };
+        assert!(!settings.uses_device(&elsewhere));
+        settings.set_video_device(&VideoDevice::TestPattern("320x240@30".parse().unwrap()));
+        assert!(!settings.uses_device(&plugged));
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::convert::PixelFormat;
+
+/// counters the video and audio threads bump so the overlay has something to show
+#[derive(Clone, Default)]
+pub(crate) struct Stats(Arc<Mutex<Counters>>);
+
+#[derive(Clone, Default)]
+pub(crate) struct Counters {
+    /// what the frames actually look like, as opposed to what we asked for
+    pub frame_format: Option<(PixelFormat, usize, usize)>,
+    pub frames_in: Rate,
+    pub frames_shown: Rate,
+    /// frames that got replaced before anything drew them
+    pub dropped: u64,
+    /// frames we couldn't decode
+    pub bad: u64,
+    pub reconnects: u64,
+    pub audio: AudioStatus,
+    // the pipewire backend's running, so recordings have no audio and the
+    // volume and meters don't do anything
+    pipewire: bool,
+    // whether the newest frame's been drawn yet
+    unpainted: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) enum AudioStatus {
+    #[default]
+    Connecting,
+    NoSource(String),
+    Looping {
+        source: String,
+        module: u32,
+    },
+    Passthrough {
+        source: String,
+        latency_ms: u32,
+    },
+    // only the pipewire backend links things
+    #[cfg_attr(not(feature = "pipewire"), allow(dead_code))]
+    Linked {
+        source: String,
+        sink: String,
+    },
+    /// lost the server (or never had it), trying again in a bit
+    Reconnecting {
+        error: String,
+        retry_secs: u64,
+    },
+    Error(String),
+}
+
+impl fmt::Display for AudioStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioStatus::Connecting => f.write_str("connecting to pulse"),
+            AudioStatus::NoSource(name) if name.is_empty() => f.write_str("no source picked"),
+            AudioStatus::NoSource(name) => write!(f, "{name} isn't around"),
+            AudioStatus::Looping { source, module } => {
+                write!(f, "looping back {source} (module {module})")
+            }
+            AudioStatus::Passthrough { source, latency_ms } => {
+                write!(f, "passing {source} through (~{latency_ms}ms)")
+            }
+            AudioStatus::Linked { source, sink } => write!(f, "linked {source} to {sink}"),
+            AudioStatus::Reconnecting { error, retry_secs } => {
+                write!(f, "no pulse ({error}), retrying in {retry_secs}s")
+            }
+            AudioStatus::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+impl Stats {
+    pub fn snapshot(&self) -> Counters {
+        let mut counters = self.0.lock().unwrap();
+        let now = Instant::now();
+        counters.frames_in.trim(now);
+        counters.frames_shown.trim(now);
+        counters.clone()
+    }
+
+    pub fn frame_in(&self, format: PixelFormat, width: usize, height: usize) {
+        let mut counters = self.0.lock().unwrap();
+        counters.frame_format = Some((format, width, height));
+        counters.frames_in.tick(Instant::now());
+    }
+
+    pub fn uploaded(&self) {
+        let mut counters = self.0.lock().unwrap();
+        if counters.unpainted {
+            counters.dropped += 1;
+        }
+        counters.unpainted = true;
+    }
+
+    pub fn painted(&self) {
+        let mut counters = self.0.lock().unwrap();
+        if counters.unpainted {
+            counters.unpainted = false;
+            counters.frames_shown.tick(Instant::now());
+        }
+    }
+
+    pub fn bad_frame(&self) {
+        self.0.lock().unwrap().bad += 1;
+    }
+
+    pub fn reconnected(&self) {
+        self.0.lock().unwrap().reconnects += 1;
+    }
+
+    pub fn set_audio(&self, status: AudioStatus) {
+        self.0.lock().unwrap().audio = status;
+    }
+
+    pub fn pipewire(&self) -> bool {
+        self.0.lock().unwrap().pipewire
+    }
+
+    #[cfg_attr(not(feature = "pipewire"), allow(dead_code))]
+    pub fn set_pipewire(&self) {
+        self.0.lock().unwrap().pipewire = true;
+    }
+}
+
+/// events in the last second
+#[derive(Clone, Default)]
+pub(crate) struct Rate(VecDeque<Instant>);
+
+impl Rate {
+    fn tick(&mut self, now: Instant) {
+        self.0.push_back(now);
+        self.trim(now);
+    }
+
+    fn trim(&mut self, now: Instant) {
+        while let Some(&t) = self.0.front() {
+            if now.duration_since(t) <= Duration::from_secs(1) {
+                break;
+            }
+            self.0.pop_front();
+        }
+    }
+
+    pub fn per_sec(&self) -> usize {
+        self.0.len()
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::convert::{Frame, PixelFormat};
+use crate::format::{DeviceModes, Mode, ModeFormat, ModeList};
+use crate::video::{EguiTexture, StreamEnd, UsbUpdate, VideoSource};
+
+/// resolution and framerate of the generated pattern, written like `1280x720@60`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Params {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            fps: 60,
+        }
+    }
+}
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}@{}", self.width, self.height, self.fps)
+    }
+}
+impl FromStr for Params {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, fps) = s.split_once('@').unwrap_or((s, "60"));
+        let (width, height) = size
+            .split_once('x')
+            .ok_or_else(|| anyhow::anyhow!("expected WIDTHxHEIGHT[@FPS], got {s:?}"))?;
+        let params = Self {
+            width: width.trim().parse()?,
+            height: height.trim().parse()?,
+            fps: fps.trim().parse()?,
+        };
+        anyhow::ensure!(
+            params.width > 0 && params.height > 0 && params.fps > 0,
+            "test pattern dimensions and fps must be nonzero"
+        );
+        Ok(params)
+    }
+}
+
+impl Params {
+    /// the only mode the pattern comes in
+    pub fn mode(&self) -> Mode {
+        Mode {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            format: ModeFormat::Uncompressed,
+        }
+    }
+}
+
+/// a fake capture device that's always plugged in and shows color bars
+pub(crate) struct TestPattern {
+    params: Params,
+    modes: ModeList,
+}
+impl TestPattern {
+    pub fn new(params: Params, modes: ModeList) -> Self {
+        Self { params, modes }
+    }
+}
+
+impl VideoSource for TestPattern {
+    fn watch(&mut self, tx: flume::Sender<UsbUpdate>) {
+        let _ = tx.send(UsbUpdate::Connected);
+    }
+
+    fn stream(
+        &mut self,
+        mut texture: EguiTexture,
+        wait: &mut dyn FnMut() -> StreamEnd,
+    ) -> anyhow::Result<()> {
+        let params = self.params;
+        eprintln!("using test pattern {params}");
+        let mode = params.mode();
+        *self.modes.lock().unwrap() = DeviceModes {
+            available: vec![mode],
+            current: Some(mode),
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = stop.clone();
+        let generator = std::thread::spawn(move || {
+            let interval = Duration::from_secs(1) / params.fps;
+            let mut buf = Vec::new();
+            let mut next = Instant::now();
+            let mut frame_no = 0u64;
+            while !stop2.load(Relaxed) {
+                render(
+                    &mut buf,
+                    params.width as usize,
+                    params.height as usize,
+                    frame_no,
+                );
+                texture.handle_frame(Frame {
+                    width: params.width as usize,
+                    height: params.height as usize,
+                    format: PixelFormat::Rgb24,
+                    data: &buf,
+                    captured: Instant::now(),
+                });
+                frame_no += 1;
+                next += interval;
+                match next.checked_duration_since(Instant::now()) {
+                    Some(d) => std::thread::sleep(d),
+                    // we're behind, don't try to catch up
+                    None => next = Instant::now(),
+                }
+            }
+        });
+        wait();
+        stop.store(true, Relaxed);
+        let _ = generator.join();
+        Ok(())
+    }
+}
+
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+const CASTELLATIONS: [[u8; 3]; 7] = [
+    [0, 0, 191],
+    [19, 19, 19],
+    [191, 0, 191],
+    [19, 19, 19],
+    [0, 191, 191],
+    [19, 19, 19],
+    [191, 191, 191],
+];
+
+/// draw one frame of the pattern into `buf` as packed rgb24
+pub(crate) fn render(buf: &mut Vec<u8>, width: usize, height: usize, frame_no: u64) {
+    buf.resize(width * height * 3, 0);
+    let bars_end = height * 2 / 3;
+    let castle_end = height * 3 / 4;
+    let shift = (frame_no as usize * 4) % width.max(1);
+    for (y, row) in buf.chunks_exact_mut(width * 3).enumerate() {
+        for (x, px) in row.chunks_exact_mut(3).enumerate() {
+            let bar = x * 7 / width;
+            let color = if y < bars_end {
+                BARS[bar]
+            } else if y < castle_end {
+                CASTELLATIONS[bar]
+            } else {
+                // a gradient that scrolls one way while the frame counter counts up
+                let v = (((x + shift) % width) * 255 / width) as u8;
+                [v, v, v]
+            };
+            px.copy_from_slice(&color);
+        }
+    }
+    draw_counter(buf, width, height, castle_end, frame_no);
+}
+
+// 3x5 bitmap digits, each row is the low 3 bits
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+fn draw_counter(buf: &mut [u8], width: usize, height: usize, top: usize, frame_no: u64) {
+    let scale = (height / 60).max(1);
+    let digits = frame_no.to_string();
+    let (x0, y0) = (scale * 2, top + scale * 2);
+    for (i, d) in digits.bytes().enumerate() {
+        let glyph = &DIGITS[(d - b'0') as usize];
+        let gx = x0 + i * 4 * scale;
+        for (gy, bits) in glyph.iter().enumerate() {
+            for col in 0..3 {
+                let on = bits & (0b100 >> col) != 0;
+                let color = if on { [235, 235, 235] } else { [16, 16, 16] };
+                for py in 0..scale {
+                    for px in 0..scale {
+                        let (x, y) = (gx + col * scale + px, y0 + gy * scale + py);
+                        if x < width && y < height {
+                            let off = (y * width + x) * 3;
+                            buf[off..off + 3].copy_from_slice(&color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{self, Colorimetry};
+
+    fn pattern(width: usize, height: usize, frame_no: u64) -> egui::ColorImage {
+        let mut buf = Vec::new();
+        render(&mut buf, width, height, frame_no);
+        let frame = Frame {
+            width,
+            height,
+            format: PixelFormat::Rgb24,
+            data: &buf,
+            captured: Instant::now(),
+        };
+        convert::to_color_image(&frame, Colorimetry::default()).unwrap()
+    }
+
+    fn px(image: &egui::ColorImage, x: usize, y: usize) -> [u8; 4] {
+        image.pixels[y * image.size[0] + x].to_array()
+    }
+
+    #[test]
+    fn bars_and_castellations() {
+        let image = pattern(140, 60, 0);
+        assert_eq!(image.size, [140, 60]);
+        for (i, (bar, castle)) in BARS.iter().zip(CASTELLATIONS).enumerate() {
+            let x = i * 20 + 10;
+            assert_eq!(px(&image, x, 0), [bar[0], bar[1], bar[2], 255], "bar {i}");
+            assert_eq!(px(&image, x, 39), [bar[0], bar[1], bar[2], 255], "bar {i}");
+            let castle = [castle[0], castle[1], castle[2], 255];
+            assert_eq!(px(&image, x, 42), castle, "castellation {i}");
+        }
+    }
+
+    #[test]
+    fn counter_shows_the_frame_number() {
+        // scale 1, so the first digit's top left is at (2, 47)
+        let zero = pattern(140, 60, 0);
+        assert_eq!(px(&zero, 2, 47), [235, 235, 235, 255]);
+        assert_eq!(px(&zero, 3, 48), [16, 16, 16, 255]);
+        let one = pattern(140, 60, 1);
+        assert_eq!(px(&one, 2, 47), [16, 16, 16, 255]);
+        assert_eq!(px(&one, 3, 47), [235, 235, 235, 255]);
+    }
+
+    #[test]
+    fn gradient_scrolls() {
+        let (a, b) = (pattern(140, 60, 0), pattern(140, 60, 1));
+        for x in 40..100 {
+            assert_eq!(px(&a, x + 4, 59), px(&b, x, 59));
+        }
+        assert_ne!(px(&a, 40, 59), px(&b, 40, 59));
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let params: Params = "640x480@30".parse().unwrap();
+        assert_eq!((params.width, params.height, params.fps), (640, 480, 30));
+        assert_eq!(params.to_string().parse::<Params>().unwrap(), params);
+        assert_eq!("320x240".parse::<Params>().unwrap().fps, 60);
+        assert!("0x240".parse::<Params>().is_err());
+        assert!("wide".parse::<Params>().is_err());
+    }
+}
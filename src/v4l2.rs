@@ -0,0 +1,537 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use v4l::io::traits::CaptureStream;
+use v4l::video::Capture;
+use v4l::FourCC;
+
+use crate::audio::UsbMatch;
+use crate::convert::{Frame, PixelFormat};
+use crate::format::{FormatChoice, Mode, ModeFormat, ModeList};
+use crate::video::{EguiTexture, StreamEnd, UsbUpdate, VideoSource};
+
+const FOURCCS: &[(FourCC, PixelFormat)] = &[
+    (FourCC { repr: *b"YUYV" }, PixelFormat::Yuyv),
+    (FourCC { repr: *b"NV12" }, PixelFormat::Nv12),
+    (FourCC { repr: *b"RGB3" }, PixelFormat::Rgb24),
+    (FourCC { repr: *b"MJPG" }, PixelFormat::Mjpeg),
+];
+
+/// a `/dev/videoN` node, driven through the kernel's own uvcvideo driver so
+/// other programs can still use the card
+pub(crate) struct V4l2Source {
+    path: PathBuf,
+    format: FormatChoice,
+    modes: ModeList,
+    stop_watch: Arc<AtomicBool>,
+    /// for telling the actor when capture dies on its own or can't start
+    conn_tx: Option<flume::Sender<UsbUpdate>>,
+}
+
+impl V4l2Source {
+    pub fn new(path: PathBuf, format: FormatChoice, modes: ModeList) -> Self {
+        Self {
+            path,
+            format,
+            modes,
+            stop_watch: Arc::new(AtomicBool::new(false)),
+            conn_tx: None,
+        }
+    }
+    /// open the node and set it up in the format we want
+    fn open(&self) -> anyhow::Result<(v4l::Device, Layout)> {
+        let dev = v4l::Device::with_path(&self.path)
+            .with_context(|| format!("couldn't open {}", self.path.display()))?;
+
+        let modes = enum_modes(&dev)?;
+        self.modes.lock().unwrap().available = modes.iter().map(|(m, _)| *m).collect();
+        let (format, fourcc) = self
+            .format
+            .choose(modes, |(m, _)| *m)
+            .context("no preferred formats")?;
+
+        let negotiated = dev.set_format(&v4l::Format::new(format.width, format.height, fourcc))?;
+        let layout = Layout::new(&negotiated)?;
+        dev.set_params(&v4l::video::capture::Parameters::with_fps(format.fps))?;
+        eprintln!("using {format} ({fourcc}) on {}", self.path.display());
+        self.modes.lock().unwrap().current = Some(format);
+        Ok((dev, layout))
+    }
+}
+
+impl Drop for V4l2Source {
+    fn drop(&mut self) {
+        self.stop_watch.store(true, Relaxed);
+    }
+}
+
+/// a v4l2 device node that we know how to show
+pub(crate) struct Node {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+pub(crate) fn list_devices() -> Vec<Node> {
+    let mut nodes = v4l::context::enum_devices()
+        .into_iter()
+        .filter(|node| {
+            // metadata nodes show up as /dev/videoN too, skip anything we can't capture from
+            v4l::Device::with_path(node.path())
+                .and_then(|dev| dev.enum_formats())
+                .map_or(false, |formats| {
+                    formats.iter().any(|f| pixel_format(f.fourcc).is_some())
+                })
+        })
+        .map(|node| Node {
+            name: node.name().unwrap_or_default(),
+            path: node.path().to_owned(),
+        })
+        .collect::<Vec<_>>();
+    nodes.sort_by(|a, b| a.path.cmp(&b.path));
+    nodes
+}
+
+/// the usb device behind a video node, according to sysfs. the node's `device`
+/// link points at the uvc interface (e.g. /sys/bus/usb/devices/1-2:1.0), and
+/// the usb device is its parent
+pub(crate) fn usb_match(path: &Path) -> UsbMatch {
+    let usb_dev = || {
+        let node = std::fs::canonicalize(path).ok()?;
+        let sys = Path::new("/sys/class/video4linux")
+            .join(node.file_name()?)
+            .join("device");
+        Some(std::fs::canonicalize(sys).ok()?.parent()?.to_owned())
+    };
+    let dev = match usb_dev() {
+        Some(dev) => dev,
+        None => return UsbMatch::default(),
+    };
+    let id = |file| {
+        let s = std::fs::read_to_string(dev.join(file)).ok()?;
+        u16::from_str_radix(s.trim(), 16).ok()
+    };
+    // usb device dirs are named bus-ports, like 1-2 or 3-1.4
+    let port_path = dev
+        .file_name()
+        .and_then(|name| name.to_str()?.split_once('-'))
+        .map(|(_, ports)| ports.to_owned());
+    UsbMatch {
+        vendor_id: id("idVendor"),
+        product_id: id("idProduct"),
+        port_path,
+    }
+}
+
+fn pixel_format(fourcc: FourCC) -> Option<PixelFormat> {
+    FOURCCS
+        .iter()
+        .find(|(f, _)| *f == fourcc)
+        .map(|(_, pf)| *pf)
+}
+
+/// how the driver lays frames out in its buffers
+#[derive(Copy, Clone, Debug)]
+struct Layout {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    /// bytes per line, which can be more than the pixels need
+    stride: usize,
+}
+
+impl Layout {
+    /// what `set_format` came back with. drivers are allowed to pick a
+    /// different fourcc than the one we asked for
+    fn new(negotiated: &v4l::Format) -> anyhow::Result<Self> {
+        let format = pixel_format(negotiated.fourcc).with_context(|| {
+            format!(
+                "driver picked {}, which we can't convert",
+                negotiated.fourcc
+            )
+        })?;
+        Ok(Self {
+            width: negotiated.width as usize,
+            height: negotiated.height as usize,
+            format,
+            stride: negotiated.stride as usize,
+        })
+    }
+
+    /// `data` with the padding at the end of each line taken out, copied
+    /// into `scratch` if there was any
+    fn unpad<'a>(&self, data: &'a [u8], scratch: &'a mut Vec<u8>) -> &'a [u8] {
+        let (w, h) = (self.width, self.height);
+        // bytes of pixels in a line and how many lines, for each plane
+        let planes = match self.format {
+            PixelFormat::Yuyv => [(w.div_ceil(2) * 4, h), (0, 0)],
+            PixelFormat::Rgb24 => [(w * 3, h), (0, 0)],
+            // the uv plane uses the same stride as the y plane
+            PixelFormat::Nv12 => [(w, h), (w.div_ceil(2) * 2, h.div_ceil(2))],
+            PixelFormat::Mjpeg => return data,
+        };
+        if planes
+            .iter()
+            .all(|&(line, lines)| lines == 0 || self.stride <= line)
+        {
+            return data;
+        }
+        scratch.clear();
+        let mut rows = data.chunks(self.stride);
+        for (line, lines) in planes {
+            for row in rows.by_ref().take(lines) {
+                // the last line doesn't always get its padding
+                scratch.extend_from_slice(&row[..line.min(row.len())]);
+            }
+        }
+        scratch
+    }
+}
+
+/// hand frames from `stream` to `sink` until `stop` gets set or a read fails
+fn capture<S>(
+    stream: &mut S,
+    layout: Layout,
+    stop: &AtomicBool,
+    mut sink: impl FnMut(Frame<'_>),
+) -> io::Result<()>
+where
+    S: for<'a> CaptureStream<'a, Item = [u8]>,
+{
+    let mut scratch = Vec::new();
+    while !stop.load(Relaxed) {
+        let (buf, meta) = match stream.next() {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        let data = &buf[..(meta.bytesused as usize).min(buf.len())];
+        sink(Frame {
+            width: layout.width,
+            height: layout.height,
+            format: layout.format,
+            data: layout.unpad(data, &mut scratch),
+            captured: Instant::now(),
+        });
+    }
+    Ok(())
+}
+
+/// what the node at `path` can do
+pub(crate) fn modes(path: &Path) -> anyhow::Result<Vec<Mode>> {
+    let dev = v4l::Device::with_path(path)
+        .with_context(|| format!("couldn't open {}", path.display()))?;
+    Ok(enum_modes(&dev)?.into_iter().map(|(m, _)| m).collect())
+}
+
+/// every mode the device advertises that we can convert
+fn enum_modes(dev: &v4l::Device) -> io::Result<Vec<(Mode, FourCC)>> {
+    let mut modes = vec![];
+    for desc in dev.enum_formats()? {
+        let pixfmt = match pixel_format(desc.fourcc) {
+            Some(x) => x,
+            None => continue,
+        };
+        for size in dev.enum_framesizes(desc.fourcc)? {
+            for size in size.size.to_discrete() {
+                for ival in dev.enum_frameintervals(desc.fourcc, size.width, size.height)? {
+                    let ival = match ival.interval {
+                        v4l::frameinterval::FrameIntervalEnum::Discrete(frac) => frac,
+                        // continuous ranges are rare on capture cards, just take the fastest
+                        v4l::frameinterval::FrameIntervalEnum::Stepwise(step) => step.min,
+                    };
+                    if ival.numerator == 0 {
+                        continue;
+                    }
+                    let format = Mode {
+                        width: size.width,
+                        height: size.height,
+                        fps: ival.denominator / ival.numerator,
+                        format: match pixfmt {
+                            PixelFormat::Mjpeg => ModeFormat::Mjpeg,
+                            _ => ModeFormat::Uncompressed,
+                        },
+                    };
+                    modes.push((format, desc.fourcc));
+                }
+            }
+        }
+    }
+    Ok(modes)
+}
+
+impl VideoSource for V4l2Source {
+    fn watch(&mut self, tx: flume::Sender<UsbUpdate>) {
+        self.conn_tx = Some(tx.clone());
+        // no udev here, so just check whether the node is around every so often
+        self.stop_watch.store(true, Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+        self.stop_watch = stop.clone();
+        let path = self.path.clone();
+        std::thread::spawn(move || {
+            let mut present = false;
+            while !stop.load(Relaxed) {
+                let now = Path::exists(&path);
+                if now != present {
+                    present = now;
+                    let upd = if now {
+                        UsbUpdate::Connected
+                    } else {
+                        UsbUpdate::Disconnected
+                    };
+                    if tx.send(upd).is_err() {
+                        break;
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(500));
+            }
+        });
+    }
+
+    fn stream(
+        &mut self,
+        mut texture: EguiTexture,
+        wait: &mut dyn FnMut() -> StreamEnd,
+    ) -> anyhow::Result<()> {
+        let (dev, layout) = match self.open() {
+            Ok(x) => x,
+            Err(e) => {
+                // busy, or udev hasn't fixed its permissions yet. the watcher
+                // only speaks up when the node comes or goes, so ask for a retry
+                if let Some(tx) = self.conn_tx.as_ref().filter(|_| self.path.exists()) {
+                    let _ = tx.send(UsbUpdate::Failed);
+                }
+                return Err(e);
+            }
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = stop.clone();
+        let failed_tx = self.conn_tx.clone();
+        let capture = std::thread::spawn(move || -> io::Result<()> {
+            let res = v4l::io::mmap::Stream::with_buffers(&dev, v4l::buffer::Type::VideoCapture, 4)
+                .and_then(|mut stream| {
+                    stream.set_timeout(Duration::from_millis(200));
+                    capture(&mut stream, layout, &stop2, |frame| {
+                        texture.handle_frame(frame)
+                    })
+                });
+            if res.is_err() {
+                // otherwise nothing ends the wait until the device goes away
+                if let Some(tx) = failed_tx {
+                    let _ = tx.send(UsbUpdate::Failed);
+                }
+            }
+            res
+        });
+
+        wait();
+        stop.store(true, Relaxed);
+        match capture.join() {
+            Ok(res) => res.context("v4l2 capture failed"),
+            Err(e) => std::panic::resume_unwind(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use v4l::buffer::Metadata;
+    use v4l::io::traits::Stream;
+
+    use super::*;
+
+    /// stands in for the driver: every `next` hands out the next scripted
+    /// buffer, or fails the way the ioctl would have
+    struct FakeStream {
+        script: VecDeque<io::Result<Vec<u8>>>,
+        buf: Vec<u8>,
+        meta: Metadata,
+    }
+
+    impl FakeStream {
+        fn new(script: impl IntoIterator<Item = io::Result<Vec<u8>>>) -> Self {
+            Self {
+                script: script.into_iter().collect(),
+                buf: vec![],
+                meta: Metadata::default(),
+            }
+        }
+    }
+
+    impl Stream for FakeStream {
+        type Item = [u8];
+        fn start(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+        fn stop(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> CaptureStream<'a> for FakeStream {
+        fn queue(&mut self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn dequeue(&mut self) -> io::Result<usize> {
+            Ok(0)
+        }
+        fn next(&'a mut self) -> io::Result<(&'a Self::Item, &'a Metadata)> {
+            // EBADF, like reading a closed fd
+            let data = self
+                .script
+                .pop_front()
+                .unwrap_or_else(|| Err(io::Error::from_raw_os_error(9)))?;
+            self.meta.bytesused = data.len() as u32;
+            // the real buffers are as big as sizeimage, whatever got used
+            self.buf = data;
+            self.buf.resize(self.buf.len() + 64, 0xee);
+            Ok((&self.buf, &self.meta))
+        }
+    }
+
+    fn layout(format: PixelFormat, stride: usize) -> Layout {
+        Layout {
+            width: 4,
+            height: 2,
+            format,
+            stride,
+        }
+    }
+
+    /// every frame `capture` gives out, until the script runs out
+    fn frames(layout: Layout, script: Vec<io::Result<Vec<u8>>>) -> (Vec<Vec<u8>>, io::Error) {
+        let mut stream = FakeStream::new(script);
+        let mut frames = vec![];
+        let stop = AtomicBool::new(false);
+        let err = capture(&mut stream, layout, &stop, |frame| {
+            assert_eq!((frame.width, frame.height), (4, 2));
+            frames.push(frame.data.to_vec());
+        })
+        .unwrap_err();
+        (frames, err)
+    }
+
+    #[test]
+    fn tight_frames_pass_through() {
+        let frame: Vec<u8> = (0..16).collect();
+        let (frames, _) = frames(layout(PixelFormat::Yuyv, 8), vec![Ok(frame.clone())]);
+        assert_eq!(frames, [frame]);
+    }
+
+    #[test]
+    fn padding_gets_stripped() {
+        // two yuyv lines of 8 bytes, padded out to 12
+        let mut padded = vec![];
+        for line in 0..2u8 {
+            padded.extend((0..8).map(|i| line * 8 + i));
+            padded.extend([0xaa; 4]);
+        }
+        let (frames, _) = frames(layout(PixelFormat::Yuyv, 12), vec![Ok(padded)]);
+        assert_eq!(frames, [(0..16).collect::<Vec<u8>>()]);
+    }
+
+    #[test]
+    fn nv12_chroma_lines_are_padded_too() {
+        // 2 y lines and 1 uv line of 4 bytes, with a stride of 6, and no
+        // padding after the last line
+        let padded = vec![1, 2, 3, 4, 0, 0, 5, 6, 7, 8, 0, 0, 9, 10, 11, 12];
+        let (frames, _) = frames(layout(PixelFormat::Nv12, 6), vec![Ok(padded)]);
+        assert_eq!(frames, [(1..=12).collect::<Vec<u8>>()]);
+    }
+
+    #[test]
+    fn mjpeg_is_left_alone() {
+        let jpeg = vec![0xff, 0xd8, 1, 2, 3, 0xff, 0xd9];
+        let (frames, _) = frames(layout(PixelFormat::Mjpeg, 0), vec![Ok(jpeg.clone())]);
+        assert_eq!(frames, [jpeg]);
+    }
+
+    #[test]
+    fn timeouts_are_skipped_and_errors_end_it() {
+        let frame = vec![0; 16];
+        let script = vec![
+            Ok(frame.clone()),
+            Err(io::ErrorKind::TimedOut.into()),
+            Ok(frame.clone()),
+            Err(io::Error::from_raw_os_error(19)),
+            Ok(frame),
+        ];
+        let (frames, err) = frames(layout(PixelFormat::Yuyv, 8), script);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(err.raw_os_error(), Some(19));
+    }
+
+    #[test]
+    fn stop_ends_it() {
+        let mut stream = FakeStream::new((0..10).map(|_| Ok(vec![0; 16])));
+        let stop = AtomicBool::new(false);
+        let mut n = 0;
+        capture(&mut stream, layout(PixelFormat::Yuyv, 8), &stop, |_| {
+            n += 1;
+            if n == 3 {
+                stop.store(true, Relaxed);
+            }
+        })
+        .unwrap();
+        assert_eq!(n, 3);
+    }
+
+    #[test]
+    fn unsupported_fourcc_is_an_error() {
+        let fourcc = FourCC::new(b"Y10 ");
+        let err = Layout::new(&v4l::Format::new(640, 480, fourcc)).unwrap_err();
+        assert!(err.to_string().contains("Y10"), "{err}");
+        let yuyv = v4l::Format::new(640, 480, FourCC::new(b"YUYV"));
+        assert_eq!(Layout::new(&yuyv).unwrap().format, PixelFormat::Yuyv);
+    }
+
+    /// the vivid test driver, if it's loaded (`modprobe vivid`)
+    fn vivid() -> Option<PathBuf> {
+        std::fs::read_dir("/sys/class/video4linux")
+            .ok()?
+            .flatten()
+            .find(|node| {
+                std::fs::read_to_string(node.path().join("name"))
+                    .is_ok_and(|name| name.trim().ends_with("vid-cap"))
+            })
+            .map(|node| Path::new("/dev").join(node.file_name()))
+    }
+
+    #[test]
+    fn vivid_streams() {
+        let path = match vivid() {
+            Some(path) => path,
+            None => return eprintln!("vivid isn't loaded, skipping"),
+        };
+        let dev = v4l::Device::with_path(&path).unwrap();
+        let modes = enum_modes(&dev).unwrap();
+        let (mode, fourcc) = modes
+            .iter()
+            .find(|(_, fourcc)| fourcc.repr == *b"YUYV")
+            .expect("vivid always has yuyv");
+        let negotiated = dev
+            .set_format(&v4l::Format::new(mode.width, mode.height, *fourcc))
+            .unwrap();
+        let layout = Layout::new(&negotiated).unwrap();
+        let mut stream =
+            v4l::io::mmap::Stream::with_buffers(&dev, v4l::buffer::Type::VideoCapture, 4).unwrap();
+        stream.set_timeout(Duration::from_secs(2));
+        let stop = AtomicBool::new(false);
+        let mut n = 0;
+        capture(&mut stream, layout, &stop, |frame| {
+            let len = frame.format.frame_len(frame.width, frame.height);
+            assert_eq!(Some(frame.data.len()), len);
+            n += 1;
+            if n == 3 {
+                stop.store(true, Relaxed);
+            }
+        })
+        .unwrap();
+    }
+}
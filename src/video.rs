@@ -1,100 +1,387 @@
-use std::cmp;
+use std::collections::VecDeque;
 use std::ops::ControlFlow;
-use std::time::Duration;
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Context;
-use egui::Color32;
 use rusb::UsbContext;
 
-use crate::DeviceId;
+use crate::convert::{self, Colorimetry, Frame, PixelFormat};
+use crate::format::{FormatChoice, Mode, ModeFormat, ModeList};
+use crate::gpu::GpuFrames;
+use crate::latency::Latency;
+use crate::record::Recorder;
+use crate::screenshot::FrameGrab;
+use crate::stats::Stats;
+use crate::{testpattern, v4l2, DeviceId, VideoConfig, VideoDevice};
 
 pub(crate) struct CameraParams {
     pub texture: egui::TextureHandle,
     pub ctx: egui::Context,
-    pub devid_rx: flume::Receiver<DeviceId>,
-    pub devid: DeviceId,
+    pub config_rx: flume::Receiver<VideoConfig>,
+    pub config: VideoConfig,
+    pub modes: ModeList,
+    pub gpu: GpuFrames,
+    pub render_opts: Arc<Mutex<RenderOptions>>,
+    pub recorder: Recorder,
+    pub grab: FrameGrab,
+    pub latency: Latency,
+    pub stats: Stats,
+}
+
+/// display settings the video thread needs to know about
+#[derive(Copy, Clone, Default)]
+pub(crate) struct RenderOptions {
+    /// hand yuv frames to the gpu instead of converting them ourselves
+    pub gpu_yuv: bool,
+    pub colorimetry: Colorimetry,
+    /// hold frames back this long, for when the audio's behind
+    pub video_delay: Duration,
+}
+
+/// something `CameraActor` can pull frames out of
+pub(crate) trait VideoSource {
+    /// start watching for the device; `tx` should get a `Connected` once it's
+    /// ready to stream, and a `Disconnected` if it goes away
+    fn watch(&mut self, tx: flume::Sender<UsbUpdate>);
+    /// feed frames into `texture` until `wait` returns
+    fn stream(
+        &mut self,
+        texture: EguiTexture,
+        wait: &mut dyn FnMut() -> StreamEnd,
+    ) -> anyhow::Result<()>;
 }
 
 struct CameraActor {
     texture: EguiTexture,
-    devid_rx: flume::Receiver<DeviceId>,
-    devid: DeviceId,
-    usb_ctx: rusb::Context,
-    plug_reg: Option<rusb::Registration<rusb::Context>>,
+    chans: Chans,
+    source: Box<dyn VideoSource>,
+    usb: Option<UsbState>,
+    modes: ModeList,
+}
+
+struct Chans {
+    config_rx: flume::Receiver<VideoConfig>,
+    config: VideoConfig,
     conn_tx: flume::Sender<UsbUpdate>,
     conn_rx: flume::Receiver<UsbUpdate>,
 }
 
 pub(crate) fn run(args: CameraParams) {
-    let (conn_tx, conn_rx) = flume::bounded(16);
-    CameraActor {
-        texture: EguiTexture {
-            texture: args.texture,
-            ctx: args.ctx,
-        },
-        devid_rx: args.devid_rx,
-        devid: args.devid,
-        usb_ctx: rusb::Context::new().expect("couldn't create context"),
-        plug_reg: None,
-        conn_tx,
-        conn_rx,
-    }
-    .run()
-}
-
-macro_rules! prefer {
-    ($f:expr) => {
-        |a, b| {
-            let f: fn(&uvc::StreamFormat) -> _ = $f;
-            f(&a).cmp(&f(&b))
-        }
-    };
-}
-const TARGET_RATIO: f32 = 16.0 / 9.0;
-const FORMAT_PREFERENCES: &[fn(uvc::StreamFormat, uvc::StreamFormat) -> cmp::Ordering] = &[
-    prefer!(|x| x.fps),
-    prefer!(|x| x.format == uvc::FrameFormat::Uncompressed),
-    prefer!(|x| {
-        let ratio = x.width as f32 / x.height as f32;
-        let diff = TARGET_RATIO - ratio;
-        cmp::Reverse(ordered_float::OrderedFloat(diff))
-    }),
-];
+    std::thread::spawn(move || {
+        let (conn_tx, conn_rx) = flume::bounded(16);
+        let mut actor = CameraActor {
+            texture: EguiTexture {
+                texture: args.texture,
+                ctx: args.ctx,
+                gpu: args.gpu,
+                opts: args.render_opts,
+                recorder: args.recorder,
+                grab: args.grab,
+                latency: args.latency,
+                stats: args.stats,
+                delay: None,
+            },
+            chans: Chans {
+                config_rx: args.config_rx,
+                config: args.config,
+                conn_tx,
+                conn_rx,
+            },
+            source: Box::new(NoSource),
+            usb: None,
+            modes: args.modes,
+        };
+        actor.switch_source();
+        actor.run()
+    });
+}
 
 #[derive(PartialEq, Eq)]
-enum UsbUpdate {
+pub(crate) enum UsbUpdate {
     Connected,
     Disconnected,
+    /// the source stopped streaming by itself, or couldn't start but should be
+    /// tried again. the error comes out of `stream`
+    Failed,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) enum StreamEnd {
+    Disconnected,
+    DevSwitch,
+    Failed,
+    /// nobody's sending configs anymore, time to go
+    Quit,
 }
 
 impl CameraActor {
     fn run(mut self) {
-        std::thread::spawn(move || {
-            let usb_ctx = self.usb_ctx.clone();
-            let ctx = uvc::Context::from_usb_ctx(&usb_ctx).expect("couldn't create context");
-
-            self.hotplug();
-
-            let usb_ctx = self.usb_ctx.clone();
-            std::thread::spawn(move || loop {
-                if let Err(e) = usb_ctx.handle_events(None) {
-                    eprintln!("libusb error? {e}")
+        let mut lost = false;
+        // the device is still there after a failure, so there's no connect to wait for
+        let mut retry = false;
+        loop {
+            while !std::mem::take(&mut retry) {
+                match self.chans.poll() {
+                    PollChanRes::Plug(UsbUpdate::Connected | UsbUpdate::Failed) => break,
+                    PollChanRes::Plug(UsbUpdate::Disconnected) => {}
+                    PollChanRes::DevSwitch => {
+                        lost = false;
+                        self.switch_source()
+                    }
+                    PollChanRes::Quit => return,
                 }
+            }
+            if std::mem::take(&mut lost) {
+                self.texture.stats.reconnected();
+            }
+            let chans = &mut self.chans;
+            let mut end = None;
+            let res = self.source.stream(self.texture.clone(), &mut || {
+                let e = chans.wait_stream_end();
+                end = Some(e);
+                e
             });
+            self.texture.set_texture(egui::ColorImage::example());
+            match end {
+                Some(StreamEnd::DevSwitch) => self.switch_source(),
+                Some(StreamEnd::Disconnected) => lost = true,
+                Some(StreamEnd::Failed) => retry = true,
+                Some(StreamEnd::Quit) => return,
+                None => {}
+            }
+            if let Err(e) = res {
+                eprintln!("error!! {e}");
+                std::thread::sleep(Duration::from_millis(500));
+            }
+        }
+    }
 
-            loop {
-                while !matches!(self.poll_chan(), PollChanRes::Plug(UsbUpdate::Connected)) {}
-                let res = self.go(&ctx);
-                self.texture.set_texture(egui::ColorImage::example());
-                if let Err(e) = res {
-                    eprintln!("error!! {e}");
-                    std::thread::sleep(Duration::from_millis(500));
-                }
+    fn switch_source(&mut self) {
+        // drop the old one first so it stops sending hotplug events
+        self.source = Box::new(NoSource);
+        *self.modes.lock().unwrap() = Default::default();
+        let VideoConfig { dev, format } = self.chans.config.clone();
+        let modes = self.modes.clone();
+        self.source = match dev {
+            VideoDevice::Uvc(devid) => {
+                let usb = self.usb.get_or_insert_with(UsbState::new);
+                Box::new(UvcSource::new(usb, devid, format, modes))
+            }
+            VideoDevice::V4l2(path) => Box::new(v4l2::V4l2Source::new(path, format, modes)),
+            VideoDevice::TestPattern(params) => {
+                Box::new(testpattern::TestPattern::new(params, modes))
+            }
+        };
+        self.source.watch(self.chans.conn_tx.clone());
+    }
+}
+
+impl Chans {
+    fn wait_stream_end(&mut self) -> StreamEnd {
+        loop {
+            match self.poll() {
+                PollChanRes::Plug(UsbUpdate::Connected) => continue,
+                PollChanRes::Plug(UsbUpdate::Disconnected) => return StreamEnd::Disconnected,
+                PollChanRes::Plug(UsbUpdate::Failed) => return StreamEnd::Failed,
+                PollChanRes::DevSwitch => return StreamEnd::DevSwitch,
+                PollChanRes::Quit => return StreamEnd::Quit,
+            }
+        }
+    }
+
+    fn poll(&mut self) -> PollChanRes {
+        loop {
+            let res = flume::Selector::new()
+                .recv(&self.conn_rx, |upd| {
+                    ControlFlow::Break(PollChanRes::Plug(upd.unwrap()))
+                })
+                .recv(&self.config_rx, |config| {
+                    if let Ok(config) = config {
+                        let switch = config != self.config;
+                        if switch {
+                            self.config = config;
+                            ControlFlow::Break(PollChanRes::DevSwitch)
+                        } else {
+                            ControlFlow::Continue(())
+                        }
+                    } else {
+                        // extra sources get dropped when they're taken out,
+                        // the main one only goes away with the process
+                        ControlFlow::Break(PollChanRes::Quit)
+                    }
+                })
+                .wait();
+            if let ControlFlow::Break(x) = res {
+                return x;
+            }
+        }
+    }
+}
+enum PollChanRes {
+    Plug(UsbUpdate),
+    DevSwitch,
+    Quit,
+}
+
+/// placeholder while switching between sources
+struct NoSource;
+impl VideoSource for NoSource {
+    fn watch(&mut self, _: flume::Sender<UsbUpdate>) {}
+    fn stream(
+        &mut self,
+        _: EguiTexture,
+        wait: &mut dyn FnMut() -> StreamEnd,
+    ) -> anyhow::Result<()> {
+        wait();
+        Ok(())
+    }
+}
+
+/// libusb state shared by every uvc source, so we only have one event thread
+struct UsbState {
+    usb_ctx: rusb::Context,
+    uvc_ctx: Rc<uvc::Context<'static>>,
+}
+impl UsbState {
+    fn new() -> Self {
+        let usb_ctx = rusb::Context::new().expect("couldn't create context");
+        let uvc_ctx = uvc::Context::from_usb_ctx(&usb_ctx).expect("couldn't create context");
+
+        let events_ctx = usb_ctx.clone();
+        std::thread::spawn(move || loop {
+            if let Err(e) = events_ctx.handle_events(None) {
+                eprintln!("libusb error? {e}")
             }
         });
+
+        Self {
+            usb_ctx,
+            uvc_ctx: Rc::new(uvc_ctx),
+        }
     }
+}
+
+struct UvcSource {
+    usb_ctx: rusb::Context,
+    ctx: Rc<uvc::Context<'static>>,
+    devid: DeviceId,
+    format: FormatChoice,
+    modes: ModeList,
+    plug_reg: Option<rusb::Registration<rusb::Context>>,
+    /// bus and address of the device we're streaming from
+    streaming: Arc<Mutex<Option<(u8, u8)>>>,
+}
 
-    fn hotplug(&mut self) {
+impl UvcSource {
+    fn new(usb: &UsbState, devid: DeviceId, format: FormatChoice, modes: ModeList) -> Self {
+        Self {
+            usb_ctx: usb.usb_ctx.clone(),
+            ctx: usb.uvc_ctx.clone(),
+            devid,
+            format,
+            modes,
+            plug_reg: None,
+            streaming: Default::default(),
+        }
+    }
+}
+
+pub(crate) struct UvcDevice {
+    pub desc: uvc::DeviceDescription,
+    /// everything we know to find this exact one again
+    pub id: DeviceId,
+}
+
+/// every uvc device plugged in right now
+pub(crate) fn uvc_devices() -> Result<Vec<UvcDevice>, uvc::Error> {
+    let ctx = uvc::Context::new()?;
+    let devices = list_uvc(&ctx)?;
+    Ok(devices.into_iter().map(|(_, dev)| dev).collect())
+}
+
+/// what the first uvc device matching `devid` can do
+pub(crate) fn uvc_device_modes(devid: &DeviceId) -> anyhow::Result<Vec<Mode>> {
+    let ctx = uvc::Context::new()?;
+    let device = find_uvc(&ctx, devid)?;
+    let devh = device.open()?;
+    Ok(uvc_modes(&devh))
+}
+
+fn list_uvc<'a>(
+    ctx: &'a uvc::Context<'_>,
+) -> Result<Vec<(uvc::Device<'a>, UvcDevice)>, uvc::Error> {
+    // libuvc only knows the bus and address, libusb knows the ports
+    let usb = rusb::devices()
+        .map(|list| list.iter().collect::<Vec<_>>())
+        .unwrap_or_default();
+    ctx.devices()?
+        .map(|dev| {
+            let desc = dev.description()?;
+            let port_path = usb
+                .iter()
+                .find(|usb| {
+                    usb.bus_number() == dev.bus_number() && usb.address() == dev.device_address()
+                })
+                .and_then(port_path);
+            let id = DeviceId {
+                vendor_id: Some(desc.vendor_id),
+                product_id: Some(desc.product_id),
+                serial: desc.serial_number.clone(),
+                port_path,
+            };
+            Ok((dev, UvcDevice { desc, id }))
+        })
+        .collect()
+}
+
+// libuvc's find_device can only go by the serial, not the port
+fn find_uvc<'a>(ctx: &'a uvc::Context<'_>, devid: &DeviceId) -> anyhow::Result<uvc::Device<'a>> {
+    list_uvc(ctx)?
+        .into_iter()
+        .find(|(_, dev)| devid.matches(&dev.id))
+        .map(|(dev, _)| dev)
+        .ok_or_else(|| {
+            let dev = VideoDevice::Uvc(devid.clone());
+            anyhow::anyhow!("no uvc device matching {dev} plugged in")
+        })
+}
+
+/// bus-ports, like 3-1.4, same as the device's name under /sys/bus/usb/devices
+pub(crate) fn port_path<T: UsbContext>(dev: &rusb::Device<T>) -> Option<String> {
+    let ports = dev.port_numbers().ok().filter(|ports| !ports.is_empty())?;
+    let ports = ports
+        .iter()
+        .map(|port| port.to_string())
+        .collect::<Vec<_>>();
+    Some(format!("{}-{}", dev.bus_number(), ports.join(".")))
+}
+
+fn uvc_modes(devh: &uvc::DeviceHandle<'_>) -> Vec<Mode> {
+    let mut modes = vec![];
+    for format in devh.supported_formats() {
+        let kind = match format.subtype() {
+            uvc::DescriptionSubtype::FormatMJPEG => ModeFormat::Mjpeg,
+            uvc::DescriptionSubtype::FormatUncompressed => ModeFormat::Uncompressed,
+            _ => continue,
+        };
+        for frame in format.supported_formats() {
+            // intervals are in 100ns units
+            for &interval in frame.intervals().iter().filter(|&&i| i != 0) {
+                modes.push(Mode {
+                    width: frame.width() as u32,
+                    height: frame.height() as u32,
+                    fps: 10_000_000 / interval,
+                    format: kind,
+                });
+            }
+        }
+    }
+    modes
+}
+
+impl VideoSource for UvcSource {
+    fn watch(&mut self, tx: flume::Sender<UsbUpdate>) {
         self.plug_reg = None;
         let mut hotplug = rusb::HotplugBuilder::new();
         hotplug.enumerate(true);
@@ -106,18 +393,31 @@ impl CameraActor {
         }
         struct Hotplug {
             tx: flume::Sender<UsbUpdate>,
+            port_path: Option<String>,
+            streaming: Arc<Mutex<Option<(u8, u8)>>>,
         }
         impl rusb::Hotplug<rusb::Context> for Hotplug {
-            fn device_arrived(&mut self, _: rusb::Device<rusb::Context>) {
+            fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+                // checking the serial means opening it, stream() does that
+                if self.port_path.is_some() && port_path(&device) != self.port_path {
+                    return;
+                }
                 let _ = self.tx.send(UsbUpdate::Connected);
             }
 
-            fn device_left(&mut self, _: rusb::Device<rusb::Context>) {
-                let _ = self.tx.send(UsbUpdate::Disconnected);
+            fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+                // an identical card gets through the filter too, make sure it's
+                // the one we're using
+                let ours = *self.streaming.lock().unwrap();
+                if ours.map_or(true, |ours| ours == (device.bus_number(), device.address())) {
+                    let _ = self.tx.send(UsbUpdate::Disconnected);
+                }
             }
         }
         let callback = Box::new(Hotplug {
-            tx: self.conn_tx.clone(),
+            tx,
+            port_path: self.devid.port_path.clone(),
+            streaming: self.streaming.clone(),
         });
         self.plug_reg = Some(
             hotplug
@@ -126,119 +426,439 @@ impl CameraActor {
         )
     }
 
-    fn go(&mut self, ctx: &uvc::Context) -> anyhow::Result<()> {
-        let device = ctx.find_device(
-            self.devid.vendor_id.map(Into::into),
-            self.devid.product_id.map(Into::into),
-            None,
-        )?;
+    fn stream(
+        &mut self,
+        mut texture: EguiTexture,
+        wait: &mut dyn FnMut() -> StreamEnd,
+    ) -> anyhow::Result<()> {
+        let device = find_uvc(&self.ctx, &self.devid)?;
+        *self.streaming.lock().unwrap() = Some((device.bus_number(), device.device_address()));
 
         let devh = device.open()?;
 
-        let format = devh
-            .get_preferred_format(|a, b| {
-                for pref in FORMAT_PREFERENCES {
-                    match pref(a, b) {
-                        cmp::Ordering::Less => return b,
-                        cmp::Ordering::Greater => return a,
-                        cmp::Ordering::Equal => continue,
-                    }
-                }
-                a
-            })
-            .context("no preferred formats")?;
-
-        // let format = uvc::StreamFormat {
-        //     width: 1280,
-        //     height: 720,
-        //     fps: 60,
-        //     format: uvc::FrameFormat::Any,
-        // };
-        eprintln!("using {format:?}");
+        let modes = uvc_modes(&devh);
+        self.modes.lock().unwrap().available = modes.clone();
+        let mode = self
+            .format
+            .choose(modes, |m| *m)
+            .ok_or_else(|| anyhow::anyhow!("no preferred formats"))?;
+        self.modes.lock().unwrap().current = Some(mode);
+        let format = uvc::StreamFormat::from(mode);
+        eprintln!("using {mode}");
 
         let mut streamh = devh.get_stream_handle_with_format(format)?;
 
-        let mut texture = self.texture.clone();
-        let stream = streamh.start_stream(move |frame| texture.handle_frame(frame))?;
-
-        loop {
-            let forget_dev = match self.poll_chan() {
-                PollChanRes::Plug(UsbUpdate::Connected) => continue,
-                PollChanRes::Plug(UsbUpdate::Disconnected) => true,
-                PollChanRes::DevSwitch => false,
+        let stream = streamh.start_stream(move |frame| {
+            let captured = Instant::now();
+            let (width, height) = (frame.width() as usize, frame.height() as usize);
+            let data = frame.to_bytes();
+            // libuvc only tells us "uncompressed", but the size gives it away
+            let format = match mode.format {
+                ModeFormat::Mjpeg => Some(PixelFormat::Mjpeg),
+                _ => [PixelFormat::Yuyv, PixelFormat::Nv12, PixelFormat::Rgb24]
+                    .into_iter()
+                    .find(|f| f.frame_len(width, height) == Some(data.len())),
             };
-            stream.stop();
-            if forget_dev {
-                // aborts if we try to drop devicehandle while the device is disconnected
-                std::mem::forget(devh);
+            match format {
+                Some(format) => texture.handle_frame(Frame {
+                    width,
+                    height,
+                    format,
+                    data,
+                    captured,
+                }),
+                // something exotic, let libuvc deal with it
+                None => match frame.to_rgb() {
+                    Ok(rgb) => texture.handle_frame(Frame {
+                        width,
+                        height,
+                        format: PixelFormat::Rgb24,
+                        data: rgb.to_bytes(),
+                        captured,
+                    }),
+                    Err(e) => texture.bad_frame(format_args!("bad rgb {e}")),
+                },
             }
-            return Ok(());
+        })?;
+
+        let end = wait();
+        stream.stop();
+        *self.streaming.lock().unwrap() = None;
+        if end == StreamEnd::Disconnected {
+            // aborts if we try to drop devicehandle while the device is disconnected
+            std::mem::forget(devh);
         }
+        Ok(())
     }
+}
 
-    fn poll_chan(&mut self) -> PollChanRes {
-        loop {
-            let res = flume::Selector::new()
-                .recv(&self.conn_rx, |upd| {
-                    ControlFlow::Break(PollChanRes::Plug(upd.unwrap()))
-                })
-                .recv(&self.devid_rx, |devid| {
-                    if let Ok(devid) = devid {
-                        let switch = devid != self.devid;
-                        if switch {
-                            self.devid = devid;
-                            ControlFlow::Break(PollChanRes::DevSwitch)
-                        } else {
-                            ControlFlow::Continue(())
-                        }
-                    } else {
-                        // the process is gonna end really soon anyway
-                        ControlFlow::Continue(())
-                    }
-                })
-                .wait();
-            if let ControlFlow::Break(x) = res {
-                if let PollChanRes::DevSwitch = x {
-                    self.hotplug();
+#[derive(Clone)]
+pub(crate) struct EguiTexture {
+    texture: egui::TextureHandle,
+    ctx: egui::Context,
+    gpu: GpuFrames,
+    opts: Arc<Mutex<RenderOptions>>,
+    recorder: Recorder,
+    grab: FrameGrab,
+    latency: Latency,
+    stats: Stats,
+    // only started once there's something to hold back
+    delay: Option<Arc<Delay>>,
+}
+
+/// an owned copy of a frame sitting in the delay queue
+struct DelayedFrame {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    data: Vec<u8>,
+    captured: Instant,
+}
+
+impl DelayedFrame {
+    fn frame(&self) -> Frame<'_> {
+        Frame {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            data: &self.data,
+            captured: self.captured,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DelayQueue {
+    frames: VecDeque<DelayedFrame>,
+    // taken out of the queue but not shown yet
+    in_flight: bool,
+    // buffers from frames that have been shown, to copy the next ones into
+    spare: Vec<Vec<u8>>,
+    quit: bool,
+}
+
+/// holds frames back for the a/v offset. they get let out by a thread of
+/// its own, so they come out on time rather than whenever the next one shows up
+struct Delay {
+    queue: Arc<(Mutex<DelayQueue>, Condvar)>,
+}
+
+impl Delay {
+    fn spawn(texture: EguiTexture) -> Self {
+        let queue = Arc::new(Default::default());
+        std::thread::spawn({
+            let queue = Arc::clone(&queue);
+            move || Self::run(texture, &queue)
+        });
+        Self { queue }
+    }
+
+    fn run(mut texture: EguiTexture, queue: &(Mutex<DelayQueue>, Condvar)) {
+        let (lock, cond) = queue;
+        let mut q = lock.lock().unwrap();
+        while !q.quit {
+            let delay = texture.opts.lock().unwrap().video_delay;
+            let due = match q.frames.front() {
+                Some(f) => f.captured + delay,
+                None => {
+                    q = cond.wait(q).unwrap();
+                    continue;
                 }
-                return x;
+            };
+            let now = Instant::now();
+            if now < due {
+                q = cond.wait_timeout(q, due - now).unwrap().0;
+                continue;
             }
+            let f = q.frames.pop_front().unwrap();
+            q.in_flight = true;
+            drop(q);
+            // recordings should line up with what's on screen, so it goes in
+            // as if it had been captured when it came out
+            let frame = f.frame();
+            texture.recorder.video_frame(&Frame {
+                captured: due,
+                ..frame
+            });
+            texture.show_frame(frame);
+            q = lock.lock().unwrap();
+            q.in_flight = false;
+            q.spare.push(f.data);
         }
     }
-}
-enum PollChanRes {
-    Plug(UsbUpdate),
-    DevSwitch,
+
+    fn push(&self, frame: &Frame<'_>) {
+        let (lock, cond) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        let mut data = q.spare.pop().unwrap_or_default();
+        data.clear();
+        data.extend_from_slice(frame.data);
+        q.frames.push_back(DelayedFrame {
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+            data,
+            captured: frame.captured,
+        });
+        drop(q);
+        cond.notify_one();
+    }
+
+    /// nothing's waiting, so new frames can skip the queue. the spare buffers
+    /// won't be needed then either
+    fn drained(&self) -> bool {
+        let mut q = self.queue.0.lock().unwrap();
+        let drained = q.frames.is_empty() && !q.in_flight;
+        if drained {
+            q.spare.clear();
+        }
+        drained
+    }
 }
 
-#[derive(Clone)]
-struct EguiTexture {
-    texture: egui::TextureHandle,
-    ctx: egui::Context,
+impl Drop for Delay {
+    fn drop(&mut self) {
+        let (lock, cond) = &*self.queue;
+        lock.lock().unwrap().quit = true;
+        cond.notify_one();
+    }
 }
+
 impl EguiTexture {
+    /// one that isn't hooked up to a window, for tests
+    #[cfg(test)]
+    pub fn headless(opts: RenderOptions, latency: Latency) -> Self {
+        let ctx = egui::Context::default();
+        Self {
+            texture: ctx.load_texture("test", egui::ColorImage::example(), crate::TEXTURE_FILTER),
+            ctx,
+            gpu: Default::default(),
+            opts: Arc::new(Mutex::new(opts)),
+            recorder: Recorder::new().0,
+            grab: Default::default(),
+            latency,
+            stats: Default::default(),
+            delay: None,
+        }
+    }
     fn set_texture(&mut self, texture: egui::ColorImage) {
+        self.gpu.clear();
         self.texture.set(texture, crate::TEXTURE_FILTER);
         self.ctx.request_repaint();
     }
-    fn handle_frame(&mut self, frame: &uvc::Frame) {
-        let (width, height) = (frame.width() as usize, frame.height() as usize);
-        let mut rgba = vec![Color32::TRANSPARENT; width * height];
+    pub fn bad_frame(&self, msg: impl std::fmt::Display) {
+        eprintln!("{msg}");
+        self.stats.bad_frame();
+    }
+    pub fn handle_frame(&mut self, frame: Frame<'_>) {
+        self.stats.frame_in(frame.format, frame.width, frame.height);
+        let delay = self.opts.lock().unwrap().video_delay;
+        let drained = match &self.delay {
+            Some(delay) => delay.drained(),
+            None => true,
+        };
+        if delay.is_zero() && drained {
+            self.recorder.video_frame(&frame);
+            return self.show_frame(frame);
+        }
+        if self.delay.is_none() {
+            self.delay = Some(Arc::new(Delay::spawn(self.clone())));
+        }
+        self.delay.as_ref().unwrap().push(&frame);
+    }
+    fn show_frame(&mut self, frame: Frame<'_>) {
+        let opts = *self.opts.lock().unwrap();
+        let grab = self.grab.take();
+        if opts.gpu_yuv && GpuFrames::supports(frame.format) && self.gpu.enabled() {
+            // the gpu path never makes a ColorImage, so convert this one just for the screenshot
+            if let Some(tx) = grab {
+                match convert::to_color_image(&frame, opts.colorimetry) {
+                    Ok(image) => drop(tx.send(image)),
+                    Err(e) => eprintln!("couldn't convert frame for screenshot: {e}"),
+                }
+            }
+            if let Err(e) = self.gpu.push(&frame) {
+                return self.bad_frame(format_args!("bad frame {e}"));
+            }
+            self.latency.uploaded(frame.captured);
+            self.stats.uploaded();
+            self.ctx.request_repaint();
+            return;
+        }
+        match convert::to_color_image(&frame, opts.colorimetry) {
+            Ok(image) => {
+                if let Some(tx) = grab {
+                    let _ = tx.send(image.clone());
+                }
+                self.set_texture(image);
+                self.latency.uploaded(frame.captured);
+                self.stats.uploaded();
+            }
+            Err(e) => self.bad_frame(format_args!("bad frame {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let rgb = match frame.to_rgb() {
-            Ok(rgb) => rgb,
-            Err(e) => {
-                eprintln!("bad rgb {e}");
-                return;
+    fn chans() -> (Chans, flume::Sender<VideoConfig>) {
+        let config = VideoConfig {
+            dev: VideoDevice::TestPattern(Default::default()),
+            format: Default::default(),
+        };
+        let (config_tx, config_rx) = flume::unbounded();
+        let (conn_tx, conn_rx) = flume::unbounded();
+        let chans = Chans {
+            config_rx,
+            config,
+            conn_tx,
+            conn_rx,
+        };
+        (chans, config_tx)
+    }
+
+    fn frame(data: &[u8]) -> Frame<'_> {
+        Frame {
+            width: 2,
+            height: 2,
+            format: PixelFormat::Rgb24,
+            data,
+            captured: Instant::now(),
+        }
+    }
+
+    fn delayed(ms: u64) -> (EguiTexture, Latency) {
+        let latency = Latency::default();
+        latency.start(None).unwrap();
+        let opts = RenderOptions {
+            video_delay: Duration::from_millis(ms),
+            ..Default::default()
+        };
+        (EguiTexture::headless(opts, latency.clone()), latency)
+    }
+
+    #[test]
+    fn delayed_frames_come_out_without_another_one() {
+        let (mut texture, latency) = delayed(50);
+        texture.handle_frame(frame(&[0; 12]));
+        // only how long it's held back is certain, a busy machine can take
+        // as long as it likes to get it out after that
+        let start = Instant::now();
+        let stats = loop {
+            std::thread::sleep(Duration::from_millis(10));
+            latency.painted();
+            if let Some(stats) = latency.stats() {
+                break stats;
             }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the frame never came out"
+            );
         };
-        for (rgba, rgb) in rgba.iter_mut().zip(rgb.to_bytes().chunks_exact(3)) {
-            *rgba = Color32::from_rgb(rgb[0], rgb[1], rgb[2]);
+        let upload = stats.upload.min;
+        assert!(upload >= Duration::from_millis(50), "{upload:?}");
+    }
+
+    #[test]
+    fn delay_reuses_buffers() {
+        let (mut texture, _) = delayed(0);
+        texture.opts.lock().unwrap().video_delay = Duration::from_millis(10);
+        texture.handle_frame(frame(&[1; 12]));
+        std::thread::sleep(Duration::from_millis(50));
+        let queue = texture.delay.as_ref().unwrap().queue.clone();
+        let spare = queue.0.lock().unwrap().spare[0].as_ptr();
+        // long enough that it's still in there when we look
+        texture.opts.lock().unwrap().video_delay = Duration::from_secs(10);
+        texture.handle_frame(frame(&[2; 12]));
+        let q = queue.0.lock().unwrap();
+        assert!(q.spare.is_empty());
+        assert_eq!(q.frames[0].data.as_ptr(), spare);
+        assert_eq!(q.frames[0].data, [2; 12]);
+    }
+
+    #[test]
+    fn capture_failure_ends_the_wait() {
+        let (mut chans, _config_tx) = chans();
+        let conn_tx = chans.conn_tx.clone();
+        // what a capture thread does when its device is busy
+        let capture = std::thread::spawn(move || conn_tx.send(UsbUpdate::Failed).unwrap());
+        assert!(chans.wait_stream_end() == StreamEnd::Failed);
+        capture.join().unwrap();
+    }
+
+    /// can't be set up the first couple of times, like a node someone else
+    /// is streaming from
+    struct BusySource {
+        tx: Option<flume::Sender<UsbUpdate>>,
+        tries: flume::Sender<()>,
+        busy: usize,
+    }
+    impl VideoSource for BusySource {
+        fn watch(&mut self, tx: flume::Sender<UsbUpdate>) {
+            tx.send(UsbUpdate::Connected).unwrap();
+            self.tx = Some(tx);
         }
+        fn stream(
+            &mut self,
+            _: EguiTexture,
+            wait: &mut dyn FnMut() -> StreamEnd,
+        ) -> anyhow::Result<()> {
+            let _ = self.tries.send(());
+            if self.busy > 0 {
+                self.busy -= 1;
+                self.tx.as_ref().unwrap().send(UsbUpdate::Failed).unwrap();
+                anyhow::bail!("device busy");
+            }
+            wait();
+            Ok(())
+        }
+    }
 
-        self.set_texture(egui::ColorImage {
-            pixels: rgba,
-            size: [width, height],
+    #[test]
+    fn failing_to_start_gets_retried() {
+        let (chans, config_tx) = chans();
+        let (tries_tx, tries) = flume::unbounded();
+        let mut source = BusySource {
+            tx: None,
+            tries: tries_tx,
+            busy: 2,
+        };
+        source.watch(chans.conn_tx.clone());
+        let actor = std::thread::spawn(move || {
+            CameraActor {
+                texture: EguiTexture::headless(Default::default(), Latency::default()),
+                chans,
+                source: Box::new(source),
+                usb: None,
+                modes: Default::default(),
+            }
+            .run()
         });
+        for _ in 0..3 {
+            tries
+                .recv_timeout(Duration::from_secs(10))
+                .expect("never tried again");
+        }
+        drop(config_tx);
+        actor.join().unwrap();
+    }
+
+    #[test]
+    fn only_a_new_config_is_a_switch() {
+        let (mut chans, config_tx) = chans();
+        config_tx.send(chans.config.clone()).unwrap();
+        chans.conn_tx.send(UsbUpdate::Connected).unwrap();
+        chans.conn_tx.send(UsbUpdate::Disconnected).unwrap();
+        assert!(chans.wait_stream_end() == StreamEnd::Disconnected);
+        let other = VideoConfig {
+            dev: VideoDevice::TestPattern("320x240@30".parse().unwrap()),
+            format: Default::default(),
+        };
+        config_tx.send(other).unwrap();
+        assert!(chans.wait_stream_end() == StreamEnd::DevSwitch);
+        drop(config_tx);
+        assert!(chans.wait_stream_end() == StreamEnd::Quit);
     }
 }